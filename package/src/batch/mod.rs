@@ -0,0 +1,309 @@
+use std::fs;
+use std::io::{BufRead, Read, Write};
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::build::classification::{model_type_of, ModelType};
+use crate::build::features::SignalFeatures;
+use crate::build::language_model::embeddings::{EmbeddingProvider, LlamaCppEmbeddingProvider};
+use crate::detector::{FraudDetector, FraudScore};
+
+pub const SCORE_COLUMN: &str = "score";
+pub const VERDICT_COLUMN: &str = "verdict";
+pub const NOVELTY_COLUMN: &str = "novelty";
+pub const OUT_OF_DISTRIBUTION_COLUMN: &str = "out_of_distribution";
+pub const PREDICTION_SET_COLUMN: &str = "prediction_set";
+pub const CATEGORIES_COLUMN: &str = "categories";
+pub const SIGNALS_COLUMN: &str = "signals";
+pub const ERROR_COLUMN: &str = "error";
+/// Holds JSON lines that are not objects (or not JSON at all), so every output row can be joined back to its input.
+pub const INPUT_FIELD: &str = "input";
+
+/// Appended to every input row, in this order.
+pub const OUTPUT_COLUMNS: [&str; 8] = [SCORE_COLUMN, VERDICT_COLUMN, NOVELTY_COLUMN, OUT_OF_DISTRIBUTION_COLUMN, PREDICTION_SET_COLUMN, CATEGORIES_COLUMN, SIGNALS_COLUMN, ERROR_COLUMN];
+
+#[derive(Clone)]
+pub struct BatchOptions {
+    pub model_path: String,
+    pub threshold: f32,
+    /// Texts with a novelty at or above this are flagged as out of distribution.
+    pub novelty_threshold: f32,
+    /// Error level of the prediction sets, defaults to the one the model was calibrated for.
+    pub error_level: Option<f32>,
+    /// Number of texts scored (and written) at a time.
+    pub batch_size: usize,
+    /// Embeds with this provider instead of the llama.cpp server from the environment.
+    pub provider: Option<Arc<dyn EmbeddingProvider>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BatchSummary {
+    pub scored_count: usize,
+    pub fraud_count: usize,
+    pub failed_count: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Prediction {
+    /// `None` if the text could not be scored, see `error`.
+    pub score: Option<FraudScore>,
+    /// `None` if there is no text to extract them from.
+    pub signals: Option<SignalFeatures>,
+    pub error: Option<String>,
+}
+
+impl Prediction {
+    fn failed(error: String, signals: Option<SignalFeatures>) -> Self {
+        Prediction { score: None, signals, error: Some(error) }
+    }
+
+    pub fn verdict(&self, threshold: f32) -> &'static str {
+        match &self.score {
+            Some(score) if score.score >= threshold => "fraud",
+            Some(_) => "ham",
+            None => "error",
+        }
+    }
+
+    /// The values of `OUTPUT_COLUMNS` for CSV and tab separated output, nested values as JSON.
+    fn columns(&self, threshold: f32) -> Vec<String> {
+        let score = self.score.as_ref();
+        let json = |value: Option<Value>| value.filter(|value| !value.is_null()).map(|value| value.to_string()).unwrap_or_default();
+        vec![
+            score.map(|score| format!("{:.4}", score.score)).unwrap_or_default(),
+            self.verdict(threshold).to_string(),
+            score.and_then(|score| score.novelty).map(|novelty| format!("{:.4}", novelty)).unwrap_or_default(),
+            score.map(|score| score.out_of_distribution.to_string()).unwrap_or_default(),
+            json(score.map(|score| serde_json::json!(score.prediction_set))),
+            json(score.map(|score| serde_json::json!(score.categories))),
+            json(self.signals.as_ref().map(|signals| serde_json::json!(signals))),
+            self.error.clone().unwrap_or_default(),
+        ]
+    }
+
+    /// The values of `OUTPUT_COLUMNS` for JSON output.
+    fn fields(&self, threshold: f32) -> Vec<(&'static str, Value)> {
+        let score = self.score.as_ref();
+        let values = [
+            score.map(|score| Value::from(score.score)).unwrap_or(Value::Null),
+            Value::from(self.verdict(threshold)),
+            score.and_then(|score| score.novelty).map(Value::from).unwrap_or(Value::Null),
+            Value::from(score.is_some_and(|score| score.out_of_distribution)),
+            serde_json::json!(score.and_then(|score| score.prediction_set.as_ref())),
+            serde_json::json!(score.and_then(|score| score.categories.as_ref())),
+            serde_json::json!(self.signals),
+            serde_json::json!(self.error),
+        ];
+        OUTPUT_COLUMNS.into_iter().zip(values).collect()
+    }
+}
+
+impl BatchSummary {
+    fn add(&mut self, prediction: &Prediction, threshold: f32) {
+        match prediction.verdict(threshold) {
+            "fraud" => { self.scored_count += 1; self.fraud_count += 1; },
+            "ham" => { self.scored_count += 1; },
+            _ => { self.failed_count += 1; },
+        }
+    }
+}
+
+/// Scores KNN bundles with the fraud detector, which adds the novelty, prediction set and categories like the
+/// HTTP service, and random forests with the pooled model, which only gives the score.
+pub enum Scorer {
+    Detector(FraudDetector),
+    Model(BatchOptions),
+}
+
+impl Scorer {
+    /// Fails if the model can not be loaded.
+    pub fn new(options: &BatchOptions) -> anyhow::Result<Self> {
+        if model_type_of(&options.model_path)? == ModelType::RandomForest {
+            return Ok(Scorer::Model(options.clone()));
+        }
+        let provider: Arc<dyn EmbeddingProvider> = match &options.provider {
+            Some(provider) => Arc::clone(provider),
+            None => Arc::new(LlamaCppEmbeddingProvider::from_env()?),
+        };
+        let mut detector = FraudDetector::load(provider, &options.model_path, options.threshold)?;
+        detector.novelty_threshold = options.novelty_threshold;
+        detector.error_level = options.error_level;
+        Ok(Scorer::Detector(detector))
+    }
+
+    async fn score(&self, texts: &[&str]) -> Vec<anyhow::Result<FraudScore>> {
+        let options = match self {
+            Scorer::Detector(detector) => return detector.score(texts).await,
+            Scorer::Model(options) => options,
+        };
+        let results = match &options.provider {
+            Some(provider) => crate::fraud_probability_results_with_provider(texts, &options.model_path, provider.as_ref()).await,
+            None => crate::fraud_probability_results(texts, &options.model_path).await,
+        };
+        results.into_iter().map(|score| score.map(|score| FraudScore {
+            score,
+            fraud: score >= options.threshold,
+            novelty: None,
+            out_of_distribution: false,
+            prediction_set: None,
+            categories: None,
+        })).collect()
+    }
+}
+
+/// Scores the texts, texts that could not be read are passed in as errors and reported as such.
+pub async fn predict_batch(texts: &[Result<String, String>], scorer: &Scorer) -> Vec<Prediction> {
+    let valid: Vec<&str> = texts.iter()
+        .filter_map(|text| text.as_ref().ok())
+        .filter(|text| !text.trim().is_empty())
+        .map(|text| text.as_str())
+        .collect();
+    let mut results = scorer.score(&valid).await.into_iter();
+
+    texts.iter().map(|text| match text {
+        Err(err) => Prediction::failed(err.clone(), None),
+        Ok(text) if text.trim().is_empty() => Prediction::failed("empty text".to_string(), None),
+        Ok(text) => {
+            let signals = Some(SignalFeatures::extract(text));
+            match results.next() {
+                Some(Ok(score)) => Prediction { score: Some(score), signals, error: None },
+                Some(Err(err)) => Prediction::failed(err.to_string(), signals),
+                None => Prediction::failed("missing prediction".to_string(), signals),
+            }
+        },
+    }).collect()
+}
+
+fn tsv_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+/// Scores (id, text) pairs and writes them as tab separated lines of the id and the `OUTPUT_COLUMNS`.
+pub async fn predict_texts<W: Write>(inputs: Vec<(String, Result<String, String>)>, writer: &mut W, options: &BatchOptions) -> anyhow::Result<BatchSummary> {
+    let scorer = Scorer::new(options)?;
+    let mut summary = BatchSummary::default();
+    writeln!(writer, "id\t{}", OUTPUT_COLUMNS.join("\t"))?;
+
+    for chunk in inputs.chunks(options.batch_size.max(1)) {
+        let texts: Vec<Result<String, String>> = chunk.iter().map(|(_, text)| text.clone()).collect();
+        let predictions = predict_batch(&texts, &scorer).await;
+        for ((id, _), prediction) in chunk.iter().zip(predictions) {
+            summary.add(&prediction, options.threshold);
+            let columns: Vec<String> = prediction.columns(options.threshold).iter().map(|column| tsv_field(column)).collect();
+            writeln!(writer, "{}\t{}", tsv_field(id), columns.join("\t"))?;
+        }
+        writer.flush()?;
+    }
+    Ok(summary)
+}
+
+/// One input per line, or all of it as a single text if `whole` is set.
+pub fn read_texts<R: Read>(mut reader: R, whole: bool) -> anyhow::Result<Vec<(String, Result<String, String>)>> {
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents)?;
+    if whole {
+        return Ok(vec![("stdin".to_string(), Ok(contents))]);
+    }
+    Ok(contents.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| (line.to_string(), Ok(line.to_string())))
+        .collect())
+}
+
+/// Every regular file of the directory (sorted by name), keyed by its path.
+pub fn read_directory(path: &str) -> anyhow::Result<Vec<(String, Result<String, String>)>> {
+    let mut paths: Vec<_> = fs::read_dir(path)
+        .map_err(|err| anyhow::anyhow!("Error: unable to read directory '{}': {}", path, err))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    Ok(paths.iter().map(|path| {
+        let text = fs::read_to_string(path).map_err(|err| format!("unable to read file: {}", err));
+        (path.to_string_lossy().to_string(), text)
+    }).collect())
+}
+
+/// Copies the CSV and appends the `OUTPUT_COLUMNS`, scoring the text in `column`.
+pub async fn predict_csv<R: Read, W: Write>(reader: R, writer: W, column: &str, options: &BatchOptions) -> anyhow::Result<BatchSummary> {
+    let scorer = Scorer::new(options)?;
+    let mut reader = csv::Reader::from_reader(reader);
+    let mut writer = csv::Writer::from_writer(writer);
+
+    let headers = reader.headers()?.clone();
+    let column_index = headers.iter().position(|header| header == column)
+        .ok_or_else(|| anyhow::anyhow!("Error: column '{}' not found, available columns: {:?}", column, headers.iter().collect::<Vec<&str>>()))?;
+    let mut output_headers = headers.clone();
+    output_headers.extend(OUTPUT_COLUMNS);
+    writer.write_record(&output_headers)?;
+
+    let mut summary = BatchSummary::default();
+    let mut records = reader.records();
+    loop {
+        let chunk: Vec<Result<csv::StringRecord, csv::Error>> = records.by_ref().take(options.batch_size.max(1)).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let texts: Vec<Result<String, String>> = chunk.iter().map(|record| match record {
+            Ok(record) => record.get(column_index).map(|text| text.to_string()).ok_or_else(|| format!("missing column '{}'", column)),
+            Err(err) => Err(format!("invalid record: {}", err)),
+        }).collect();
+        let predictions = predict_batch(&texts, &scorer).await;
+
+        for (record, prediction) in chunk.into_iter().zip(predictions) {
+            summary.add(&prediction, options.threshold);
+            // a row that could not be read keeps the width of the header, so the remaining rows are still written
+            let mut record = record.unwrap_or_default();
+            while record.len() < headers.len() {
+                record.push_field("");
+            }
+            record.extend(prediction.columns(options.threshold));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+    }
+    Ok(summary)
+}
+
+/// Copies the JSON lines and adds the `OUTPUT_COLUMNS` as fields, scoring the text in `field`.
+/// Lines that are not JSON objects are kept under `input`.
+pub async fn predict_jsonl<R: BufRead, W: Write>(reader: R, writer: &mut W, field: &str, options: &BatchOptions) -> anyhow::Result<BatchSummary> {
+    let scorer = Scorer::new(options)?;
+    let mut summary = BatchSummary::default();
+    let mut lines = reader.lines().filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()));
+    loop {
+        // the parsed line, or the raw line (if it could be read) and why it can not be scored
+        let chunk: Vec<Result<Value, (Value, String)>> = lines.by_ref()
+            .take(options.batch_size.max(1))
+            .map(|line| match line {
+                Ok(line) => serde_json::from_str::<Value>(&line).map_err(|err| (Value::from(line), format!("invalid JSON: {}", err))),
+                Err(err) => Err((Value::Null, format!("unable to read line: {}", err))),
+            })
+            .collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let texts: Vec<Result<String, String>> = chunk.iter().map(|value| match value {
+            Ok(value) => value[field].as_str().map(|text| text.to_string()).ok_or_else(|| format!("missing string field '{}'", field)),
+            Err((_, err)) => Err(err.clone()),
+        }).collect();
+        let predictions = predict_batch(&texts, &scorer).await;
+
+        for (value, prediction) in chunk.into_iter().zip(predictions) {
+            summary.add(&prediction, options.threshold);
+            let mut object = match value {
+                Ok(Value::Object(object)) => object,
+                Ok(input) | Err((input, _)) => serde_json::Map::from_iter([(INPUT_FIELD.to_string(), input)]),
+            };
+            for (name, value) in prediction.fields(options.threshold) {
+                object.insert(name.to_string(), value);
+            }
+            writeln!(writer, "{}", Value::Object(object))?;
+        }
+        writer.flush()?;
+    }
+    Ok(summary)
+}
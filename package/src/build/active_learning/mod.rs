@@ -0,0 +1,210 @@
+//! Active learning: picks the unlabeled texts a human should label next and imports the labels.
+//!
+//! Candidates are the texts the model is least sure about (scores closest to the threshold),
+//! spread out in embedding space so a batch does not consist of near-duplicates.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::data::embedding_dataset::EmbeddingRecord;
+use super::data::{DatasetEntry, DatasetManifest, MIN_TEXT_LENGTH};
+use super::row_hash;
+
+pub const LABEL_COLUMN: &str = "label";
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SelectionConfig {
+    pub threshold: f32,
+    pub batch_size: usize,
+    /// The batch is picked from the `batch_size * pool_factor` most uncertain texts.
+    pub pool_factor: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LabelingCandidate {
+    /// Row hash of the text.
+    pub id: String,
+    pub text: String,
+    pub score: f32,
+    /// Distance of the score to the threshold, lower is more uncertain.
+    pub uncertainty: f32,
+}
+
+/// Uncertainty sampling near the threshold followed by greedy k-center selection for diversity.
+///
+/// `scores` are the model scores of the `records`, repeated texts are only considered once.
+pub fn select_for_labeling(records: &[EmbeddingRecord], scores: &[f32], config: &SelectionConfig) -> anyhow::Result<Vec<LabelingCandidate>> {
+    if records.len() != scores.len() {
+        return Err(anyhow::anyhow!("Error: expected one score per record, got {} scores for {} records", scores.len(), records.len()));
+    }
+    if config.batch_size == 0 || config.pool_factor == 0 {
+        return Err(anyhow::anyhow!("Error: the batch size and pool factor must be at least 1"));
+    }
+
+    let mut seen = HashSet::new();
+    let mut pool: Vec<usize> = (0..records.len())
+        .filter(|&i| scores[i].is_finite() && seen.insert(row_hash(&records[i].text)))
+        .collect();
+    pool.sort_by(|&a, &b| (scores[a] - config.threshold).abs().total_cmp(&(scores[b] - config.threshold).abs()));
+    pool.truncate(config.batch_size.saturating_mul(config.pool_factor));
+
+    // the most uncertain text first, then always the one farthest from everything selected so far
+    let mut selected: Vec<usize> = Vec::new();
+    let mut min_distances: Vec<f32> = vec![f32::INFINITY; pool.len()];
+    let mut next = if pool.is_empty() { None } else { Some(0) };
+    while let Some(position) = next {
+        let chosen = pool[position];
+        selected.push(chosen);
+        if selected.len() >= config.batch_size {
+            break;
+        }
+        for (candidate, min_distance) in pool.iter().zip(min_distances.iter_mut()) {
+            *min_distance = min_distance.min(squared_distance(&records[*candidate].embedding, &records[chosen].embedding));
+        }
+        // ties go to the more uncertain text, as the pool is sorted by uncertainty
+        next = min_distances.iter().enumerate()
+            .filter(|(_, &distance)| distance > 0.0)
+            .fold(None, |best: Option<(usize, f32)>, (position, &distance)| match best {
+                Some((_, best_distance)) if best_distance >= distance => best,
+                _ => Some((position, distance)),
+            })
+            .map(|(position, _)| position);
+    }
+
+    Ok(selected.into_iter().map(|i| LabelingCandidate {
+        id: row_hash(&records[i].text),
+        text: records[i].text.clone(),
+        score: scores[i],
+        uncertainty: (scores[i] - config.threshold).abs(),
+    }).collect())
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// Writes the candidates as a CSV with the columns `id, text, score, label`, `label` is left empty for the labeler.
+pub fn write_labeling_batch(path: &str, candidates: &[LabelingCandidate]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["id", "text", "score", LABEL_COLUMN])?;
+    for candidate in candidates {
+        writer.write_record([candidate.id.as_str(), candidate.text.as_str(), &format!("{:.4}", candidate.score), ""])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Parses a label written by a human: a number between 0 and 1, or spam/fraud/ham.
+pub fn parse_label(label: &str) -> Option<f32> {
+    match label.trim().to_lowercase().as_str() {
+        "spam" | "fraud" => Some(1.0),
+        "ham" => Some(0.0),
+        value => value.parse::<f32>().ok().filter(|value| (0.0..=1.0).contains(value)),
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub labeled_count: usize,
+    /// Rows whose label was left empty.
+    pub unlabeled_count: usize,
+    pub invalid_count: usize,
+    /// Labeled texts shorter than `MIN_TEXT_LENGTH`, which reading the dataset would skip.
+    pub too_short_count: usize,
+    /// Labeled texts already in one of the datasets of the manifest.
+    pub duplicate_count: usize,
+    pub added_count: usize,
+    /// Whether the dataset was newly added to the manifest.
+    pub registered: bool,
+}
+
+/// Appends the labeled rows of a labeling batch to the CSV `dataset_path` and adds that dataset to the manifest,
+/// so the next `embed` and training runs pick them up.
+///
+/// New datasets get the columns `text, label`, datasets already in the manifest are written and read with their
+/// registered text and label columns.
+pub fn import_labeled_batch(batch_path: &str, manifest_path: &str, dataset_path: &str) -> anyhow::Result<ImportReport> {
+    let mut report = ImportReport::default();
+
+    let mut reader = csv::Reader::from_path(batch_path)
+        .map_err(|err| anyhow::anyhow!("Error: unable to read labeling batch '{}': {}", batch_path, err))?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|header| header == name)
+        .ok_or_else(|| anyhow::anyhow!("Error: labeling batch '{}' has no '{}' column", batch_path, name));
+    let (text_index, label_index) = (column("text")?, column(LABEL_COLUMN)?);
+
+    let manifest = DatasetManifest::load(manifest_path)?;
+    let (dataset_text_index, dataset_label_index) = manifest.entry(dataset_path)?.map(DatasetEntry::columns).unwrap_or((0, 1));
+    let mut known: HashSet<String> = manifest.read_texts()?.iter().map(|(text, _)| row_hash(text)).collect();
+    if Path::new(dataset_path).exists() {
+        let mut existing = csv::Reader::from_path(dataset_path)?;
+        for row in existing.records() {
+            if let Some(text) = row?.get(dataset_text_index) {
+                known.insert(row_hash(text));
+            }
+        }
+    }
+
+    let mut rows: Vec<(String, f32)> = Vec::new();
+    for row in reader.records() {
+        let row = row?;
+        let (text, label) = (row.get(text_index).unwrap_or_default(), row.get(label_index).unwrap_or_default());
+        if label.trim().is_empty() {
+            report.unlabeled_count += 1;
+            continue;
+        }
+        let Some(label) = parse_label(label) else {
+            report.invalid_count += 1;
+            continue;
+        };
+        report.labeled_count += 1;
+        if text.len() < MIN_TEXT_LENGTH {
+            report.too_short_count += 1;
+            continue;
+        }
+        if !known.insert(row_hash(text)) {
+            report.duplicate_count += 1;
+            continue;
+        }
+        rows.push((text.to_string(), label));
+    }
+
+    let is_new = !Path::new(dataset_path).exists();
+    let file = File::options().create(true).append(true).open(dataset_path)?;
+    let mut writer = csv::Writer::from_writer(file);
+    if is_new {
+        writer.write_record(["text", LABEL_COLUMN])?;
+    }
+    let mut record = vec![String::new(); dataset_text_index.max(dataset_label_index) + 1];
+    for (text, label) in &rows {
+        record[dataset_text_index] = text.clone();
+        record[dataset_label_index] = label.to_string();
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    report.added_count = rows.len();
+
+    report.registered = DatasetManifest::register(manifest_path, dataset_path, dataset_text_index, dataset_label_index)?;
+    info!(batch = batch_path, dataset = dataset_path, added_count = report.added_count, registered = report.registered, "imported labeling batch");
+    Ok(report)
+}
+
+/// Row hashes of the texts in earlier labeling batches, so they are not selected again.
+pub fn batch_text_hashes(paths: &[String]) -> anyhow::Result<HashSet<String>> {
+    let mut texts = HashSet::new();
+    for path in paths {
+        let contents = fs::read(path).map_err(|err| anyhow::anyhow!("Error: unable to read labeling batch '{}': {}", path, err))?;
+        let mut reader = csv::Reader::from_reader(contents.as_slice());
+        let text_index = reader.headers()?.iter().position(|header| header == "text").unwrap_or(1);
+        for row in reader.records() {
+            if let Some(text) = row?.get(text_index) {
+                texts.insert(row_hash(text));
+            }
+        }
+    }
+    Ok(texts)
+}
@@ -0,0 +1,339 @@
+//! Spam campaigns: groups of near-identical flagged texts that arrive in waves.
+//!
+//! Flagged texts are clustered with average-linkage agglomerative clustering on the cosine distance
+//! of their embeddings, cut at a maximum distance. Each campaign keeps its centroid, so new
+//! predictions can be assigned to the campaigns found so far.
+
+use std::collections::HashMap;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+
+use super::data::dedup::text_hash;
+use super::normalization::NormalizationConfig;
+
+/// Cosine distance below which flagged texts are considered variants of the same campaign.
+pub const DEFAULT_CAMPAIGN_DISTANCE: f32 = 0.2;
+pub const DEFAULT_MIN_CAMPAIGN_SIZE: usize = 3;
+/// Distinct texts closest to the centroid reported per campaign.
+pub const REPRESENTATIVE_TEXTS: usize = 3;
+/// The condensed distance matrix of the distinct texts is held in memory, `n (n - 1) / 2` f32 values:
+/// about 200 MB for this many texts.
+pub const MAX_CLUSTERED_TEXTS: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct CampaignConfig {
+    /// Maximum average cosine distance between the texts of two merged clusters.
+    pub distance: f32,
+    /// Smaller clusters are not reported as campaigns.
+    pub min_size: usize,
+}
+
+impl Default for CampaignConfig {
+    fn default() -> Self {
+        CampaignConfig { distance: DEFAULT_CAMPAIGN_DISTANCE, min_size: DEFAULT_MIN_CAMPAIGN_SIZE }
+    }
+}
+
+/// A flagged text and when it was seen (unix seconds).
+#[derive(Clone, Debug)]
+pub struct FlaggedText {
+    pub text: String,
+    pub embedding: Vec<f32>,
+    pub seen_at: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Campaign {
+    /// 1 for the largest campaign.
+    pub id: usize,
+    /// Flagged texts in the campaign, repeated texts included.
+    pub size: usize,
+    pub distinct_count: usize,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    /// The distinct texts closest to the centroid, closest first.
+    pub representatives: Vec<String>,
+    /// Largest cosine distance of a text to the centroid.
+    pub radius: f32,
+    /// Unit length mean of the unit length embeddings.
+    pub centroid: Vec<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CampaignIndex {
+    pub created_at: u64,
+    /// Normalization of the texts before embedding, new texts have to be embedded the same way.
+    pub normalization: NormalizationConfig,
+    pub distance: f32,
+    pub min_size: usize,
+    pub flagged_count: usize,
+    /// Flagged texts in clusters smaller than `min_size`.
+    pub unclustered_count: usize,
+    /// Largest first.
+    pub campaigns: Vec<Campaign>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CampaignMatch {
+    pub id: usize,
+    pub distance: f32,
+}
+
+impl CampaignIndex {
+    /// Writes a temporary file next to `path` and renames it, so an interrupted save keeps the previous campaigns.
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path).map_err(|err| anyhow::anyhow!("Error: unable to read campaigns '{}': {}", path, err))?;
+        serde_json::from_str(&contents).map_err(|err| anyhow::anyhow!("Error: invalid campaigns file '{}': {}", path, err))
+    }
+
+    /// The campaign with the closest centroid, if it is within the distance cut-off.
+    pub fn assign(&self, embedding: &[f32]) -> anyhow::Result<Option<CampaignMatch>> {
+        let embedding = unit(embedding);
+        let mut best: Option<CampaignMatch> = None;
+        for campaign in &self.campaigns {
+            if campaign.centroid.len() != embedding.len() {
+                return Err(anyhow::anyhow!("Error: the campaigns were clustered on {} dimensional embeddings, got {}", campaign.centroid.len(), embedding.len()));
+            }
+            let distance = cosine_distance(&campaign.centroid, &embedding);
+            if distance <= self.distance && best.as_ref().is_none_or(|best| distance < best.distance) {
+                best = Some(CampaignMatch { id: campaign.id, distance });
+            }
+        }
+        Ok(best)
+    }
+
+    /// Counts an assigned text towards its campaign. The centroid stays as clustered.
+    pub fn record(&mut self, id: usize, seen_at: u64) {
+        if let Some(campaign) = self.campaigns.iter_mut().find(|campaign| campaign.id == id) {
+            campaign.size += 1;
+            campaign.first_seen = campaign.first_seen.min(seen_at);
+            campaign.last_seen = campaign.last_seen.max(seen_at);
+        }
+    }
+}
+
+/// Clusters the flagged texts into campaigns. Repeated texts are clustered once and count towards the size.
+pub fn cluster_campaigns(texts: &[FlaggedText], config: &CampaignConfig, normalization: &NormalizationConfig) -> anyhow::Result<CampaignIndex> {
+    if !(config.distance > 0.0 && config.distance < 2.0) {
+        return Err(anyhow::anyhow!("Error: the campaign distance must be between 0 and 2 (exclusive), got {}", config.distance));
+    }
+    if let Some(text) = texts.iter().find(|text| text.embedding.len() != texts[0].embedding.len()) {
+        return Err(anyhow::anyhow!("Error: embeddings of different dimensions ({} and {})", texts[0].embedding.len(), text.embedding.len()));
+    }
+
+    // distinct texts, each with the indices of its occurrences
+    let mut positions: HashMap<u64, usize> = HashMap::new();
+    let mut occurrences: Vec<Vec<usize>> = Vec::new();
+    for (index, text) in texts.iter().enumerate() {
+        let position = *positions.entry(text_hash(&text.text)).or_insert_with(|| {
+            occurrences.push(Vec::new());
+            occurrences.len() - 1
+        });
+        occurrences[position].push(index);
+    }
+    if occurrences.len() > MAX_CLUSTERED_TEXTS {
+        return Err(anyhow::anyhow!("Error: {} distinct flagged texts, at most {} can be clustered at once to bound the memory of their distances (raise the threshold or split the input)", occurrences.len(), MAX_CLUSTERED_TEXTS));
+    }
+    let embeddings: Vec<Vec<f32>> = occurrences.iter().map(|indices| unit(&texts[indices[0]].embedding)).collect();
+    let weights: Vec<usize> = occurrences.iter().map(Vec::len).collect();
+
+    let merges = average_linkage(&embeddings, &weights);
+    let mut parents: Vec<usize> = (0..embeddings.len()).collect();
+    for (a, b, distance) in merges {
+        if distance <= config.distance {
+            let (a, b) = (find(&mut parents, a), find(&mut parents, b));
+            parents[a] = b;
+        }
+    }
+    let mut clusters: HashMap<usize, Vec<usize>> = HashMap::new();
+    for member in 0..embeddings.len() {
+        let root = find(&mut parents, member);
+        clusters.entry(root).or_default().push(member);
+    }
+
+    let mut campaigns: Vec<Campaign> = clusters.into_values()
+        .filter(|members| members.iter().map(|&member| weights[member]).sum::<usize>() >= config.min_size.max(1))
+        .map(|members| campaign(&members, &embeddings, &occurrences, texts))
+        .collect();
+    campaigns.sort_by(|a, b| b.size.cmp(&a.size).then(a.first_seen.cmp(&b.first_seen)).then_with(|| a.representatives.cmp(&b.representatives)));
+    for (index, campaign) in campaigns.iter_mut().enumerate() {
+        campaign.id = index + 1;
+    }
+
+    let clustered_count: usize = campaigns.iter().map(|campaign| campaign.size).sum();
+    info!(flagged_count = texts.len(), distinct_count = embeddings.len(), campaign_count = campaigns.len(), clustered_count, "clustered campaigns");
+    Ok(CampaignIndex {
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        normalization: *normalization,
+        distance: config.distance,
+        min_size: config.min_size,
+        flagged_count: texts.len(),
+        unclustered_count: texts.len() - clustered_count,
+        campaigns,
+    })
+}
+
+fn campaign(members: &[usize], embeddings: &[Vec<f32>], occurrences: &[Vec<usize>], texts: &[FlaggedText]) -> Campaign {
+    let dimension = embeddings[members[0]].len();
+    let mut centroid = vec![0.0f32; dimension];
+    for &member in members {
+        for (sum, value) in centroid.iter_mut().zip(&embeddings[member]) {
+            *sum += value * occurrences[member].len() as f32;
+        }
+    }
+    let centroid = unit(&centroid);
+
+    let mut by_distance: Vec<(f32, usize)> = members.iter().map(|&member| (cosine_distance(&centroid, &embeddings[member]), member)).collect();
+    by_distance.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    let seen_at = || members.iter().flat_map(|&member| occurrences[member].iter().map(|&index| texts[index].seen_at));
+
+    Campaign {
+        id: 0,
+        size: members.iter().map(|&member| occurrences[member].len()).sum(),
+        distinct_count: members.len(),
+        first_seen: seen_at().min().unwrap_or(0),
+        last_seen: seen_at().max().unwrap_or(0),
+        representatives: by_distance.iter().take(REPRESENTATIVE_TEXTS).map(|(_, member)| texts[occurrences[*member][0]].text.clone()).collect(),
+        radius: by_distance.last().map(|(distance, _)| *distance).unwrap_or(0.0),
+        centroid,
+    }
+}
+
+/// Average-linkage (UPGMA) dendrogram with the nearest-neighbor chain algorithm, as `(a, b, distance)`
+/// merges of clusters named by one of their members. Average linkage has no inversions, so cutting the
+/// dendrogram at a distance keeps exactly the merges at or below it.
+fn average_linkage(embeddings: &[Vec<f32>], weights: &[usize]) -> Vec<(usize, usize, f32)> {
+    let count = embeddings.len();
+    // condensed upper triangle, row i holds the distances to i + 1..count
+    let offsets: Vec<usize> = (0..count).map(|i| i * count - i * (i + 1) / 2).collect();
+    let index = |i: usize, j: usize| {
+        let (i, j) = if i < j { (i, j) } else { (j, i) };
+        offsets[i] + j - i - 1
+    };
+    let mut distances: Vec<f32> = (0..count).into_par_iter()
+        .flat_map_iter(|i| (i + 1..count).map(move |j| (i, j)))
+        .map(|(i, j)| cosine_distance(&embeddings[i], &embeddings[j]))
+        .collect();
+
+    let mut sizes: Vec<f32> = weights.iter().map(|weight| *weight as f32).collect();
+    let mut active = vec![true; count];
+    let mut merges = Vec::with_capacity(count.saturating_sub(1));
+    let mut chain: Vec<usize> = Vec::new();
+    for _ in 1..count {
+        if chain.is_empty() {
+            chain.push(active.iter().position(|&active| active).unwrap());
+        }
+        let (a, b, distance) = loop {
+            let a = *chain.last().unwrap();
+            // the previous element of the chain wins ties, otherwise the chain could cycle
+            let previous = chain.len().checked_sub(2).map(|position| chain[position]);
+            let mut best = previous.map(|previous| (previous, distances[index(a, previous)]));
+            for candidate in (0..count).filter(|&candidate| active[candidate] && candidate != a) {
+                let distance = distances[index(a, candidate)];
+                if best.is_none_or(|(_, best)| distance < best) {
+                    best = Some((candidate, distance));
+                }
+            }
+            let (b, distance) = best.unwrap();
+            if Some(b) == previous {
+                chain.truncate(chain.len() - 2);
+                break (a, b, distance);
+            }
+            chain.push(b);
+        };
+
+        // Lance-Williams update, the merged cluster is stored at b
+        for k in (0..count).filter(|&k| active[k] && k != a && k != b) {
+            let merged = (sizes[a] * distances[index(k, a)] + sizes[b] * distances[index(k, b)]) / (sizes[a] + sizes[b]);
+            distances[index(k, b)] = merged;
+        }
+        sizes[b] += sizes[a];
+        active[a] = false;
+        merges.push((a, b, distance));
+    }
+    merges
+}
+
+fn find(parents: &mut [usize], mut node: usize) -> usize {
+    while parents[node] != node {
+        parents[node] = parents[parents[node]];
+        node = parents[node];
+    }
+    node
+}
+
+fn unit(x: &[f32]) -> Vec<f32> {
+    let norm = x.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 { x.iter().map(|value| value / norm).collect() } else { x.to_vec() }
+}
+
+/// Of unit length vectors, a zero vector is at distance 1 from everything.
+fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    (1.0 - a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>()).clamp(0.0, 2.0)
+}
+
+/// A row of a JSON lines file that should be clustered or assigned: `field` holds the text and
+/// `time_field` when it was seen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlaggedRow {
+    pub text: String,
+    pub seen_at: Option<u64>,
+}
+
+/// The text of the row if it was flagged: rows with a `score` field (like the output of `predict --jsonl`)
+/// are flagged at or above `threshold`, rows without one are all taken as flagged, failed rows
+/// (`score` null) never are.
+pub fn flagged_row(row: &Value, field: &str, time_field: Option<&str>, threshold: f32) -> anyhow::Result<Option<FlaggedRow>> {
+    match row.get("score") {
+        Some(Value::Number(score)) if score.as_f64().unwrap_or(0.0) < threshold as f64 => return Ok(None),
+        Some(Value::Number(_)) | None => {},
+        Some(_) => return Ok(None),
+    }
+    let text = row[field].as_str().ok_or_else(|| anyhow::anyhow!("Error: missing string field '{}'", field))?;
+    let seen_at = match time_field.map(|time_field| &row[time_field]) {
+        None | Some(Value::Null) => None,
+        Some(Value::Number(seconds)) => Some(seconds.as_f64().filter(|seconds| *seconds >= 0.0).map(|seconds| seconds as u64)
+            .ok_or_else(|| anyhow::anyhow!("Error: invalid timestamp {}", seconds))?),
+        Some(Value::String(timestamp)) => Some(parse_timestamp(timestamp)?),
+        Some(other) => return Err(anyhow::anyhow!("Error: invalid timestamp {}", other)),
+    };
+    Ok(Some(FlaggedRow { text: text.to_string(), seen_at }))
+}
+
+/// Unix seconds, or an RFC 3339 / ISO 8601 date or date-time (UTC unless an offset is given).
+pub fn parse_timestamp(timestamp: &str) -> anyhow::Result<u64> {
+    let timestamp = timestamp.trim();
+    if let Ok(seconds) = timestamp.parse::<u64>() {
+        return Ok(seconds);
+    }
+    let parsed = DateTime::parse_from_rfc3339(timestamp).map(|date_time| date_time.timestamp())
+        .or_else(|_| DateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f%#z").map(|date_time| date_time.timestamp()))
+        .or_else(|_| DateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f%#z").map(|date_time| date_time.timestamp()))
+        .or_else(|_| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f").map(|date_time| date_time.and_utc().timestamp()))
+        .or_else(|_| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f").map(|date_time| date_time.and_utc().timestamp()))
+        .or_else(|_| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M").map(|date_time| date_time.and_utc().timestamp()))
+        .or_else(|_| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M").map(|date_time| date_time.and_utc().timestamp()))
+        .or_else(|_| NaiveDate::parse_from_str(timestamp, "%Y-%m-%d").map(|date| date.and_time(NaiveTime::MIN).and_utc().timestamp()));
+    parsed.ok().and_then(|seconds| u64::try_from(seconds).ok())
+        .ok_or_else(|| anyhow::anyhow!("Error: invalid timestamp '{}'", timestamp))
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ`
+pub fn format_timestamp(seconds: u64) -> String {
+    i64::try_from(seconds).ok().and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+        .map(|date_time| date_time.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_else(|| seconds.to_string())
+}
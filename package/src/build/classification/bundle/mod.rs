@@ -0,0 +1,551 @@
+use std::collections::HashSet;
+use std::fs;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use smartcore::ensemble::random_forest_regressor::{RandomForestRegressor, RandomForestRegressorParameters};
+use smartcore::linalg::naive::dense_matrix::DenseMatrix;
+use smartcore::math::distance::euclidian::Euclidian;
+use smartcore::neighbors::knn_regressor::{KNNRegressor, KNNRegressorParameters};
+use smartcore::neighbors::KNNWeightFunction;
+
+use super::categories::{CategoryHead, CategoryModel, CategoryProbabilities};
+use super::conformal::ConformalCalibration;
+use super::feedback::FEEDBACK_SOURCE;
+use super::novelty::{Novelty, NoveltyCalibration};
+use crate::build::data::embedding_dataset::EmbeddingRecord;
+use crate::build::features::{with_signal_features, SIGNAL_FEATURE_LABELS};
+use crate::build::normalization::NormalizationConfig;
+use crate::build::row_hash;
+
+/// Version 2 added the training examples used for explanations, version 3 the novelty calibration,
+/// version 4 the conformal calibration, version 5 the fraud categories, version 6 random forest bundles
+/// and version 7 dropped the regressor of bundles with training examples.
+pub const BUNDLE_FORMAT_VERSION: u32 = 7;
+
+/// Length of the text snippets returned with the neighbors of an input.
+pub const SNIPPET_LENGTH: usize = 280;
+
+/// Describes how a model bundle was trained.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BundleMetadata {
+    pub format_version: u32,
+    pub model_type: String,
+    pub k: Option<usize>,
+    /// Unknown for models saved before bundles existed.
+    pub embedding_dimension: Option<usize>,
+    pub training_count: usize,
+    pub spam_count: usize,
+    pub ham_count: usize,
+    /// Seconds since the unix epoch.
+    pub created_at: u64,
+    /// Applied to the texts before they were embedded, inputs have to be normalized the same way.
+    #[serde(default)]
+    pub normalization: NormalizationConfig,
+    /// Training examples that were added as feedback after training.
+    #[serde(default)]
+    pub feedback_count: usize,
+    /// The signal features of the texts are appended to the embeddings (random forest only).
+    #[serde(default)]
+    pub signal_features: bool,
+}
+
+impl BundleMetadata {
+    fn new(model_type: &str, k: Option<usize>, embedding_dimension: usize, labels: &[f32]) -> Self {
+        let spam_count = labels.iter().filter(|&&label| label >= 0.5).count();
+        BundleMetadata {
+            format_version: BUNDLE_FORMAT_VERSION,
+            model_type: model_type.to_string(),
+            k,
+            embedding_dimension: Some(embedding_dimension),
+            training_count: labels.len(),
+            spam_count,
+            ham_count: labels.len() - spam_count,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            normalization: NormalizationConfig::default(),
+            feedback_count: 0,
+            signal_features: false,
+        }
+    }
+}
+
+/// A labeled training row, kept so predictions can be explained by their nearest neighbors.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TrainingExample {
+    /// `<source>:<row-hash>`, the same key the embeddings file uses (only the row-hash without a source).
+    pub id: String,
+    pub text: String,
+    pub source: String,
+    pub label: f32,
+    pub embedding: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+}
+
+impl From<&EmbeddingRecord> for TrainingExample {
+    fn from(record: &EmbeddingRecord) -> Self {
+        let hash = row_hash(&record.text);
+        TrainingExample {
+            id: if record.source.is_empty() { hash } else { format!("{}:{}", record.source, hash) },
+            text: record.text.clone(),
+            source: record.source.clone(),
+            label: record.label,
+            embedding: record.embedding.clone(),
+            categories: record.categories.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Neighbor {
+    pub id: String,
+    pub source: String,
+    pub label: f32,
+    pub distance: f32,
+    /// The first `SNIPPET_LENGTH` characters of the text.
+    pub text: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+}
+
+/// What a KNN bundle says about one input, all from a single search of the training examples.
+#[derive(Clone, Debug)]
+pub struct Assessment {
+    pub score: f32,
+    /// `None` for bundles without a novelty calibration.
+    pub novelty: Option<Novelty>,
+    /// `None` for bundles without categories.
+    pub categories: Option<CategoryProbabilities>,
+    /// The nearest training examples that were asked for, nearest first.
+    pub neighbors: Vec<Neighbor>,
+}
+
+/// A trained KNN model together with its metadata, stored as a single JSON file.
+///
+/// Bundles with training examples score inputs by searching the examples, so every embedding is stored once;
+/// only bundles without examples keep a fitted regressor.
+#[derive(Deserialize, Serialize)]
+pub struct ModelBundle {
+    pub metadata: BundleMetadata,
+    /// Only for bundles trained from bare embeddings or saved before format version 2.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regressor: Option<KNNRegressor<f32, Euclidian>>,
+    /// Empty for bundles trained from bare embeddings or saved before format version 2.
+    /// Shared with the bundles feedback is added to, so adding feedback does not copy them.
+    #[serde(default)]
+    pub examples: Arc<Vec<TrainingExample>>,
+    /// Feedback added since the bundle was loaded, searched next to the examples until it is compacted into them.
+    #[serde(skip)]
+    feedback: FeedbackIndex,
+    /// Computed from the examples, missing for bundles saved before format version 3.
+    #[serde(default)]
+    pub novelty: Option<NoveltyCalibration>,
+    /// Only present if the model was calibrated on held-out examples.
+    #[serde(default)]
+    pub conformal: Option<ConformalCalibration>,
+    /// Only present if the training examples have categories.
+    #[serde(default)]
+    pub categories: Option<CategoryModel>,
+}
+
+impl ModelBundle {
+    /// Fits a regressor on bare embeddings, the bundle can not explain its predictions.
+    pub fn fit(x_dataset: &[Vec<f32>], y_dataset: &[f32], k: usize) -> anyhow::Result<Self> {
+        if x_dataset.is_empty() || x_dataset.len() != y_dataset.len() {
+            return Err(anyhow::anyhow!("Error: expected the same non-zero number of embeddings and labels, got {} and {}", x_dataset.len(), y_dataset.len()));
+        }
+        let x = DenseMatrix::from_2d_array(&x_dataset.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
+        let y = y_dataset.to_vec();
+
+        let regressor = KNNRegressor::fit(&x, &y, KNNRegressorParameters::default().with_k(k).with_weight(KNNWeightFunction::Distance))
+            .map_err(|err| anyhow::anyhow!("Error: unable to fit KNN regressor: {}", err))?;
+
+        let metadata = BundleMetadata::new("knn", Some(k), x_dataset[0].len(), y_dataset);
+        Ok(ModelBundle { metadata, regressor: Some(regressor), examples: Arc::default(), feedback: FeedbackIndex::default(), novelty: None, conformal: None, categories: None })
+    }
+
+    /// Keeps the records as training examples, which are searched for the neighbors of every input.
+    ///
+    /// `normalization` is the one the texts of the records were normalized with before embedding.
+    pub fn fit_records(records: &[EmbeddingRecord], k: usize, normalization: &NormalizationConfig) -> anyhow::Result<Self> {
+        let examples: Vec<TrainingExample> = records.iter().map(TrainingExample::from).collect();
+        let mut bundle = Self::from_examples(examples, k)?;
+        bundle.metadata.normalization = *normalization;
+        Ok(bundle)
+    }
+
+    fn from_examples(examples: Vec<TrainingExample>, k: usize) -> anyhow::Result<Self> {
+        let Some(dimension) = examples.first().map(|example| example.embedding.len()) else {
+            return Err(anyhow::anyhow!("Error: expected a non-zero number of training examples"));
+        };
+        if let Some(example) = examples.iter().find(|example| example.embedding.len() != dimension) {
+            return Err(dimension_mismatch(example.embedding.len(), dimension));
+        }
+        if k == 0 || k > examples.len() {
+            return Err(anyhow::anyhow!("Error: k must be between 1 and the number of training examples ({}), got {}", examples.len(), k));
+        }
+        let labels: Vec<f32> = examples.iter().map(|example| example.label).collect();
+        let novelty = if examples.len() >= 2 {
+            let embeddings: Vec<&[f32]> = examples.iter().map(|example| &example.embedding[..]).collect();
+            Some(NoveltyCalibration::fit(&embeddings, k)?)
+        } else {
+            None
+        };
+        let metadata = BundleMetadata::new("knn", Some(k), dimension, &labels);
+        Ok(ModelBundle { metadata, regressor: None, examples: Arc::new(examples), feedback: FeedbackIndex::default(), novelty, conformal: None, categories: None })
+    }
+
+    /// Adds `examples` to a side index that is searched next to the training examples, an example replaces
+    /// the existing ones with the same id or the same text (e.g. feedback relabeling a training example).
+    ///
+    /// The training examples are shared with this bundle and nothing is refitted: the novelty and conformal
+    /// calibrations and the linear category heads stay the ones of the training examples until `compacted`.
+    pub fn with_feedback(&self, examples: &[TrainingExample]) -> anyhow::Result<Self> {
+        let Some(dimension) = self.examples.first().map(|example| example.embedding.len()) else {
+            return Err(anyhow::anyhow!("Error: the model has no training examples, retrain it to add examples"));
+        };
+        if let Some(example) = examples.iter().find(|example| example.embedding.len() != dimension) {
+            return Err(dimension_mismatch(example.embedding.len(), dimension));
+        }
+
+        // the latest example with an id or text wins, which drops the earlier ones with either
+        let (mut ids, mut texts) = (HashSet::new(), HashSet::new());
+        let mut latest: Vec<TrainingExample> = self.feedback.examples.iter().chain(examples).rev()
+            .filter(|example| {
+                let latest = !ids.contains(&example.id) && !texts.contains(&example.text);
+                ids.insert(example.id.clone());
+                texts.insert(example.text.clone());
+                latest
+            })
+            .cloned()
+            .collect();
+        latest.reverse();
+        let replaced: HashSet<usize> = self.examples.iter().enumerate()
+            .filter(|(_, example)| ids.contains(&example.id) || texts.contains(&example.text))
+            .map(|(index, _)| index)
+            .collect();
+
+        let feedback = FeedbackIndex { examples: latest, replaced };
+        let mut metadata = self.metadata.clone();
+        let labels: Vec<f32> = feedback.iter(&self.examples).map(|example| example.label).collect();
+        metadata.training_count = labels.len();
+        metadata.spam_count = labels.iter().filter(|&&label| label >= 0.5).count();
+        metadata.ham_count = labels.len() - metadata.spam_count;
+        metadata.feedback_count = feedback.iter(&self.examples).filter(|example| example.source == FEEDBACK_SOURCE).count();
+        Ok(ModelBundle {
+            metadata,
+            regressor: None,
+            examples: Arc::clone(&self.examples),
+            feedback,
+            novelty: self.novelty.clone(),
+            conformal: self.conformal.clone(),
+            categories: self.categories.clone(),
+        })
+    }
+
+    /// Folds the feedback into the training examples and refits the novelty calibration on them,
+    /// the conformal calibration and the linear category heads are kept.
+    pub fn compacted(&self) -> anyhow::Result<Self> {
+        let examples: Vec<TrainingExample> = self.all_examples().cloned().collect();
+        let mut bundle = Self::from_examples(examples, self.k())?;
+        bundle.metadata.normalization = self.metadata.normalization;
+        bundle.metadata.feedback_count = self.metadata.feedback_count;
+        bundle.conformal = self.conformal.clone();
+        bundle.categories = self.categories.clone();
+        Ok(bundle)
+    }
+
+    /// The training examples that are searched: the ones not replaced by feedback, followed by the feedback.
+    pub fn all_examples(&self) -> impl Iterator<Item = &TrainingExample> {
+        self.feedback.iter(&self.examples)
+    }
+
+    /// Calibrates conformal prediction sets on `records`, which must not be part of the training examples.
+    pub fn calibrate(&mut self, records: &[EmbeddingRecord], error_level: f32) -> anyhow::Result<()> {
+        let x_dataset: Vec<Vec<f32>> = records.iter().map(|record| record.embedding.clone()).collect();
+        let labels: Vec<f32> = records.iter().map(|record| record.label).collect();
+        self.conformal = Some(ConformalCalibration::fit(&self.predict(&x_dataset)?, &labels, error_level)?);
+        Ok(())
+    }
+
+    /// Fits a head per fraud category of the training examples, no-op if none of them has a category.
+    pub fn fit_categories(&mut self, head: CategoryHead) -> anyhow::Result<()> {
+        self.categories = CategoryModel::fit(&self.examples, head)?;
+        Ok(())
+    }
+
+    /// `None` for bundles without categories.
+    pub fn category_probabilities(&self, x: &[f32]) -> anyhow::Result<Option<CategoryProbabilities>> {
+        if self.categories.is_none() {
+            return Ok(None);
+        }
+        Ok(self.assess(x, 0)?.categories)
+    }
+
+    /// Loads a bundle, or a bare regressor as written by earlier versions.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path).map_err(|err| anyhow::anyhow!("Error: unable to read '{}': {}", path, err))?;
+        match serde_json::from_str::<ModelBundle>(&contents) {
+            Ok(mut bundle) => {
+                // bundles saved before format version 7 store the embeddings of the examples in the regressor as well
+                if !bundle.examples.is_empty() {
+                    bundle.regressor = None;
+                }
+                Ok(bundle)
+            },
+            Err(bundle_err) => match serde_json::from_str::<Option<KNNRegressor<f32, Euclidian>>>(&contents) {
+                Ok(Some(regressor)) => Ok(ModelBundle {
+                    metadata: BundleMetadata { model_type: "knn".to_string(), ..Default::default() },
+                    regressor: Some(regressor),
+                    examples: Arc::default(),
+                    feedback: FeedbackIndex::default(),
+                    novelty: None,
+                    conformal: None,
+                    categories: None,
+                }),
+                _ if super::ModelType::detect(path).ok() == Some(super::ModelType::RandomForest) => {
+                    Err(anyhow::anyhow!("Error: '{}' is a random forest model, only KNN models are supported here", path))
+                },
+                _ => Err(anyhow::anyhow!("Error: unable to load '{}': {}", path, bundle_err)),
+            },
+        }
+    }
+
+    /// Writes to a temporary file first, so readers never see a partially written bundle.
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Errors if `x` does not have the embedding dimension of the model, passes if the dimension is unknown.
+    pub fn check_dimension(&self, x: &[f32]) -> anyhow::Result<()> {
+        match self.metadata.embedding_dimension.or(self.examples.first().map(|example| example.embedding.len())) {
+            Some(dimension) if x.len() != dimension => Err(dimension_mismatch(x.len(), dimension)),
+            _ => Ok(()),
+        }
+    }
+
+    fn k(&self) -> usize {
+        self.metadata.k.unwrap_or(super::KNN_DEFAULT_K)
+    }
+
+    pub fn predict(&self, x_dataset: &[Vec<f32>]) -> anyhow::Result<Vec<f32>> {
+        if x_dataset.is_empty() {
+            return Ok(Vec::new());
+        }
+        for x in x_dataset {
+            self.check_dimension(x)?;
+        }
+        match &self.regressor {
+            Some(regressor) if self.examples.is_empty() => {
+                let x = DenseMatrix::from_2d_array(&x_dataset.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
+                regressor.predict(&x).map_err(|err| anyhow::anyhow!("Error: prediction failed: {}", err))
+            },
+            _ => Ok(x_dataset.par_iter().map(|x| weighted_label(&self.nearest(x, self.k()))).collect()),
+        }
+    }
+
+    /// Scores `x` and returns its `explain` nearest training examples, with the novelty and the category
+    /// probabilities computed from the same neighbors.
+    pub fn assess(&self, x: &[f32], explain: usize) -> anyhow::Result<Assessment> {
+        self.check_dimension(x)?;
+        if self.examples.is_empty() {
+            if explain > 0 {
+                return Err(anyhow::anyhow!("Error: the model has no training examples, retrain it to enable explanations"));
+            }
+            let score = self.predict(&[x.to_vec()])?.remove(0);
+            let categories = match &self.categories {
+                Some(model) if model.head == CategoryHead::Linear => Some(model.linear_probabilities(x)),
+                Some(_) => return Err(anyhow::anyhow!("Error: the model has no training examples for its category head")),
+                None => None,
+            };
+            return Ok(Assessment { score, novelty: None, categories, neighbors: Vec::new() });
+        }
+
+        let k = self.k();
+        let category_k = if self.categories.as_ref().is_some_and(|model| model.head == CategoryHead::Knn) { k } else { 0 };
+        let novelty_k = self.novelty.as_ref().map_or(0, |calibration| calibration.k);
+        let nearest = self.nearest(x, k.max(novelty_k).max(explain));
+
+        let score = weighted_label(&nearest[..k.min(nearest.len())]);
+        let novelty = self.novelty.as_ref().map(|calibration| {
+            let nearest = &nearest[..calibration.k.clamp(1, nearest.len())];
+            calibration.novelty(nearest.iter().map(|(distance, _)| distance).sum::<f32>() / nearest.len() as f32)
+        });
+        let neighbors: Vec<Neighbor> = nearest.iter().take(category_k.max(explain)).map(|(distance, example)| Neighbor {
+            id: example.id.clone(),
+            source: example.source.clone(),
+            label: example.label,
+            distance: *distance,
+            text: example.text.chars().take(SNIPPET_LENGTH).collect(),
+            categories: example.categories.clone(),
+        }).collect();
+        let categories = match &self.categories {
+            Some(model) if model.head == CategoryHead::Knn => Some(model.knn_probabilities(&neighbors[..k.min(neighbors.len())])),
+            Some(model) => Some(model.linear_probabilities(x)),
+            None => None,
+        };
+        Ok(Assessment { score, novelty, categories, neighbors: neighbors.into_iter().take(explain).collect() })
+    }
+
+    /// The `count` training examples closest to `x` (euclidean distance) and their distances, nearest first.
+    fn nearest(&self, x: &[f32], count: usize) -> Vec<(f32, &TrainingExample)> {
+        let mut distances: Vec<(f32, &TrainingExample)> = self.all_examples()
+            .map(|example| (euclidean_distance(&example.embedding, x), example))
+            .collect();
+        let count = count.min(distances.len());
+        if count == 0 {
+            return Vec::new();
+        }
+        if count < distances.len() {
+            distances.select_nth_unstable_by(count - 1, |(a, _), (b, _)| a.total_cmp(b));
+            distances.truncate(count);
+        }
+        distances.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        distances
+    }
+
+    /// The `k` training examples closest to `x` (euclidean distance, like the score), nearest first.
+    pub fn neighbors(&self, x: &[f32], k: usize) -> anyhow::Result<Vec<Neighbor>> {
+        if self.examples.is_empty() {
+            return Err(anyhow::anyhow!("Error: the model has no training examples, retrain it to enable explanations"));
+        }
+        Ok(self.assess(x, k)?.neighbors)
+    }
+
+    /// How unusual `x` is compared to the training examples, `None` for bundles without a novelty calibration.
+    pub fn novelty(&self, x: &[f32]) -> Option<Novelty> {
+        self.novelty.as_ref()?;
+        self.assess(x, 0).ok()?.novelty
+    }
+}
+
+/// Feedback examples of a bundle, kept apart from the training examples so adding one does not copy them.
+#[derive(Clone, Debug, Default)]
+struct FeedbackIndex {
+    examples: Vec<TrainingExample>,
+    /// Positions of the training examples with the id or text of a feedback example.
+    replaced: HashSet<usize>,
+}
+
+impl FeedbackIndex {
+    fn iter<'a>(&'a self, training: &'a [TrainingExample]) -> impl Iterator<Item = &'a TrainingExample> {
+        training.iter().enumerate()
+            .filter(|(index, _)| !self.replaced.contains(index))
+            .map(|(_, example)| example)
+            .chain(self.examples.iter())
+    }
+}
+
+/// A trained random forest regressor together with its metadata, stored as a single JSON file.
+#[derive(Deserialize, Serialize)]
+pub struct RandomForestBundle {
+    pub metadata: BundleMetadata,
+    pub regressor: RandomForestRegressor<f32>,
+}
+
+impl RandomForestBundle {
+    pub fn fit(x_dataset: &[Vec<f32>], y_dataset: &[f32]) -> anyhow::Result<Self> {
+        if x_dataset.is_empty() || x_dataset.len() != y_dataset.len() {
+            return Err(anyhow::anyhow!("Error: expected the same non-zero number of embeddings and labels, got {} and {}", x_dataset.len(), y_dataset.len()));
+        }
+        let x = DenseMatrix::from_2d_array(&x_dataset.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
+        let regressor = RandomForestRegressor::fit(&x, &y_dataset.to_vec(), RandomForestRegressorParameters::default().with_n_trees(32).with_min_samples_leaf(4))
+            .map_err(|err| anyhow::anyhow!("Error: unable to fit random forest regressor: {}", err))?;
+
+        let metadata = BundleMetadata::new("random_forest", None, x_dataset[0].len(), y_dataset);
+        Ok(RandomForestBundle { metadata, regressor })
+    }
+
+    /// Fits on the embeddings of the records, followed by the signal features of their texts if `signal_features` is set.
+    ///
+    /// `normalization` is the one the texts of the records were normalized with before embedding.
+    pub fn fit_records(records: &[EmbeddingRecord], signal_features: bool, normalization: &NormalizationConfig) -> anyhow::Result<Self> {
+        let x_dataset: Vec<Vec<f32>> = records.iter().map(|record| {
+            if signal_features { with_signal_features(&record.embedding, &record.text) } else { record.embedding.clone() }
+        }).collect();
+        let y_dataset: Vec<f32> = records.iter().map(|record| record.label).collect();
+        let mut bundle = Self::fit(&x_dataset, &y_dataset)?;
+        bundle.metadata.embedding_dimension = records.first().map(|record| record.embedding.len());
+        bundle.metadata.normalization = *normalization;
+        bundle.metadata.signal_features = signal_features;
+        Ok(bundle)
+    }
+
+    /// Loads a bundle, or a bare regressor as written by earlier versions.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path).map_err(|err| anyhow::anyhow!("Error: unable to read '{}': {}", path, err))?;
+        match serde_json::from_str::<RandomForestBundle>(&contents) {
+            Ok(bundle) => Ok(bundle),
+            Err(bundle_err) => match serde_json::from_str::<Option<RandomForestRegressor<f32>>>(&contents) {
+                Ok(Some(regressor)) => Ok(RandomForestBundle {
+                    metadata: BundleMetadata { model_type: "random_forest".to_string(), ..Default::default() },
+                    regressor,
+                }),
+                _ => Err(anyhow::anyhow!("Error: unable to load '{}': {}", path, bundle_err)),
+            },
+        }
+    }
+
+    /// Writes to a temporary file first, so readers never see a partially written bundle.
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Number of inputs the regressor expects, unknown for regressors saved before bundles existed.
+    pub fn feature_count(&self) -> Option<usize> {
+        let signal_count = if self.metadata.signal_features { SIGNAL_FEATURE_LABELS.len() } else { 0 };
+        self.metadata.embedding_dimension.map(|dimension| dimension + signal_count)
+    }
+
+    /// Scores rows of `feature_count` features, see `fit_records`.
+    pub fn predict(&self, x_dataset: &[Vec<f32>]) -> anyhow::Result<Vec<f32>> {
+        if x_dataset.is_empty() {
+            return Ok(Vec::new());
+        }
+        let feature_count = self.feature_count().unwrap_or(x_dataset[0].len());
+        if let Some(x) = x_dataset.iter().find(|x| x.len() != feature_count) {
+            return Err(if self.metadata.signal_features {
+                anyhow::anyhow!("Error: got {} features, the model expects {} (embeddings followed by the signal features)", x.len(), feature_count)
+            } else {
+                dimension_mismatch(x.len(), feature_count)
+            });
+        }
+        let x = DenseMatrix::from_2d_array(&x_dataset.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
+        self.regressor.predict(&x).map_err(|err| anyhow::anyhow!("Error: prediction failed: {}", err))
+    }
+}
+
+/// Typically the inputs were embedded by another embedder (e.g. the offline one) than the training examples.
+fn dimension_mismatch(dimension: usize, expected: usize) -> anyhow::Error {
+    anyhow::anyhow!("Error: embedding dimension {} does not match the model ({}), embed with the embedder the model was trained with", dimension, expected)
+}
+
+/// Weights of neighbors at these distances, like a distance-weighted KNN regressor: the inverse distance,
+/// or only the neighbors at distance 0 if there are any.
+pub fn distance_weights(distances: &[f32]) -> Vec<f32> {
+    if distances.contains(&0.0) {
+        distances.iter().map(|&distance| if distance == 0.0 { 1.0 } else { 0.0 }).collect()
+    } else {
+        distances.iter().map(|&distance| 1.0 / distance).collect()
+    }
+}
+
+/// The distance-weighted mean label of the neighbors.
+fn weighted_label(neighbors: &[(f32, &TrainingExample)]) -> f32 {
+    let distances: Vec<f32> = neighbors.iter().map(|(distance, _)| *distance).collect();
+    let weights = distance_weights(&distances);
+    let total: f32 = weights.iter().sum();
+    neighbors.iter().zip(&weights).map(|((_, example), weight)| example.label * weight).sum::<f32>() / total
+}
+
+fn euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| (a - b) * (a - b)).sum::<f32>().sqrt()
+}
@@ -0,0 +1,214 @@
+//! Fraud categories (phishing airdrop, fake upgrade, funding grab, ...) predicted next to the fraud score,
+//! with one head per category on the same embeddings as the fraud model.
+
+use std::collections::BTreeMap;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::bundle::{distance_weights, Neighbor, TrainingExample};
+
+/// Category name to the probability that a text belongs to it. Every category has its own head,
+/// so the probabilities do not have to add up to 1 (a text can belong to several categories or to none).
+pub type CategoryProbabilities = BTreeMap<String, f32>;
+
+/// Probabilities at or above this count as a prediction of the category in evaluations.
+pub const CATEGORY_THRESHOLD: f32 = 0.5;
+
+const LINEAR_EPOCHS: usize = 300;
+const LINEAR_LEARNING_RATE: f32 = 0.05;
+const LINEAR_L2: f32 = 1e-3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CategoryHead {
+    /// Distance-weighted share of the nearest training examples in the category.
+    Knn,
+    /// One logistic regression per category.
+    Linear,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LinearHead {
+    pub weights: Vec<f32>,
+    pub bias: f32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CategoryModel {
+    /// Sorted by name.
+    pub categories: Vec<String>,
+    /// Training examples per category, in the order of `categories`.
+    pub counts: Vec<usize>,
+    /// Some training examples belong to more than one category.
+    pub multi_label: bool,
+    pub head: CategoryHead,
+    /// Standardization of the embeddings for the linear heads, empty for KNN heads.
+    #[serde(default)]
+    pub mean: Vec<f32>,
+    #[serde(default)]
+    pub scale: Vec<f32>,
+    /// One per category for linear heads, empty for KNN heads.
+    #[serde(default)]
+    pub linear: Vec<LinearHead>,
+}
+
+impl CategoryModel {
+    /// Fits a head per category found in the examples, `None` if none of them has a category.
+    pub fn fit(examples: &[TrainingExample], head: CategoryHead) -> anyhow::Result<Option<Self>> {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for example in examples {
+            for category in &example.categories {
+                *counts.entry(category.clone()).or_default() += 1;
+            }
+        }
+        if counts.is_empty() {
+            return Ok(None);
+        }
+        let multi_label = examples.iter().any(|example| example.categories.len() > 1);
+        let (categories, counts): (Vec<String>, Vec<usize>) = counts.into_iter().unzip();
+        let mut model = CategoryModel { categories, counts, multi_label, head, mean: Vec::new(), scale: Vec::new(), linear: Vec::new() };
+
+        if head == CategoryHead::Linear {
+            let embeddings: Vec<&[f32]> = examples.iter().map(|example| &example.embedding[..]).collect();
+            let (mean, scale) = standardization(&embeddings)?;
+            let x: Vec<Vec<f32>> = embeddings.iter().map(|embedding| standardize(embedding, &mean, &scale)).collect();
+            model.linear = model.categories.par_iter().map(|category| {
+                let y: Vec<f32> = examples.iter().map(|example| if example.categories.contains(category) { 1.0 } else { 0.0 }).collect();
+                fit_logistic_regression(&x, &y)
+            }).collect();
+            model.mean = mean;
+            model.scale = scale;
+        }
+        Ok(Some(model))
+    }
+
+    /// Share of the neighbors in each category, weighted by inverse distance like the fraud score.
+    /// Neighbors at distance 0 outweigh all others.
+    pub fn knn_probabilities(&self, neighbors: &[Neighbor]) -> CategoryProbabilities {
+        let distances: Vec<f32> = neighbors.iter().map(|neighbor| neighbor.distance).collect();
+        let weighted: Vec<(f32, &Neighbor)> = distance_weights(&distances).into_iter().zip(neighbors).collect();
+        let total: f32 = weighted.iter().map(|(weight, _)| weight).sum();
+
+        self.categories.iter().map(|category| {
+            let weight: f32 = weighted.iter().filter(|(_, neighbor)| neighbor.categories.contains(category)).map(|(weight, _)| weight).sum();
+            (category.clone(), if total > 0.0 { weight / total } else { 0.0 })
+        }).collect()
+    }
+
+    pub fn linear_probabilities(&self, x: &[f32]) -> CategoryProbabilities {
+        let x = standardize(x, &self.mean, &self.scale);
+        self.categories.iter().zip(&self.linear).map(|(category, head)| {
+            (category.clone(), sigmoid(dot(&head.weights, &x) + head.bias))
+        }).collect()
+    }
+}
+
+fn standardization(embeddings: &[&[f32]]) -> anyhow::Result<(Vec<f32>, Vec<f32>)> {
+    let dimension = embeddings.first().map(|embedding| embedding.len()).unwrap_or(0);
+    if dimension == 0 {
+        return Err(anyhow::anyhow!("Error: no embeddings to fit category heads on"));
+    }
+    let count = embeddings.len() as f32;
+    let mut mean = vec![0.0; dimension];
+    for embedding in embeddings {
+        for (sum, value) in mean.iter_mut().zip(embedding.iter()) {
+            *sum += value / count;
+        }
+    }
+    let mut variance = vec![0.0; dimension];
+    for embedding in embeddings {
+        for ((sum, value), mean) in variance.iter_mut().zip(embedding.iter()).zip(&mean) {
+            *sum += (value - mean) * (value - mean) / count;
+        }
+    }
+    let scale = variance.into_iter().map(|variance: f32| if variance > 1e-12 { 1.0 / variance.sqrt() } else { 1.0 }).collect();
+    Ok((mean, scale))
+}
+
+fn standardize(x: &[f32], mean: &[f32], scale: &[f32]) -> Vec<f32> {
+    x.iter().zip(mean).zip(scale).map(|((value, mean), scale)| (value - mean) * scale).collect()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn sigmoid(z: f32) -> f32 {
+    1.0 / (1.0 + (-z).exp())
+}
+
+/// Full-batch gradient descent with Adam and a little L2 regularization, which keeps the weights
+/// finite on separable categories.
+fn fit_logistic_regression(x: &[Vec<f32>], y: &[f32]) -> LinearHead {
+    let dimension = x[0].len();
+    let count = x.len() as f32;
+    let prior = (y.iter().sum::<f32>() / count).clamp(1e-4, 1.0 - 1e-4);
+    let mut head = LinearHead { weights: vec![0.0; dimension], bias: (prior / (1.0 - prior)).ln() };
+
+    let (beta1, beta2, epsilon) = (0.9f32, 0.999f32, 1e-8f32);
+    let (mut m, mut v) = (vec![0.0f32; dimension + 1], vec![0.0f32; dimension + 1]);
+    for epoch in 1..=LINEAR_EPOCHS {
+        let mut gradient = vec![0.0f32; dimension + 1];
+        for (x, y) in x.iter().zip(y) {
+            let error = sigmoid(dot(&head.weights, x) + head.bias) - y;
+            for (gradient, value) in gradient.iter_mut().zip(x) {
+                *gradient += error * value / count;
+            }
+            gradient[dimension] += error / count;
+        }
+        for (gradient, weight) in gradient.iter_mut().zip(&head.weights) {
+            *gradient += LINEAR_L2 * weight;
+        }
+
+        let correction1 = 1.0 - beta1.powi(epoch as i32);
+        let correction2 = 1.0 - beta2.powi(epoch as i32);
+        for (index, gradient) in gradient.into_iter().enumerate() {
+            m[index] = beta1 * m[index] + (1.0 - beta1) * gradient;
+            v[index] = beta2 * v[index] + (1.0 - beta2) * gradient * gradient;
+            let step = LINEAR_LEARNING_RATE * (m[index] / correction1) / ((v[index] / correction2).sqrt() + epsilon);
+            match head.weights.get_mut(index) {
+                Some(weight) => *weight -= step,
+                None => head.bias -= step,
+            }
+        }
+    }
+    head
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CategoryMetrics {
+    pub category: String,
+    /// Examples of the category.
+    pub support: usize,
+    pub predicted_count: usize,
+    pub true_positive_count: usize,
+    pub precision: f32,
+    pub recall: f32,
+    pub f_score: f32,
+}
+
+/// Precision and recall per category, a category counts as predicted at or above `CATEGORY_THRESHOLD`.
+pub fn category_metrics(categories: &[String], predictions: &[CategoryProbabilities], truth: &[Vec<String>]) -> Vec<CategoryMetrics> {
+    categories.iter().map(|category| {
+        let (mut support, mut predicted_count, mut true_positive_count) = (0, 0, 0);
+        for (probabilities, truth) in predictions.iter().zip(truth) {
+            let actual = truth.contains(category);
+            let predicted = probabilities.get(category).is_some_and(|probability| *probability >= CATEGORY_THRESHOLD);
+            support += actual as usize;
+            predicted_count += predicted as usize;
+            true_positive_count += (actual && predicted) as usize;
+        }
+        let precision = true_positive_count as f32 / predicted_count as f32;
+        let recall = true_positive_count as f32 / support as f32;
+        CategoryMetrics {
+            category: category.clone(),
+            support,
+            predicted_count,
+            true_positive_count,
+            precision,
+            recall,
+            f_score: 2.0 * precision * recall / (precision + recall),
+        }
+    }).collect()
+}
@@ -0,0 +1,121 @@
+//! Split-conformal prediction sets: calibrated on examples the model was not trained on, the set
+//! returned for an input contains its true label with probability at least `1 - error_level`.
+//!
+//! The nonconformity of a label is how badly the score disagrees with it: `1 - score` for fraud, `score` for ham.
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_ERROR_LEVEL: f32 = 0.1;
+
+/// Error levels reported by `coverage_reports` next to the calibrated one.
+pub const REPORTED_ERROR_LEVELS: [f32; 4] = [0.01, 0.05, 0.1, 0.2];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConformalLabel {
+    Fraud,
+    Ham,
+}
+
+impl ConformalLabel {
+    /// Labels at or above 0.5 are fraud.
+    pub fn from_label(label: f32) -> Self {
+        if label >= 0.5 { ConformalLabel::Fraud } else { ConformalLabel::Ham }
+    }
+
+    pub fn nonconformity(&self, score: f32) -> f32 {
+        match self {
+            ConformalLabel::Fraud => 1.0 - score,
+            ConformalLabel::Ham => score,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ConformalCalibration {
+    /// Prediction sets are built for this error level unless another one is requested.
+    pub error_level: f32,
+    /// Nonconformity of every calibration example with its true label, ascending.
+    pub nonconformity: Vec<f32>,
+}
+
+pub fn validate_error_level(error_level: f32) -> anyhow::Result<()> {
+    if !(error_level > 0.0 && error_level < 1.0) {
+        return Err(anyhow::anyhow!("Error: the error level must be between 0 and 1 (exclusive), got {}", error_level));
+    }
+    Ok(())
+}
+
+impl ConformalCalibration {
+    /// Calibrates on the scores the model gives to held-out examples with the given labels.
+    pub fn fit(scores: &[f32], labels: &[f32], error_level: f32) -> anyhow::Result<Self> {
+        validate_error_level(error_level)?;
+        if scores.is_empty() || scores.len() != labels.len() {
+            return Err(anyhow::anyhow!("Error: expected the same non-zero number of calibration scores and labels, got {} and {}", scores.len(), labels.len()));
+        }
+        let mut nonconformity: Vec<f32> = scores.iter().zip(labels)
+            .map(|(score, label)| ConformalLabel::from_label(*label).nonconformity(*score))
+            .collect();
+        nonconformity.sort_by(|a, b| a.total_cmp(b));
+        Ok(ConformalCalibration { error_level, nonconformity })
+    }
+
+    /// The `ceil((n + 1)(1 - error_level))`-th smallest nonconformity, or infinity (every label is included)
+    /// when there are too few calibration examples to guarantee `error_level`.
+    pub fn quantile(&self, error_level: f32) -> f32 {
+        let n = self.nonconformity.len();
+        // the epsilon keeps e.g. 100 * 0.9 from rounding up to the next rank
+        let rank = ((n + 1) as f64 * (1.0 - error_level as f64) - 1e-9).ceil().max(1.0) as usize;
+        if rank > n { f32::INFINITY } else { self.nonconformity[rank - 1] }
+    }
+
+    /// Every label whose nonconformity is within the quantile of `error_level`, fraud first.
+    pub fn prediction_set(&self, score: f32, error_level: f32) -> Vec<ConformalLabel> {
+        let quantile = self.quantile(error_level);
+        [ConformalLabel::Fraud, ConformalLabel::Ham].into_iter()
+            .filter(|label| label.nonconformity(score) <= quantile)
+            .collect()
+    }
+
+    /// How often the prediction sets for `scores` contain the true label, at the calibrated and the usual error levels.
+    pub fn coverage_reports(&self, scores: &[f32], labels: &[f32]) -> Vec<CoverageReport> {
+        let mut error_levels: Vec<f32> = REPORTED_ERROR_LEVELS.to_vec();
+        if !error_levels.contains(&self.error_level) {
+            error_levels.push(self.error_level);
+            error_levels.sort_by(|a, b| a.total_cmp(b));
+        }
+        error_levels.into_iter().map(|error_level| self.coverage(scores, labels, error_level)).collect()
+    }
+
+    pub fn coverage(&self, scores: &[f32], labels: &[f32], error_level: f32) -> CoverageReport {
+        let mut report = CoverageReport { error_level, quantile: self.quantile(error_level), total_count: scores.len(), ..Default::default() };
+        for (score, label) in scores.iter().zip(labels) {
+            let set = self.prediction_set(*score, error_level);
+            if set.contains(&ConformalLabel::from_label(*label)) {
+                report.covered_count += 1;
+            }
+            match set.len() {
+                0 => report.empty_count += 1,
+                1 => report.single_count += 1,
+                _ => report.both_count += 1,
+            }
+        }
+        report.coverage = report.covered_count as f32 / report.total_count.max(1) as f32;
+        report
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CoverageReport {
+    pub error_level: f32,
+    pub quantile: f32,
+    pub total_count: usize,
+    /// Examples whose prediction set contains their true label.
+    pub covered_count: usize,
+    /// Should be at least `1 - error_level`.
+    pub coverage: f32,
+    pub single_count: usize,
+    /// Uncertain examples, both labels are in the set.
+    pub both_count: usize,
+    pub empty_count: usize,
+}
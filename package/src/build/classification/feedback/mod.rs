@@ -0,0 +1,153 @@
+//! Moderator feedback: labeled examples added to a trained KNN model without retraining.
+//!
+//! Every example is appended to a log next to the model bundle (`<model>.feedback.jsonl`) and
+//! replayed whenever the bundle is loaded, until `compact` folds the log into the bundle itself.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::bundle::{ModelBundle, TrainingExample};
+use crate::build::row_hash;
+
+/// Source of the training examples added as feedback.
+pub const FEEDBACK_SOURCE: &str = "feedback";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FeedbackEntry {
+    pub text: String,
+    pub label: f32,
+    /// Embedding of the normalized text, the way the model embeds its inputs.
+    pub embedding: Vec<f32>,
+    /// Seconds since the unix epoch.
+    pub created_at: u64,
+}
+
+impl FeedbackEntry {
+    pub fn new(text: &str, label: f32, embedding: Vec<f32>) -> anyhow::Result<Self> {
+        if !(0.0..=1.0).contains(&label) {
+            return Err(anyhow::anyhow!("Error: the label must be between 0 and 1, got {}", label));
+        }
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Ok(FeedbackEntry { text: text.to_string(), label, embedding, created_at })
+    }
+
+    /// Feedback on the same text has the same id, so the latest label wins.
+    pub fn id(&self) -> String {
+        format!("{}:{}", FEEDBACK_SOURCE, row_hash(&self.text))
+    }
+
+    pub fn to_example(&self) -> TrainingExample {
+        TrainingExample {
+            id: self.id(),
+            text: self.text.clone(),
+            source: FEEDBACK_SOURCE.to_string(),
+            label: self.label,
+            embedding: self.embedding.clone(),
+            categories: Vec::new(),
+        }
+    }
+}
+
+pub fn log_path(model_path: &str) -> String {
+    format!("{}.feedback.jsonl", model_path)
+}
+
+/// The log while it is being compacted, replayed as well in case compaction was interrupted.
+fn compacting_path(model_path: &str) -> String {
+    format!("{}.compacting", log_path(model_path))
+}
+
+/// Appends the entry to the feedback log of the model and syncs it to disk.
+pub fn append(model_path: &str, entry: &FeedbackEntry) -> anyhow::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    let mut file = File::options().create(true).append(true).open(log_path(model_path))?;
+    // a single write per entry, so only the entry being written during a crash can be incomplete
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// All logged feedback of the model, oldest first. Lines that can not be parsed (e.g. cut off by a crash) are skipped.
+pub fn read_log(model_path: &str) -> anyhow::Result<Vec<FeedbackEntry>> {
+    let mut entries = read_entries(&compacting_path(model_path))?;
+    entries.extend(read_entries(&log_path(model_path))?);
+    Ok(entries)
+}
+
+fn read_entries(path: &str) -> anyhow::Result<Vec<FeedbackEntry>> {
+    if !Path::new(path).exists() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<FeedbackEntry>(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => warn!(path, line = index + 1, error = %err, "skipping invalid feedback entry"),
+        }
+    }
+    Ok(entries)
+}
+
+/// Adds the feedback entries to the bundle, next to its training examples.
+pub fn apply(bundle: &ModelBundle, entries: &[FeedbackEntry]) -> anyhow::Result<ModelBundle> {
+    let examples: Vec<TrainingExample> = entries.iter().map(FeedbackEntry::to_example).collect();
+    bundle.with_feedback(&examples)
+}
+
+/// Loads the bundle with its logged feedback applied. A log that does not fit the bundle
+/// (e.g. left behind by a model trained on other embeddings) is ignored with a warning.
+pub fn load_bundle(model_path: &str) -> anyhow::Result<ModelBundle> {
+    let bundle = ModelBundle::load(model_path)?;
+    let entries = read_log(model_path)?;
+    if entries.is_empty() {
+        return Ok(bundle);
+    }
+    match apply(&bundle, &entries) {
+        Ok(updated) => Ok(updated),
+        Err(err) => {
+            warn!(path = model_path, error = %err, "ignoring the feedback log");
+            Ok(bundle)
+        },
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CompactionReport {
+    /// Logged entries folded into the bundle.
+    pub entry_count: usize,
+    pub feedback_count: usize,
+    pub training_count: usize,
+}
+
+/// Folds the feedback log into the bundle file and removes the log, refitting the novelty calibration.
+///
+/// The log is moved aside first, so feedback appended during compaction goes to a new log
+/// that is replayed on top of the compacted bundle. A log left aside by an interrupted
+/// compaction is folded in first, the new log then waits for the next compaction.
+pub fn compact(model_path: &str) -> anyhow::Result<CompactionReport> {
+    let compacting = compacting_path(model_path);
+    if !Path::new(&compacting).exists() && Path::new(&log_path(model_path)).exists() {
+        fs::rename(log_path(model_path), &compacting)?;
+    }
+    let entries = read_entries(&compacting)?;
+
+    let bundle = ModelBundle::load(model_path)?;
+    let bundle = if entries.is_empty() { bundle } else { apply(&bundle, &entries)?.compacted()? };
+    bundle.save(model_path)?;
+    if Path::new(&compacting).exists() {
+        fs::remove_file(&compacting)?;
+    }
+
+    info!(path = model_path, entry_count = entries.len(), "compacted feedback log");
+    Ok(CompactionReport { entry_count: entries.len(), feedback_count: bundle.metadata.feedback_count, training_count: bundle.metadata.training_count })
+}
@@ -1,167 +1,298 @@
 use std::cmp::Ordering;
 
+pub mod bundle;
+pub mod categories;
+pub mod conformal;
+pub mod feedback;
+pub mod novelty;
+pub mod self_training;
+
+use bundle::{ModelBundle, RandomForestBundle};
+use crate::build::data::embedding_dataset::EmbeddingRecord;
+use crate::build::normalization::NormalizationConfig;
+
 use importance::score::Model;
 use importance::*;
 use importance::score::ScoreKind;
 use importance::importance;
-use smartcore::linalg::naive::dense_matrix::DenseMatrix;
 
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use std::{fs, thread};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use smartcore::math::distance::euclidian::Euclidian;
 use smartcore::neighbors::knn_regressor::KNNRegressor;
 
-use smartcore::neighbors::KNNWeightFunction;
-use smartcore::ensemble::random_forest_regressor::RandomForestRegressor;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use tracing::{debug, info, warn};
+
+type SharedPredictor = Arc<Mutex<Box<dyn Predictor>>>;
+type PooledPredictor = (String, Option<SystemTime>, ModelType, SharedPredictor, ModelInputs);
 
 lazy_static::lazy_static! {
-    static ref PREDICTOR_POOL: Arc<Mutex<Vec<(String, Arc<Mutex<Box<dyn Model>>>)>>>
+    // predictors are keyed by path and modification time, so a replaced model file is picked up
+    static ref PREDICTOR_POOL: Arc<Mutex<Vec<PooledPredictor>>>
         = Arc::new(Mutex::new(Vec::new()));
 }
 
 
-/*
-pub trait Model: Send + Sync {
-    fn predict(&self, x: &Vec<Vec<f32>>) -> Vec<f32>;
-}*/
+/// Scores embeddings. Unlike `importance::score::Model` it returns an error instead of panicking,
+/// e.g. for embeddings of a different dimension than the model was trained on.
+pub trait Predictor: Send + Sync {
+    fn predict(&self, x: &[Vec<f32>]) -> anyhow::Result<Vec<f32>>;
+}
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ModelType {
     KNN,
     RandomForest,
 }
 
+/// The fields of a model file that tell which kind of model it holds, everything else is skipped.
+#[derive(serde::Deserialize)]
+struct ModelFileHeader {
+    metadata: Option<ModelFileMetadata>,
+    trees: Option<serde::de::IgnoredAny>,
+}
+
+#[derive(serde::Deserialize)]
+struct ModelFileMetadata {
+    model_type: String,
+}
+
+impl ModelType {
+    /// Reads the model type from the bundle metadata, files without metadata are bare regressors
+    /// written by earlier versions: random forests have trees, anything else is a KNN regressor.
+    pub fn detect(path: &str) -> anyhow::Result<ModelType> {
+        let file = fs::File::open(path).map_err(|err| anyhow::anyhow!("Error: unable to read '{}': {}", path, err))?;
+        let header: ModelFileHeader = serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|err| anyhow::anyhow!("Error: unable to load '{}': {}", path, err))?;
+        match header.metadata {
+            Some(metadata) => match metadata.model_type.as_str() {
+                "knn" => Ok(ModelType::KNN),
+                "random_forest" => Ok(ModelType::RandomForest),
+                other => Err(anyhow::anyhow!("Error: '{}' holds an unknown model type '{}'", path, other)),
+            },
+            None if header.trees.is_some() => Ok(ModelType::RandomForest),
+            None => Ok(ModelType::KNN),
+        }
+    }
+}
+
+/// Like `ModelType::detect`, but answers from the predictor pool if the model file is already loaded.
+pub fn model_type_of(label: &str) -> anyhow::Result<ModelType> {
+    let modified = fs::metadata(label).and_then(|metadata| metadata.modified()).ok();
+    let pooled = PREDICTOR_POOL.lock().unwrap().iter()
+        .find(|(l, m, _, _, _)| l == label && *m == modified)
+        .map(|(_, _, model_type, _, _)| *model_type);
+    match pooled {
+        Some(model_type) => Ok(model_type),
+        None => ModelType::detect(label),
+    }
+}
+
 pub struct ClassificationMockModel {
     pub label: String,
     pub model_type: ModelType,
 }
 
-struct KNNRegressorModel(KNNRegressor<f32,Euclidian>);
-struct RandomForestRegressorModel(RandomForestRegressor<f32>);
+/// How the inputs of a model are prepared from a text.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModelInputs {
+    /// Applied to the text before it is embedded.
+    pub normalization: NormalizationConfig,
+    /// The signal features of the text are appended to its embedding.
+    pub signal_features: bool,
+}
 
-impl Model for KNNRegressorModel {
-    fn predict(&self, x: &Vec<Vec<f32>>) -> Vec<f32> {
-        let x = DenseMatrix::from_2d_array(&x.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
-        self.0.predict(&x).unwrap()
+impl ModelInputs {
+    fn of(metadata: &bundle::BundleMetadata) -> Self {
+        ModelInputs { normalization: metadata.normalization, signal_features: metadata.signal_features }
     }
 }
 
-impl Model for RandomForestRegressorModel {
-    fn predict(&self, x: &Vec<Vec<f32>>) -> Vec<f32> {
-        let x = DenseMatrix::from_2d_array(&x.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
-        self.0.predict(&x).unwrap()
+struct KNNRegressorModel(ModelBundle);
+struct RandomForestRegressorModel(RandomForestBundle);
+
+impl Predictor for KNNRegressorModel {
+    fn predict(&self, x: &[Vec<f32>]) -> anyhow::Result<Vec<f32>> {
+        self.0.predict(x)
     }
 }
 
-impl Model for ClassificationMockModel {
+impl Predictor for RandomForestRegressorModel {
+    fn predict(&self, x: &[Vec<f32>]) -> anyhow::Result<Vec<f32>> {
+        self.0.predict(x)
+    }
+}
+
+impl Predictor for ClassificationMockModel {
+    fn predict(&self, x: &[Vec<f32>]) -> anyhow::Result<Vec<f32>> {
+        predict_with_model(&self.label, &self.model_type, x)
+    }
+}
+
+/// Lets the importance crate call a `Predictor`, it has no way to report errors.
+struct ImportanceModel<'a>(&'a dyn Predictor);
+
+impl Model for ImportanceModel<'_> {
     fn predict(&self, x: &Vec<Vec<f32>>) -> Vec<f32> {
-        let predictor = get_model(&self.label, &self.model_type).expect("Failed to get a predictor from the pool.");
-        let model = predictor.lock().unwrap();
-        model.predict(&x)
+        // the unpermuted rows were scored before, so a failure here is not expected
+        self.0.predict(x).unwrap_or_else(|err| {
+            warn!(error = %err, "prediction failed while computing permutation importance");
+            vec![f32::NAN; x.len()]
+        })
     }
 }
 
 
-fn get_model(label: &String, model_type: &ModelType) -> anyhow::Result<Arc<Mutex<Box<dyn Model>>>> {
+/// Scores `x` with the pooled model, errors if the model can not be loaded or does not fit `x`.
+pub fn predict_with_model(label: &str, model_type: &ModelType, x: &[Vec<f32>]) -> anyhow::Result<Vec<f32>> {
+    let predictor = get_model(&label.to_string(), model_type)?;
+    let model = predictor.lock().map_err(|_| anyhow::anyhow!("Error: predictor lock is poisoned"))?;
+    model.predict(x)
+}
+
+
+/// The normalization texts have to go through before they are embedded for this model.
+pub fn model_normalization(label: &str, model_type: &ModelType) -> anyhow::Result<NormalizationConfig> {
+    Ok(model_inputs(label, model_type)?.normalization)
+}
+
+/// How the inputs of this model are prepared from a text.
+pub fn model_inputs(label: &str, model_type: &ModelType) -> anyhow::Result<ModelInputs> {
+    Ok(get_pooled_model(&label.to_string(), model_type)?.1)
+}
+
+fn get_model(label: &String, model_type: &ModelType) -> anyhow::Result<SharedPredictor> {
+    Ok(get_pooled_model(label, model_type)?.0)
+}
+
+fn get_pooled_model(label: &String, model_type: &ModelType) -> anyhow::Result<(SharedPredictor, ModelInputs)> {
     let mut pool = PREDICTOR_POOL.lock().unwrap();
+    let modified = fs::metadata(label).and_then(|metadata| metadata.modified()).ok();
 
     // Check if any predictor is available in the pool
-    if let Some((_, predictor)) = pool.iter().find(|(l, p)| l == label && !p.try_lock().is_err()) {
-        return Ok(Arc::clone(predictor));
+    if let Some((_, _, _, predictor, normalization)) = pool.iter().find(|(l, m, t, p, _)| l == label && *m == modified && t == model_type && p.try_lock().is_ok()) {
+        return Ok((Arc::clone(predictor), *normalization));
     }
 
     // If all predictors are in use, wait until one becomes available
     while pool.len() >= 500 {
         thread::sleep(Duration::from_millis(100));
-        if let Some((_, predictor)) = pool.iter().find(|(l, p)| l == label && !p.try_lock().is_err()) {
-            return Ok(Arc::clone(predictor));
+        if let Some((_, _, _, predictor, normalization)) = pool.iter().find(|(l, m, t, p, _)| l == label && *m == modified && t == model_type && p.try_lock().is_ok()) {
+            return Ok((Arc::clone(predictor), *normalization));
         }
     }
 
-    // Create a new predictor and add it to the pool
-    let new_predictor: Box<dyn Model> = match model_type {
-        ModelType::KNN => {
-            let model: KNNRegressor<f32,Euclidian> = match serde_json::from_str(&fs::read_to_string(label)?)? {
-                Some(lr) => { lr },
-                None => { return Err(anyhow::anyhow!(format!("Error: unable to load '{}'",label)));}
-            };
-            Box::new(KNNRegressorModel(model))
-        }
-        ModelType::RandomForest => {
-            let model: RandomForestRegressor<f32> = match serde_json::from_str(&fs::read_to_string(label)?)? {
-                Some(lr) => lr,
-                None => return Err(anyhow::anyhow!(format!("Error: unable to load '{}'", label))),
+    // Create a new predictor, keep using the previous version of the model if the file can not be loaded
+    let (new_predictor, inputs) = match load_predictor(label, model_type) {
+        Ok(predictor) => predictor,
+        Err(err) => {
+            return match pool.iter().find(|(l, _, t, _, _)| l == label && t == model_type) {
+                Some((_, _, _, predictor, normalization)) => {
+                    warn!(path = label.as_str(), error = %err, "failed to reload model, using the previously loaded model");
+                    Ok((Arc::clone(predictor), *normalization))
+                },
+                None => Err(err),
             };
-            Box::new(RandomForestRegressorModel(model))
         }
     };
 
+    // drop predictors of a replaced model file
+    pool.retain(|(l, m, _, _, _)| l != label || *m == modified);
+
     let new_predictor = Arc::new(Mutex::new(new_predictor));
-    pool.push((label.to_owned(), Arc::clone(&new_predictor)));
+    pool.push((label.to_owned(), modified, *model_type, Arc::clone(&new_predictor), inputs));
 
-    Ok(new_predictor)
+    Ok((new_predictor, inputs))
 }
 
+fn load_predictor(label: &str, model_type: &ModelType) -> anyhow::Result<(Box<dyn Predictor>, ModelInputs)> {
+    Ok(match model_type {
+        ModelType::KNN => {
+            let bundle = feedback::load_bundle(label)?;
+            let inputs = ModelInputs::of(&bundle.metadata);
+            (Box::new(KNNRegressorModel(bundle)), inputs)
+        }
+        ModelType::RandomForest => {
+            let bundle = RandomForestBundle::load(label)?;
+            let inputs = ModelInputs::of(&bundle.metadata);
+            (Box::new(RandomForestRegressorModel(bundle)), inputs)
+        }
+    })
+}
 
-pub fn update_knn_regression_model(x_dataset: &Vec<Vec<f32>>, y_dataset: &Vec<f32>) ->  anyhow::Result<()> {
-
-    let x = DenseMatrix::from_2d_array(&x_dataset.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
-    let y = y_dataset;
 
+pub const KNN_DEFAULT_K: usize = 3;
 
-    let regressor = KNNRegressor::fit(&x, &y, smartcore::neighbors::knn_regressor::KNNRegressorParameters::default().with_k(3).with_weight(KNNWeightFunction::Distance)).unwrap();
-    fs::write("./KNNRegressor.bin", &serde_json::to_string(&regressor)?).ok();
+pub const THRESHOLDS: [f32; 9] = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9];
 
-    Ok(())
+pub fn fit_knn_regression_model(x_dataset: &[Vec<f32>], y_dataset: &[f32], k: usize) -> anyhow::Result<KNNRegressor<f32,Euclidian>> {
+    ModelBundle::fit(x_dataset, y_dataset, k)?.regressor.ok_or_else(|| anyhow::anyhow!("Error: unable to fit KNN regressor"))
 }
 
+pub fn update_knn_regression_model(path: &str, x_dataset: &[Vec<f32>], y_dataset: &[f32], k: usize) ->  anyhow::Result<()> {
+    ModelBundle::fit(x_dataset, y_dataset, k)?.save(path)
+}
 
-pub fn test_knn_regression_model(x_dataset: &Vec<Vec<f32>>, y_dataset: &Vec<f32>) -> anyhow::Result<()> {
-
-    let model: KNNRegressor<f32,Euclidian> = match serde_json::from_str(&fs::read_to_string("./KNNRegressor.bin")?)? {
-        Some(lr) => { lr },
-        None => { return Err(anyhow::anyhow!(format!("Error: unable to load '{}'","./KNNRegressor.bin")));}
-    };
-    let x = DenseMatrix::from_2d_array(&x_dataset.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
-
-    let y_hat = model.predict(&x).unwrap();
+/// Like `update_knn_regression_model`, but keeps the texts of the records so predictions can be explained,
+/// and the normalization the texts were embedded with.
+pub fn update_knn_regression_model_from_records(path: &str, records: &[EmbeddingRecord], k: usize, normalization: &NormalizationConfig) -> anyhow::Result<()> {
+    ModelBundle::fit_records(records, k, normalization)?.save(path)
+}
 
-    let thresholds = vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9];
 
-    calculate_metrics(&y_dataset,&y_hat, &thresholds);
-    Ok(())
+pub fn predict_knn_regression_model(path: &str, x_dataset: &[Vec<f32>]) -> anyhow::Result<Vec<f32>> {
+    ModelBundle::load(path)?.predict(x_dataset)
 }
 
-pub fn update_random_forest_regression_model(path: &str, x_dataset: &Vec<Vec<f32>>, y_dataset: &Vec<f32>) ->  anyhow::Result<()> {
+pub fn test_knn_regression_model(path: &str, x_dataset: &[Vec<f32>], y_dataset: &[f32]) -> anyhow::Result<Vec<ThresholdMetrics>> {
 
-    let x = DenseMatrix::from_2d_array(&x_dataset.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
-    let y = y_dataset;
+    let y_hat = predict_knn_regression_model(path, x_dataset)?;
 
+    Ok(calculate_metrics(y_dataset,&y_hat, &THRESHOLDS))
+}
 
-    let regressor = RandomForestRegressor::fit(&x, &y, smartcore::ensemble::random_forest_regressor::RandomForestRegressorParameters::default()/*.with_max_depth(32)*/.with_n_trees(32).with_min_samples_leaf(4)).unwrap();
-    fs::write(path, &serde_json::to_string(&regressor)?).ok();
+pub fn update_random_forest_regression_model(path: &str, x_dataset: &[Vec<f32>], y_dataset: &[f32]) ->  anyhow::Result<()> {
+    RandomForestBundle::fit(x_dataset, y_dataset)?.save(path)
+}
 
-    Ok(())
+/// Like `update_random_forest_regression_model`, but records the normalization the texts were embedded with
+/// and whether the signal features of the texts are appended to the embeddings, so predictions prepare the inputs the same way.
+pub fn update_random_forest_regression_model_from_records(path: &str, records: &[EmbeddingRecord], signal_features: bool, normalization: &NormalizationConfig) -> anyhow::Result<()> {
+    RandomForestBundle::fit_records(records, signal_features, normalization)?.save(path)
 }
 
 
-pub fn test_random_forest_regression_model(path: &str, x_dataset: &Vec<Vec<f32>>, y_dataset: &Vec<f32>) -> anyhow::Result<()> {
+pub fn test_random_forest_regression_model(path: &str, x_dataset: &[Vec<f32>], y_dataset: &[f32]) -> anyhow::Result<()> {
 
     let model = ClassificationMockModel {
         label: path.to_string(), model_type: ModelType::RandomForest
     };
-    let y_hat = model.predict(x_dataset);
+    let y_hat = model.predict(x_dataset)?;
 
-    let thresholds = vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9];
-    calculate_metrics(&y_dataset,&y_hat, &thresholds);
+    calculate_metrics(y_dataset,&y_hat, &THRESHOLDS);
     Ok(())
 }
 
-pub fn calculate_metrics(y: &[f32], y_hat: &[f32], thresholds: &[f32]) {
-    for &threshold in thresholds {
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ThresholdMetrics {
+    pub threshold: f32,
+    pub true_positive_count: usize,
+    pub false_positive_count: usize,
+    pub false_negative_count: usize,
+    pub precision: f32,
+    pub recall: f32,
+    pub f_score: f32,
+}
+
+pub fn compute_metrics(y: &[f32], y_hat: &[f32], thresholds: &[f32]) -> Vec<ThresholdMetrics> {
+    thresholds.iter().map(|&threshold| {
         let true_positive_count = y_hat.iter().zip(y.iter()).filter(|(&y_hat_val, &y_val)| y_hat_val >= threshold && y_val >= 0.5).count();
         let false_positive_count = y_hat.iter().zip(y.iter()).filter(|(&y_hat_val, &y_val)| y_hat_val >= threshold && y_val < 0.5).count();
         let false_negative_count = y_hat.iter().zip(y.iter()).filter(|(&y_hat_val, &y_val)| y_hat_val < threshold && y_val >= 0.5).count();
@@ -170,42 +301,93 @@ pub fn calculate_metrics(y: &[f32], y_hat: &[f32], thresholds: &[f32]) {
         let recall = true_positive_count as f32 / (true_positive_count + false_negative_count) as f32;
         let f_score = 2.0 * (precision * recall) / (precision + recall);
 
-        println!(
-            "Threshold >= {}: True Positive = {}, False Positive = {}, Precision = {:.3}, Recall = {:.3}, F-Score = {:.3}",
-            threshold, true_positive_count, false_positive_count, precision, recall, f_score
+        ThresholdMetrics { threshold, true_positive_count, false_positive_count, false_negative_count, precision, recall, f_score }
+    }).collect()
+}
+
+pub fn calculate_metrics(y: &[f32], y_hat: &[f32], thresholds: &[f32]) -> Vec<ThresholdMetrics> {
+    let metrics = compute_metrics(y, y_hat, thresholds);
+    for m in &metrics {
+        info!(
+            threshold = %m.threshold,
+            true_positive_count = m.true_positive_count,
+            false_positive_count = m.false_positive_count,
+            precision = format!("{:.3}", m.precision),
+            recall = format!("{:.3}", m.recall),
+            f_score = format!("{:.3}", m.f_score),
+            "metrics"
         );
     }
+    metrics
 }
 
 
 
 
 
-pub fn feature_importance(x_dataset_shuffled: &Vec<Vec<f32>>, y_dataset_shuffled: &Vec<f32>, feature_labels: Vec<String>, random_forest_label: &str) -> anyhow::Result<()> {
+/// Permutation importance of a single input feature.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct FeatureImportance {
+    pub feature: String,
+    /// Mean increase of the error when the feature is shuffled.
+    pub importance: f32,
+}
 
-    let model = ClassificationMockModel {
-        label: random_forest_label.to_string(),
-        model_type: ModelType::RandomForest
-    };
+/// Names the dimensions of an embedding, `embedding_0` to `embedding_<dimension - 1>`.
+pub fn embedding_feature_labels(dimension: usize) -> Vec<String> {
+    (0..dimension).map(|i| format!("embedding_{}", i)).collect()
+}
+
+/// Permutation importance of every input feature of `model`, most important first.
+///
+/// At most `sample_size` randomly chosen rows (by `seed`) are used, the rest only cost time.
+pub fn permutation_importance(model: &dyn Predictor, x_dataset: &[Vec<f32>], y_dataset: &[f32], feature_labels: &[String], sample_size: usize, seed: u64) -> anyhow::Result<Vec<FeatureImportance>> {
+    if x_dataset.is_empty() || x_dataset.len() != y_dataset.len() {
+        return Err(anyhow::anyhow!("Error: expected the same non-zero number of rows and labels, got {} and {}", x_dataset.len(), y_dataset.len()));
+    }
+    if x_dataset[0].len() != feature_labels.len() {
+        return Err(anyhow::anyhow!("Error: got {} feature labels for {} features", feature_labels.len(), x_dataset[0].len()));
+    }
+
+    let mut indices: Vec<usize> = (0..x_dataset.len()).collect();
+    indices.shuffle(&mut StdRng::seed_from_u64(seed));
+    indices.truncate(sample_size.max(1));
+    let x_sample: Vec<Vec<f32>> = indices.iter().map(|&i| x_dataset[i].clone()).collect();
+    let y_sample: Vec<f32> = indices.iter().map(|&i| y_dataset[i]).collect();
+    // fails here on a model that can not be loaded or does not fit the rows
+    model.predict(&x_sample)?;
+    info!(sample_size = x_sample.len(), feature_count = feature_labels.len(), "computing permutation importance");
 
     let opts = Opts {
-        verbose: true,
+        verbose: false,
         kind: Some(ScoreKind::Mae),
-        n: Some(500),
+        n: Some(x_sample.len()),
         only_means: true,
         scale: true,
     };
+    let importances = importance(&ImportanceModel(model), x_sample, y_sample, opts);
+    debug!(?importances, "computed importances");
+
+    let mut result: Vec<FeatureImportance> = feature_labels.iter().enumerate().map(|(i, feature)| FeatureImportance {
+        feature: feature.clone(),
+        importance: importances.importances_means.get(i).copied().unwrap_or(f32::NAN),
+    }).collect();
+    result.sort_by(|a, b| b.importance.partial_cmp(&a.importance).unwrap_or(Ordering::Equal));
+    Ok(result)
+}
+
+pub fn feature_importance(x_dataset_shuffled: &[Vec<f32>], y_dataset_shuffled: &[f32], feature_labels: Vec<String>, random_forest_label: &str) -> anyhow::Result<()> {
 
-    let importances = importance(&model, x_dataset_shuffled.to_owned(), y_dataset_shuffled.to_owned(), opts);
-    println!("Importances: {:?}", importances);
+    let model = ClassificationMockModel {
+        label: random_forest_label.to_string(),
+        model_type: ModelType::RandomForest
+    };
 
-    let importances_means: Vec<f32> = importances.importances_means;
-    let mut result: Vec<(f32, String)> = importances_means.into_iter().zip(feature_labels).collect();
-    result.sort_by(|(a,_), (b,_)| b.partial_cmp(&a).unwrap_or(Ordering::Equal));
+    let result = permutation_importance(&model, x_dataset_shuffled, y_dataset_shuffled, &feature_labels, 500, 0)?;
 
     let json_string = serde_json::json!({"feature_importance": &result}).to_string();
 
-    println!("Result: \n\n{:?}", json_string);
+    info!(result = json_string.as_str(), "feature importance");
 
     Ok(())
 
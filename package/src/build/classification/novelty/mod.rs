@@ -0,0 +1,68 @@
+//! Novelty of an input: how far it is from the training examples, compared to how far the
+//! training examples are from each other. KNN scores inputs far from every training example
+//! (another language, a code snippet) just as confidently as typical ones, the novelty tells them apart.
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Inputs with a novelty at or above this are out of distribution.
+pub const DEFAULT_NOVELTY_THRESHOLD: f32 = 0.99;
+
+/// Number of training examples whose neighbor distances make up the reference distribution.
+pub const REFERENCE_SAMPLE_SIZE: usize = 1000;
+
+/// The distribution of nearest-neighbor distances within the training examples.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NoveltyCalibration {
+    /// Number of nearest neighbors a distance is averaged over.
+    pub k: usize,
+    /// Mean distance of sampled training examples to their `k` nearest other examples, ascending.
+    pub reference_distances: Vec<f32>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Novelty {
+    /// Mean euclidean distance to the `k` nearest training examples.
+    pub distance: f32,
+    /// Share of the training examples that are closer to their own neighbors than the input is to its neighbors,
+    /// from 0 (typical) to 1 (farther out than any of them).
+    pub score: f32,
+}
+
+impl NoveltyCalibration {
+    /// Measures every training example (up to `REFERENCE_SAMPLE_SIZE` of them, evenly spread) against all the others.
+    pub fn fit(embeddings: &[&[f32]], k: usize) -> anyhow::Result<Self> {
+        if embeddings.len() < 2 {
+            return Err(anyhow::anyhow!("Error: at least 2 training examples are needed for the novelty calibration, got {}", embeddings.len()));
+        }
+        let k = k.clamp(1, embeddings.len() - 1);
+        let step = embeddings.len().div_ceil(REFERENCE_SAMPLE_SIZE);
+        let sample: Vec<usize> = (0..embeddings.len()).step_by(step).collect();
+
+        let mut reference_distances: Vec<f32> = sample.into_par_iter()
+            .map(|index| mean_nearest_distance(embeddings, embeddings[index], k, Some(index)))
+            .collect();
+        reference_distances.sort_by(|a, b| a.total_cmp(b));
+        Ok(NoveltyCalibration { k, reference_distances })
+    }
+
+    /// The novelty of an input whose `k` nearest training examples are `distance` away on average.
+    pub fn novelty(&self, distance: f32) -> Novelty {
+        let below = self.reference_distances.partition_point(|reference| *reference < distance);
+        Novelty { distance, score: below as f32 / self.reference_distances.len().max(1) as f32 }
+    }
+}
+
+/// Mean euclidean distance from `x` to its `k` nearest embeddings, leaving out the one at index `skip`.
+pub fn mean_nearest_distance(embeddings: &[&[f32]], x: &[f32], k: usize, skip: Option<usize>) -> f32 {
+    let mut distances: Vec<f32> = embeddings.iter().enumerate()
+        .filter(|(index, _)| Some(*index) != skip)
+        .map(|(_, embedding)| embedding.iter().zip(x.iter()).map(|(a, b)| (a - b) * (a - b)).sum::<f32>().sqrt())
+        .collect();
+    if distances.is_empty() {
+        return f32::INFINITY;
+    }
+    let k = k.clamp(1, distances.len());
+    distances.select_nth_unstable_by(k - 1, |a, b| a.total_cmp(b));
+    distances[..k].iter().sum::<f32>() / k as f32
+}
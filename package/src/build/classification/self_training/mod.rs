@@ -0,0 +1,137 @@
+//! Self-training on unlabeled texts: entries the KNN model is confident about (their neighbors
+//! agree) are pseudo-labeled and added to the training data, then the model is refitted.
+
+use serde::Serialize;
+use tracing::info;
+
+use super::bundle::ModelBundle;
+use crate::build::data::embedding_dataset::EmbeddingRecord;
+use crate::build::normalization::NormalizationConfig;
+
+/// Source of pseudo-labeled training examples, so explanations can tell them apart.
+pub const PSEUDO_LABEL_SOURCE: &str = "pseudo_label";
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SelfTrainingConfig {
+    pub k: usize,
+    /// Unlabeled entries scoring at or above this are pseudo-labeled as spam.
+    pub spam_threshold: f32,
+    /// Unlabeled entries scoring at or below this are pseudo-labeled as ham.
+    pub ham_threshold: f32,
+    pub max_rounds: usize,
+    /// Maximum number of (the most confident) entries added per round and class, unlimited if `None`.
+    pub max_per_round: Option<usize>,
+}
+
+impl SelfTrainingConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if !(self.spam_threshold > 0.5 && self.spam_threshold <= 1.0) {
+            return Err(anyhow::anyhow!("Error: the spam threshold must be above 0.5 and at most 1, got {}", self.spam_threshold));
+        }
+        if !(self.ham_threshold >= 0.0 && self.ham_threshold < 0.5) {
+            return Err(anyhow::anyhow!("Error: the ham threshold must be at least 0 and below 0.5, got {}", self.ham_threshold));
+        }
+        if self.max_per_round == Some(0) {
+            return Err(anyhow::anyhow!("Error: at least one entry has to be added per round"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SelfTrainingRound {
+    pub round: usize,
+    pub spam_added: usize,
+    pub ham_added: usize,
+    /// Labeled plus pseudo-labeled entries the model is refitted on.
+    pub training_count: usize,
+    pub unlabeled_remaining: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SelfTrainingReport {
+    pub config: SelfTrainingConfig,
+    pub labeled_count: usize,
+    pub unlabeled_count: usize,
+    pub pseudo_spam_count: usize,
+    pub pseudo_ham_count: usize,
+    pub rounds: Vec<SelfTrainingRound>,
+}
+
+/// Iteratively pseudo-labels the `unlabeled` records (their labels are ignored) and refits the model,
+/// until a round adds nothing or `max_rounds` is reached.
+///
+/// Returns the final model, the pseudo-labeled records (with source `PSEUDO_LABEL_SOURCE`) and a report.
+pub fn self_train(labeled: &[EmbeddingRecord], unlabeled: Vec<EmbeddingRecord>, config: &SelfTrainingConfig, normalization: &NormalizationConfig) -> anyhow::Result<(ModelBundle, Vec<EmbeddingRecord>, SelfTrainingReport)> {
+    config.validate()?;
+    let dimension = labeled.first().map(|record| record.embedding.len())
+        .ok_or_else(|| anyhow::anyhow!("Error: self-training needs labeled entries"))?;
+    if let Some(record) = unlabeled.iter().find(|record| record.embedding.len() != dimension) {
+        return Err(anyhow::anyhow!("Error: unlabeled embedding dimension {} does not match the labeled entries ({})", record.embedding.len(), dimension));
+    }
+
+    let mut report = SelfTrainingReport {
+        config: config.clone(),
+        labeled_count: labeled.len(),
+        unlabeled_count: unlabeled.len(),
+        pseudo_spam_count: 0,
+        pseudo_ham_count: 0,
+        rounds: Vec::new(),
+    };
+    let mut training: Vec<EmbeddingRecord> = labeled.to_vec();
+    let mut pseudo_labeled: Vec<EmbeddingRecord> = Vec::new();
+    let mut remaining = unlabeled;
+    let mut bundle = ModelBundle::fit_records(&training, config.k, normalization)?;
+
+    for round in 1..=config.max_rounds {
+        if remaining.is_empty() {
+            break;
+        }
+        let x_dataset: Vec<Vec<f32>> = remaining.iter().map(|record| record.embedding.clone()).collect();
+        let scores = bundle.predict(&x_dataset)?;
+
+        let mut spam: Vec<usize> = (0..scores.len()).filter(|&i| scores[i] >= config.spam_threshold).collect();
+        let mut ham: Vec<usize> = (0..scores.len()).filter(|&i| scores[i] <= config.ham_threshold).collect();
+        spam.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+        ham.sort_by(|&a, &b| scores[a].total_cmp(&scores[b]));
+        if let Some(max) = config.max_per_round {
+            spam.truncate(max);
+            ham.truncate(max);
+        }
+        if spam.is_empty() && ham.is_empty() {
+            info!(round, "no confident unlabeled entries left");
+            break;
+        }
+
+        let mut labels: Vec<Option<f32>> = vec![None; remaining.len()];
+        spam.iter().for_each(|&i| labels[i] = Some(1.0));
+        ham.iter().for_each(|&i| labels[i] = Some(0.0));
+        let mut still_unlabeled = Vec::with_capacity(remaining.len());
+        for (mut record, label) in remaining.into_iter().zip(labels) {
+            match label {
+                Some(label) => {
+                    record.label = label;
+                    record.source = PSEUDO_LABEL_SOURCE.to_string();
+                    training.push(record.clone());
+                    pseudo_labeled.push(record);
+                },
+                None => still_unlabeled.push(record),
+            }
+        }
+        remaining = still_unlabeled;
+        bundle = ModelBundle::fit_records(&training, config.k, normalization)?;
+
+        report.pseudo_spam_count += spam.len();
+        report.pseudo_ham_count += ham.len();
+        info!(round, spam_added = spam.len(), ham_added = ham.len(), unlabeled_remaining = remaining.len(), "self-training round");
+        report.rounds.push(SelfTrainingRound {
+            round,
+            spam_added: spam.len(),
+            ham_added: ham.len(),
+            training_count: training.len(),
+            unlabeled_remaining: remaining.len(),
+        });
+    }
+
+    Ok((bundle, pseudo_labeled, report))
+}
@@ -0,0 +1,293 @@
+use std::collections::{HashMap, HashSet};
+
+use rand::prelude::SliceRandom;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+
+use super::embedding_dataset::EmbeddingRecord;
+
+const SHINGLE_SIZE: usize = 5;
+const MINHASH_PERMUTATIONS: usize = 64;
+const LSH_ROWS_PER_BAND: usize = 4;
+const REPORT_EXAMPLE_GROUPS: usize = 10;
+const REPORT_SNIPPET_LENGTH: usize = 120;
+
+pub const DEFAULT_NEAR_DUPLICATE_THRESHOLD: f32 = 0.8;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateGroupExample {
+    pub size: usize,
+    pub labels: Vec<f32>,
+    pub texts: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DedupReport {
+    pub total_count: usize,
+    pub unique_count: usize,
+    pub exact_duplicate_count: usize,
+    pub near_duplicate_count: usize,
+    pub duplicate_group_count: usize,
+    pub largest_group: usize,
+    pub conflicting_label_groups: usize,
+    pub largest_groups: Vec<DuplicateGroupExample>,
+}
+
+/// Lowercases the text and keeps only alphanumeric words separated by single spaces,
+/// so that formatting, punctuation and casing differences hash to the same value.
+pub fn normalize_text(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Stable 64-bit FNV-1a hash (unlike `DefaultHasher` it does not change between Rust releases).
+pub fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn text_hash(text: &str) -> u64 {
+    fnv1a_hash(normalize_text(text).as_bytes())
+}
+
+fn mix(mut x: u64) -> u64 {
+    // splitmix64 finalizer
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn shingles(normalized: &str) -> HashSet<u64> {
+    let chars: Vec<char> = normalized.chars().collect();
+    if chars.len() <= SHINGLE_SIZE {
+        return HashSet::from([fnv1a_hash(normalized.as_bytes())]);
+    }
+    chars
+        .windows(SHINGLE_SIZE)
+        .map(|window| fnv1a_hash(window.iter().collect::<String>().as_bytes()))
+        .collect()
+}
+
+pub fn minhash_signature(text: &str) -> Vec<u64> {
+    minhash(&shingles(&normalize_text(text)))
+}
+
+fn minhash(shingles: &HashSet<u64>) -> Vec<u64> {
+    (0..MINHASH_PERMUTATIONS as u64)
+        .map(|seed| {
+            shingles
+                .iter()
+                .map(|shingle| mix(shingle ^ mix(seed)))
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect()
+}
+
+fn jaccard(a: &HashSet<u64>, b: &HashSet<u64>) -> f32 {
+    let intersection = a.intersection(b).count();
+    intersection as f32 / (a.len() + b.len() - intersection).max(1) as f32
+}
+
+/// Estimated Jaccard similarity of the shingle sets behind two MinHash signatures.
+pub fn estimated_jaccard(a: &[u64], b: &[u64]) -> f32 {
+    if a.is_empty() || a.len() != b.len() {
+        return 0.0;
+    }
+    a.iter().zip(b.iter()).filter(|(x, y)| x == y).count() as f32 / a.len() as f32
+}
+
+struct UnionFind {
+    parent: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(size: usize) -> Self {
+        UnionFind { parent: (0..size).collect() }
+    }
+
+    fn find(&mut self, mut index: usize) -> usize {
+        while self.parent[index] != index {
+            self.parent[index] = self.parent[self.parent[index]];
+            index = self.parent[index];
+        }
+        index
+    }
+
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (root_a, root_b) = (self.find(a), self.find(b));
+        if root_a == root_b {
+            return false;
+        }
+        // keep the smaller index as representative, so groups are named after their first occurrence
+        let (keep, merge) = if root_a < root_b { (root_a, root_b) } else { (root_b, root_a) };
+        self.parent[merge] = keep;
+        true
+    }
+}
+
+fn exact_and_near_groups(texts: &[&str], threshold: f32) -> (Vec<usize>, usize, usize) {
+    let mut union_find = UnionFind::new(texts.len());
+
+    let mut first_by_hash: HashMap<u64, usize> = HashMap::new();
+    let mut representatives: Vec<usize> = Vec::new();
+    let mut exact_duplicate_count = 0;
+    for (index, text) in texts.iter().enumerate() {
+        let hash = text_hash(text);
+        match first_by_hash.get(&hash) {
+            Some(&first) => {
+                union_find.union(first, index);
+                exact_duplicate_count += 1;
+            }
+            None => {
+                first_by_hash.insert(hash, index);
+                representatives.push(index);
+            }
+        }
+    }
+
+    // MinHash + LSH banding: only candidates that share a band bucket are compared,
+    // on the exact Jaccard similarity of their shingles.
+    let shingle_sets: Vec<HashSet<u64>> = representatives
+        .par_iter()
+        .map(|&index| shingles(&normalize_text(texts[index])))
+        .collect();
+    let signatures: Vec<Vec<u64>> = shingle_sets.par_iter().map(minhash).collect();
+
+    // Every candidate is compared with all members of its bucket that are not in its group yet,
+    // so a text similar only to a later member of a bucket is still found.
+    let mut near_duplicate_count = 0;
+    for band in 0..MINHASH_PERMUTATIONS / LSH_ROWS_PER_BAND {
+        let range = band * LSH_ROWS_PER_BAND..(band + 1) * LSH_ROWS_PER_BAND;
+        let mut buckets: HashMap<&[u64], Vec<usize>> = HashMap::new();
+        for (position, signature) in signatures.iter().enumerate() {
+            let bucket = buckets.entry(&signature[range.clone()]).or_default();
+            for &member in bucket.iter() {
+                if union_find.find(representatives[member]) != union_find.find(representatives[position])
+                    && jaccard(&shingle_sets[member], &shingle_sets[position]) >= threshold
+                    && union_find.union(representatives[member], representatives[position])
+                {
+                    near_duplicate_count += 1;
+                }
+            }
+            bucket.push(position);
+        }
+    }
+
+    let groups = (0..texts.len()).map(|index| union_find.find(index)).collect();
+    (groups, exact_duplicate_count, near_duplicate_count)
+}
+
+/// Assigns every text to a duplicate group, identified by the index of its first member.
+///
+/// Texts with the same normalized form are exact duplicates, texts whose Jaccard similarity
+/// of character shingles is at least `threshold` are near duplicates (candidates are found with MinHash LSH).
+pub fn duplicate_groups(texts: &[&str], threshold: f32) -> Vec<usize> {
+    exact_and_near_groups(texts, threshold).0
+}
+
+pub fn dedup_report(dataset: &[(String, f32)], threshold: f32) -> (Vec<usize>, DedupReport) {
+    let texts: Vec<&str> = dataset.iter().map(|(text, _)| text.as_str()).collect();
+    let (groups, exact_duplicate_count, near_duplicate_count) = exact_and_near_groups(&texts, threshold);
+
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (index, group) in groups.iter().enumerate() {
+        members.entry(*group).or_default().push(index);
+    }
+    let mut duplicate_groups: Vec<&Vec<usize>> = members.values().filter(|m| m.len() > 1).collect();
+    duplicate_groups.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+
+    let conflicting_label_groups = duplicate_groups
+        .iter()
+        .filter(|m| m.iter().any(|&i| (dataset[i].1 >= 0.5) != (dataset[m[0]].1 >= 0.5)))
+        .count();
+
+    let largest_groups = duplicate_groups
+        .iter()
+        .take(REPORT_EXAMPLE_GROUPS)
+        .map(|m| DuplicateGroupExample {
+            size: m.len(),
+            labels: m.iter().map(|&i| dataset[i].1).collect(),
+            texts: m
+                .iter()
+                .take(3)
+                .map(|&i| dataset[i].0.chars().take(REPORT_SNIPPET_LENGTH).collect())
+                .collect(),
+        })
+        .collect();
+
+    let report = DedupReport {
+        total_count: dataset.len(),
+        unique_count: members.len(),
+        exact_duplicate_count,
+        near_duplicate_count,
+        duplicate_group_count: duplicate_groups.len(),
+        largest_group: duplicate_groups.first().map(|m| m.len()).unwrap_or(0),
+        conflicting_label_groups,
+        largest_groups,
+    };
+    (groups, report)
+}
+
+/// Keeps the first occurrence of every duplicate group.
+pub fn deduplicate(dataset: Vec<(String, f32)>, threshold: f32) -> (Vec<(String, f32)>, DedupReport) {
+    let (groups, report) = dedup_report(&dataset, threshold);
+    let deduplicated = dataset
+        .into_iter()
+        .zip(groups)
+        .enumerate()
+        .filter(|(index, (_, group))| index == group)
+        .map(|(_, (entry, _))| entry)
+        .collect();
+    (deduplicated, report)
+}
+
+/// Splits row indices into train and test so that all members of a duplicate group
+/// end up on the same side. `ratio` is the target share of rows in the training split.
+pub fn group_split(groups: &[usize], ratio: f64, seed: u64) -> (Vec<usize>, Vec<usize>) {
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (index, group) in groups.iter().enumerate() {
+        members.entry(*group).or_default().push(index);
+    }
+    let mut group_ids: Vec<usize> = members.keys().copied().collect();
+    group_ids.sort();
+    group_ids.shuffle(&mut StdRng::seed_from_u64(seed));
+
+    let train_target = (groups.len() as f64 * ratio) as usize;
+    let mut train: Vec<usize> = Vec::new();
+    let mut test: Vec<usize> = Vec::new();
+    for group in group_ids {
+        if train.len() < train_target {
+            train.extend(&members[&group]);
+        } else {
+            test.extend(&members[&group]);
+        }
+    }
+    (train, test)
+}
+
+/// Splits the records so that (near-)duplicates stay on one side, otherwise they leak into the test data.
+///
+/// The records are ordered by source and text hash first, so the same seed gives the same split
+/// however the records were ordered in the file.
+pub fn split_records(mut records: Vec<EmbeddingRecord>, train_ratio: f64, seed: u64) -> (Vec<EmbeddingRecord>, Vec<EmbeddingRecord>) {
+    records.sort_by_cached_key(|record| (record.source.clone(), fnv1a_hash(record.text.as_bytes())));
+    let texts: Vec<&str> = records.iter().map(|record| record.text.as_str()).collect();
+    let groups = duplicate_groups(&texts, DEFAULT_NEAR_DUPLICATE_THRESHOLD);
+    let (train_indices, test_indices) = group_split(&groups, train_ratio, seed);
+
+    let select = |indices: &[usize]| -> Vec<EmbeddingRecord> {
+        indices.iter().map(|&i| records[i].clone()).collect()
+    };
+    (select(&train_indices), select(&test_indices))
+}
@@ -0,0 +1,471 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+const COLUMNAR_MAGIC: &[u8; 8] = b"LFDEMB01";
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct EmbeddingRecord {
+    pub text: String,
+    pub label: f32,
+    #[serde(default)]
+    pub source: String,
+    pub embedding: Vec<f32>,
+    /// Fraud categories of the text, empty for datasets without category columns.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+}
+
+/// Selects a subset of the records while reading.
+#[derive(Clone, Debug, Default)]
+pub struct RecordFilter {
+    /// Only keep records from these dataset files, given as their path relative to the manifest or their file name.
+    pub sources: Option<Vec<String>>,
+    /// Only keep spam (`Some(true)`) or ham (`Some(false)`) records.
+    pub spam: Option<bool>,
+    /// Keep every matching record with this probability.
+    pub sample_rate: Option<f64>,
+    pub seed: u64,
+}
+
+impl RecordFilter {
+    fn matches(&self, record: &EmbeddingRecord) -> bool {
+        if let Some(sources) = &self.sources {
+            let file_name = record.source.rsplit('/').next().unwrap_or_default();
+            if !sources.iter().any(|source| source == &record.source || source == file_name) {
+                return false;
+            }
+        }
+        match self.spam {
+            Some(spam) => (record.label >= 0.5) == spam,
+            None => true,
+        }
+    }
+}
+
+/// Streams the records of a JSON lines embeddings file, one line at a time.
+///
+/// Blank lines are skipped. Lines that can not be parsed or fail validation are yielded
+/// as errors (with their line number), so the caller decides whether to skip or abort.
+pub struct EmbeddingRecordReader<R> {
+    reader: R,
+    line: String,
+    line_number: usize,
+    validator: RecordValidator,
+}
+
+/// The checks every record read from an embeddings file passes, whatever its format: a non-empty, finite
+/// embedding of the same dimension as the first one and a label within [0, 1].
+#[derive(Default)]
+struct RecordValidator {
+    dimension: Option<usize>,
+}
+
+impl RecordValidator {
+    fn validate(&mut self, record: &EmbeddingRecord) -> anyhow::Result<()> {
+        if record.embedding.is_empty() {
+            return Err(anyhow::anyhow!("empty embedding"));
+        }
+        if record.embedding.iter().any(|value| !value.is_finite()) {
+            return Err(anyhow::anyhow!("embedding contains non-finite values"));
+        }
+        if !record.label.is_finite() || !(0.0..=1.0).contains(&record.label) {
+            return Err(anyhow::anyhow!("label {} is not within [0, 1]", record.label));
+        }
+        match self.dimension {
+            Some(dimension) if dimension != record.embedding.len() => {
+                Err(anyhow::anyhow!("embedding dimension {} does not match {}", record.embedding.len(), dimension))
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.dimension = Some(record.embedding.len());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<R: BufRead> EmbeddingRecordReader<R> {
+    pub fn new(reader: R) -> Self {
+        EmbeddingRecordReader { reader, line: String::new(), line_number: 0, validator: RecordValidator::default() }
+    }
+}
+
+impl<R: BufRead> Iterator for EmbeddingRecordReader<R> {
+    type Item = anyhow::Result<EmbeddingRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line.clear();
+            self.line_number += 1;
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => return Some(Err(err.into())),
+            }
+            if self.line.trim().is_empty() {
+                continue;
+            }
+            let result = serde_json::from_str::<EmbeddingRecord>(&self.line)
+                .map_err(anyhow::Error::from)
+                .and_then(|record| self.validator.validate(&record).map(|_| record))
+                .map_err(|err| anyhow::anyhow!("line {}: {}", self.line_number, err));
+            return Some(result);
+        }
+    }
+}
+
+/// Reads the records of an embeddings file, in JSON lines or columnar format (detected by its header).
+pub fn read_embedding_records(path: &str, filter: RecordFilter) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<EmbeddingRecord>>>> {
+    let records: Box<dyn Iterator<Item = anyhow::Result<EmbeddingRecord>>> = if is_columnar_file(path)? {
+        Box::new(ColumnarRecordReader::open(path)?)
+    } else {
+        Box::new(EmbeddingRecordReader::new(BufReader::new(open_file(path)?)))
+    };
+
+    let mut rng = StdRng::seed_from_u64(filter.seed);
+    Ok(Box::new(records.filter(move |result| match result {
+        Ok(record) => filter.matches(record) && filter.sample_rate.map(|rate| rng.gen_bool(rate.clamp(0.0, 1.0))).unwrap_or(true),
+        Err(_) => true,
+    })))
+}
+
+fn open_file(path: &str) -> anyhow::Result<File> {
+    File::open(path).map_err(|err| anyhow::anyhow!("Error: unable to open '{}': {}", path, err))
+}
+
+pub fn is_columnar_file(path: &str) -> anyhow::Result<bool> {
+    let mut header = [0u8; 8];
+    let mut file = open_file(path)?;
+    let mut read = 0;
+    while read < header.len() {
+        match file.read(&mut header[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read == header.len() && &header == COLUMNAR_MAGIC)
+}
+
+/// All records of an embeddings file, column by column.
+#[derive(Clone, Debug, Default)]
+pub struct EmbeddingColumns {
+    pub dimension: usize,
+    pub embeddings: Vec<Vec<f32>>,
+    pub labels: Vec<f32>,
+    pub sources: Vec<String>,
+    pub texts: Vec<String>,
+    /// Lists are empty for files written before categories were stored.
+    pub categories: Vec<Vec<String>>,
+}
+
+impl EmbeddingColumns {
+    pub fn into_records(self) -> impl Iterator<Item = EmbeddingRecord> {
+        let categories = self.categories.into_iter().chain(std::iter::repeat(Vec::new()));
+        self.texts.into_iter()
+            .zip(self.labels)
+            .zip(self.sources)
+            .zip(self.embeddings)
+            .zip(categories)
+            .map(|((((text, label), source), embedding), categories)| EmbeddingRecord { text, label, source, embedding, categories })
+    }
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> std::io::Result<()> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Like `read_u32`, but `None` at the end of the input.
+fn read_optional_u32<R: Read>(reader: &mut R) -> anyhow::Result<Option<u32>> {
+    let mut bytes = [0u8; 4];
+    let mut read = 0;
+    while read < bytes.len() {
+        match reader.read(&mut bytes[read..])? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(anyhow::anyhow!("Error: truncated category table")),
+            n => read += n,
+        }
+    }
+    Ok(Some(u32::from_le_bytes(bytes)))
+}
+
+/// Grows the buffer while reading, so a corrupt length can not allocate more than the file holds.
+fn read_string<R: Read>(reader: &mut R) -> anyhow::Result<String> {
+    let length = read_u32(reader)? as u64;
+    let mut bytes = Vec::new();
+    if reader.take(length).read_to_end(&mut bytes)? as u64 != length {
+        return Err(anyhow::anyhow!("Error: truncated string"));
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Writes the records in a binary columnar format:
+///
+/// header (magic, row count as u64, dimension as u32), the embeddings as little endian f32
+/// (row-major), the labels, a source table with one u32 source id per row, and the texts,
+/// followed by a category table and the category ids of every row (count, then the ids as u32).
+///
+/// Embeddings are streamed to disk, only labels, sources and texts are kept in memory.
+/// The file is written to a temporary path and renamed once it is complete.
+pub fn write_columnar_embeddings<I: Iterator<Item = EmbeddingRecord>>(path: &str, records: I) -> anyhow::Result<usize> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+
+    writer.write_all(COLUMNAR_MAGIC)?;
+    writer.write_all(&0u64.to_le_bytes())?;
+    write_u32(&mut writer, 0)?;
+
+    let mut dimension: Option<usize> = None;
+    let mut labels: Vec<f32> = Vec::new();
+    let mut source_table: Vec<String> = Vec::new();
+    let mut source_ids: Vec<u32> = Vec::new();
+    let mut texts: Vec<String> = Vec::new();
+    let mut category_table: Vec<String> = Vec::new();
+    let mut category_ids: Vec<Vec<u32>> = Vec::new();
+
+    for record in records {
+        match dimension {
+            Some(dimension) if dimension != record.embedding.len() => {
+                return Err(anyhow::anyhow!("Error: embedding dimension {} does not match {}", record.embedding.len(), dimension));
+            }
+            None => dimension = Some(record.embedding.len()),
+            _ => {}
+        }
+        for value in &record.embedding {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        labels.push(record.label);
+        let source_id = match source_table.iter().position(|source| source == &record.source) {
+            Some(id) => id,
+            None => {
+                source_table.push(record.source);
+                source_table.len() - 1
+            }
+        };
+        source_ids.push(source_id as u32);
+        texts.push(record.text);
+        category_ids.push(record.categories.into_iter().map(|category| {
+            match category_table.iter().position(|known| known == &category) {
+                Some(id) => id as u32,
+                None => {
+                    category_table.push(category);
+                    (category_table.len() - 1) as u32
+                }
+            }
+        }).collect());
+    }
+
+    for label in &labels {
+        writer.write_all(&label.to_le_bytes())?;
+    }
+    write_u32(&mut writer, source_table.len() as u32)?;
+    for source in &source_table {
+        write_string(&mut writer, source)?;
+    }
+    for source_id in &source_ids {
+        write_u32(&mut writer, *source_id)?;
+    }
+    for text in &texts {
+        write_string(&mut writer, text)?;
+    }
+    write_u32(&mut writer, category_table.len() as u32)?;
+    for category in &category_table {
+        write_string(&mut writer, category)?;
+    }
+    for ids in &category_ids {
+        write_u32(&mut writer, ids.len() as u32)?;
+        for id in ids {
+            write_u32(&mut writer, *id)?;
+        }
+    }
+
+    let mut file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.seek(SeekFrom::Start(COLUMNAR_MAGIC.len() as u64))?;
+    file.write_all(&(labels.len() as u64).to_le_bytes())?;
+    write_u32(&mut file, dimension.unwrap_or(0) as u32)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(labels.len())
+}
+
+/// Streams the records of a columnar embeddings file.
+///
+/// The columns are read in parallel through one file cursor each, only the source and category
+/// tables are kept in memory. The header is checked against the file size before reading.
+/// Records are validated like those of a JSON lines file, invalid ones are yielded as errors (with their row number).
+pub struct ColumnarRecordReader {
+    path: String,
+    row_count: usize,
+    dimension: usize,
+    position: usize,
+    embeddings: BufReader<File>,
+    labels: BufReader<File>,
+    source_ids: BufReader<File>,
+    texts: BufReader<File>,
+    /// `None` for files written before categories were stored.
+    categories: Option<BufReader<File>>,
+    source_table: Vec<String>,
+    category_table: Vec<String>,
+    validator: RecordValidator,
+}
+
+impl ColumnarRecordReader {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let corrupt = |what: &str| anyhow::anyhow!("Error: '{}' is truncated or corrupt ({})", path, what);
+        let file_size = open_file(path)?.metadata()?.len();
+        let mut reader = BufReader::new(open_file(path)?);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != COLUMNAR_MAGIC {
+            return Err(anyhow::anyhow!("Error: '{}' is not a columnar embeddings file", path));
+        }
+        let row_count = read_u64(&mut reader)?;
+        let dimension = read_u32(&mut reader)? as u64;
+        let header_size = (COLUMNAR_MAGIC.len() + 12) as u64;
+        // every row takes its embedding, a label, a source id and a text length at least
+        let minimum_size = dimension.checked_mul(4).and_then(|size| size.checked_add(12))
+            .and_then(|size| size.checked_mul(row_count))
+            .and_then(|size| size.checked_add(header_size + 4));
+        if minimum_size.is_none_or(|size| size > file_size) {
+            return Err(corrupt(&format!("{} rows of dimension {} do not fit into {} bytes", row_count, dimension, file_size)));
+        }
+        let (row_count, dimension) = (row_count as usize, dimension as usize);
+
+        let labels_offset = header_size + (row_count * dimension * 4) as u64;
+        let source_table_offset = labels_offset + (row_count * 4) as u64;
+        reader.seek(SeekFrom::Start(source_table_offset))?;
+        let source_table = read_table(&mut reader).map_err(|err| corrupt(&err.to_string()))?;
+        let source_ids_offset = reader.stream_position()?;
+        let texts_offset = source_ids_offset + (row_count * 4) as u64;
+
+        // skip the texts to find the categories
+        reader.seek(SeekFrom::Start(texts_offset))?;
+        for _ in 0..row_count {
+            let length = read_u32(&mut reader).map_err(|_| corrupt("texts"))?;
+            reader.seek_relative(length as i64)?;
+        }
+        if reader.stream_position()? > file_size {
+            return Err(corrupt("texts"));
+        }
+        let (category_table, categories) = if read_optional_u32(&mut reader)?.is_some() {
+            reader.seek_relative(-4)?;
+            let table = read_table(&mut reader).map_err(|err| corrupt(&err.to_string()))?;
+            let offset = reader.stream_position()?;
+            if offset + (row_count * 4) as u64 > file_size {
+                return Err(corrupt("categories"));
+            }
+            (table, Some(open_at(path, offset)?))
+        } else {
+            (Vec::new(), None)
+        };
+
+        Ok(ColumnarRecordReader {
+            path: path.to_string(),
+            row_count,
+            dimension,
+            position: 0,
+            embeddings: open_at(path, header_size)?,
+            labels: open_at(path, labels_offset)?,
+            source_ids: open_at(path, source_ids_offset)?,
+            texts: open_at(path, texts_offset)?,
+            categories,
+            source_table,
+            category_table,
+            validator: RecordValidator::default(),
+        })
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.row_count
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn read_record(&mut self) -> anyhow::Result<EmbeddingRecord> {
+        let mut row = vec![0u8; self.dimension * 4];
+        self.embeddings.read_exact(&mut row)?;
+        let embedding = row.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect();
+        let label = f32::from_bits(read_u32(&mut self.labels)?);
+        let source_id = read_u32(&mut self.source_ids)? as usize;
+        let source = self.source_table.get(source_id).cloned()
+            .ok_or_else(|| anyhow::anyhow!("Error: invalid source id {} in '{}'", source_id, self.path))?;
+        let text = read_string(&mut self.texts)?;
+        let categories = match &mut self.categories {
+            Some(reader) => (0..read_u32(reader)?).map(|_| {
+                let id = read_u32(reader)? as usize;
+                self.category_table.get(id).cloned().ok_or_else(|| anyhow::anyhow!("Error: invalid category id {} in '{}'", id, self.path))
+            }).collect::<anyhow::Result<Vec<String>>>()?,
+            None => Vec::new(),
+        };
+        Ok(EmbeddingRecord { text, label, source, embedding, categories })
+    }
+}
+
+impl Iterator for ColumnarRecordReader {
+    type Item = anyhow::Result<EmbeddingRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.row_count {
+            return None;
+        }
+        self.position += 1;
+        let row = self.position;
+        let record = self.read_record();
+        if record.is_err() {
+            // the columns are out of step after a read error
+            self.position = self.row_count;
+        }
+        Some(record
+            .and_then(|record| self.validator.validate(&record).map(|_| record))
+            .map_err(|err| anyhow::anyhow!("row {}: {}", row, err)))
+    }
+}
+
+fn open_at(path: &str, offset: u64) -> anyhow::Result<BufReader<File>> {
+    let mut reader = BufReader::new(open_file(path)?);
+    reader.seek(SeekFrom::Start(offset))?;
+    Ok(reader)
+}
+
+fn read_table<R: Read>(reader: &mut R) -> anyhow::Result<Vec<String>> {
+    (0..read_u32(reader)?).map(|_| read_string(reader)).collect()
+}
+
+/// All records of a columnar embeddings file, see `ColumnarRecordReader` to stream them instead.
+pub fn read_columnar_embeddings(path: &str) -> anyhow::Result<EmbeddingColumns> {
+    let reader = ColumnarRecordReader::open(path)?;
+    let mut columns = EmbeddingColumns { dimension: reader.dimension(), ..Default::default() };
+    for record in reader {
+        let record = record?;
+        columns.embeddings.push(record.embedding);
+        columns.labels.push(record.label);
+        columns.sources.push(record.source);
+        columns.texts.push(record.text);
+        columns.categories.push(record.categories);
+    }
+    Ok(columns)
+}
@@ -1,10 +1,16 @@
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Component, Path};
+use serde::{Deserialize, Serialize};
 
-const MIN_TEXT_LENGTH: usize = 20;
+pub mod dedup;
+pub mod embedding_dataset;
 
-fn read_dataset(path: &str) -> anyhow::Result<Vec<(String,f32)>> {
+/// Shorter texts are skipped when reading a dataset.
+pub const MIN_TEXT_LENGTH: usize = 20;
 
-    let (text_index,label_index) = if path.contains("enronSpamSubset") {
+fn default_columns(path: &str) -> (usize,usize) {
+    if path.contains("enronSpamSubset") {
         (2,3)
     }else if path.contains("smsspamcollection"){
         (1,0)
@@ -12,9 +18,29 @@ fn read_dataset(path: &str) -> anyhow::Result<Vec<(String,f32)>> {
         (1,2)
     }else{ // youtubeSpamCollection
         (0,1)
-    };
+    }
+}
+
+fn read_dataset(path: &str) -> anyhow::Result<Vec<(String,f32)>> {
+    let (text_index,label_index) = default_columns(path);
+    read_dataset_columns(path, text_index, label_index)
+}
+
+fn read_dataset_columns(path: &str, text_index: usize, label_index: usize) -> anyhow::Result<Vec<(String,f32)>> {
+    Ok(read_dataset_rows(path, text_index, label_index, None, &BTreeMap::new())?.into_iter().map(|(text,label,_)| (text,label)).collect())
+}
+
+/// Separators between the categories of a row in a single category column.
+const CATEGORY_SEPARATORS: [char; 2] = [';', '|'];
+
+fn is_set(value: &str) -> bool {
+    let value = value.trim();
+    value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("yes") || value.parse::<f64>().map(|value| value >= 0.5).unwrap_or(false)
+}
 
-    let mut training_data: Vec<(String,f32)> = Vec::new();
+fn read_dataset_rows(path: &str, text_index: usize, label_index: usize, category_index: Option<usize>, category_columns: &BTreeMap<String,usize>) -> anyhow::Result<Vec<(String,f32,Vec<String>)>> {
+
+    let mut training_data: Vec<(String,f32,Vec<String>)> = Vec::new();
 
     let file = File::open(path)?;
     let mut rdr = csv::Reader::from_reader(file);
@@ -25,7 +51,17 @@ fn read_dataset(path: &str) -> anyhow::Result<Vec<(String,f32)>> {
         if let (Some(text),Some(label)) = (record_text,record_label) {
                 if let Ok(parsed_value) = label.parse::<f64>() {
                     if text.len() >= MIN_TEXT_LENGTH {
-                        training_data.push((text.to_string(),parsed_value as f32));
+                        let mut categories: Vec<String> = category_index.and_then(|index| record.get(index))
+                            .map(|value| value.split(CATEGORY_SEPARATORS).map(str::trim).filter(|category| !category.is_empty()).map(str::to_string).collect())
+                            .unwrap_or_default();
+                        for (category, &index) in category_columns {
+                            if record.get(index).is_some_and(is_set) && !categories.contains(category) {
+                                categories.push(category.clone());
+                            }
+                        }
+                        categories.sort();
+                        categories.dedup();
+                        training_data.push((text.to_string(),parsed_value as f32,categories));
                     }
                 }
         }
@@ -35,16 +71,171 @@ fn read_dataset(path: &str) -> anyhow::Result<Vec<(String,f32)>> {
 
 
 pub fn read_datasets(dataset_paths: &[&str]) -> anyhow::Result<Vec<(String,f32)>> {
-    let mut dataset: Vec<(String,f32)> = read_dataset(dataset_paths.get(0).ok_or(anyhow::anyhow!("Error: dataset_paths is empty!'"))?)?;
-    if dataset_paths.len() > 1 {
-        for i in 1..dataset_paths.len() {
-            dataset.append(&mut read_dataset(dataset_paths[i])?);
-        }
+    let mut dataset: Vec<(String,f32)> = read_dataset(dataset_paths.first().ok_or(anyhow::anyhow!("Error: dataset_paths is empty!'"))?)?;
+    for path in &dataset_paths[1..] {
+        dataset.append(&mut read_dataset(path)?);
     }
     Ok(dataset)
 }
 
 
+/// Like `read_datasets`, but keeps the file name of the dataset each row was read from.
+pub fn read_datasets_with_source(dataset_paths: &[&str]) -> anyhow::Result<Vec<(String,String,f32)>> {
+    DatasetManifest::from_paths(dataset_paths).read()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DatasetEntry {
+    pub path: String,
+    /// Column indices of the text and the label, guessed from the file name if omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_column: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_column: Option<usize>,
+    /// Column holding the category of a row (multi-class), several categories are separated by `;` or `|`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category_column: Option<usize>,
+    /// Category name to the column that is 1 (or `true`) for the rows of that category (multi-label).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub category_columns: BTreeMap<String, usize>,
+    /// The path relative to the manifest, set when the manifest is loaded.
+    #[serde(skip)]
+    source: Option<String>,
+}
+
+impl DatasetEntry {
+    pub fn new(path: &str, text_column: Option<usize>, label_column: Option<usize>) -> Self {
+        DatasetEntry { path: path.to_string(), text_column, label_column, category_column: None, category_columns: BTreeMap::new(), source: None }
+    }
+
+    /// The source of the rows of the dataset: its path relative to the manifest, or its file name
+    /// for datasets passed without a manifest.
+    pub fn source(&self) -> String {
+        self.source.clone().unwrap_or_else(|| dataset_source(&self.path))
+    }
+
+    /// Column indices of the text and the label.
+    pub fn columns(&self) -> (usize, usize) {
+        let (default_text_index,default_label_index) = default_columns(&self.path);
+        (self.text_column.unwrap_or(default_text_index), self.label_column.unwrap_or(default_label_index))
+    }
+}
+
+/// A row of a dataset, `source` is the `DatasetEntry::source` of the dataset.
+#[derive(Clone, Debug)]
+pub struct DatasetRow {
+    pub source: String,
+    pub text: String,
+    pub label: f32,
+    /// Fraud categories of the row, sorted, empty for datasets without category columns.
+    pub categories: Vec<String>,
+}
+
+/// The list of CSV datasets used for training, stored as JSON:
+///
+/// `{"datasets": [{"path": "youtubeSpamCollection.csv", "text_column": 0, "label_column": 1}]}`
+///
+/// Datasets with fraud categories add `"category_column": 3` (one column with the category names)
+/// or `"category_columns": {"phishing": 3, "fake_upgrade": 4}` (one 0/1 column per category).
+///
+/// Relative paths are resolved against the directory of the manifest file.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DatasetManifest {
+    pub datasets: Vec<DatasetEntry>,
+}
+
+impl DatasetManifest {
+    pub fn from_paths(dataset_paths: &[&str]) -> Self {
+        DatasetManifest {
+            datasets: dataset_paths.iter().map(|path| DatasetEntry::new(path, None, None)).collect(),
+        }
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let mut manifest: DatasetManifest = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| anyhow::anyhow!("Error: unable to parse dataset manifest '{}': {}", path, err))?;
+        let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let absolute_base = std::path::absolute(base)?;
+        for entry in manifest.datasets.iter_mut() {
+            let dataset = Path::new(&entry.path);
+            let relative = dataset.strip_prefix(&absolute_base).unwrap_or(dataset);
+            // the same on every platform, and `./a.csv` is `a.csv`
+            let components: Vec<String> = relative.components()
+                .filter(|component| !matches!(component, Component::CurDir))
+                .map(|component| component.as_os_str().to_string_lossy().to_string())
+                .collect();
+            entry.source = Some(components.join("/"));
+            if dataset.is_relative() {
+                entry.path = base.join(&entry.path).to_string_lossy().to_string();
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Reads all datasets as (source, text, label), see `DatasetEntry::source`.
+    pub fn read(&self) -> anyhow::Result<Vec<(String,String,f32)>> {
+        Ok(self.read_rows()?.into_iter().map(|row| (row.source,row.text,row.label)).collect())
+    }
+
+    /// Reads all datasets, including the categories of every row.
+    pub fn read_rows(&self) -> anyhow::Result<Vec<DatasetRow>> {
+        if self.datasets.is_empty() {
+            return Err(anyhow::anyhow!("Error: dataset manifest is empty!"));
+        }
+        let mut dataset: Vec<DatasetRow> = Vec::new();
+        for entry in &self.datasets {
+            let (text_index,label_index) = entry.columns();
+            let source = entry.source();
+            let rows = read_dataset_rows(&entry.path, text_index, label_index, entry.category_column, &entry.category_columns)
+                .map_err(|err| anyhow::anyhow!("Error: unable to read dataset '{}': {}", entry.path, err))?;
+            dataset.extend(rows.into_iter().map(|(text,label,categories)| DatasetRow { source: source.clone(), text, label, categories }));
+        }
+        Ok(dataset)
+    }
+
+    /// The entry of the dataset at `dataset_path`, if it is listed.
+    pub fn entry(&self, dataset_path: &str) -> anyhow::Result<Option<&DatasetEntry>> {
+        let dataset = std::path::absolute(Path::new(dataset_path))?;
+        for entry in &self.datasets {
+            if std::path::absolute(Path::new(&entry.path))? == dataset {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads all datasets as (text, label).
+    pub fn read_texts(&self) -> anyhow::Result<Vec<(String,f32)>> {
+        Ok(self.read()?.into_iter().map(|(_,text,label)| (text,label)).collect())
+    }
+
+    /// Adds a dataset to the manifest file unless it is already listed, returns whether it was added.
+    ///
+    /// The path is stored relative to the manifest if the dataset lies below its directory.
+    pub fn register(manifest_path: &str, dataset_path: &str, text_column: usize, label_column: usize) -> anyhow::Result<bool> {
+        let mut manifest: DatasetManifest = serde_json::from_str(&fs::read_to_string(manifest_path)?)
+            .map_err(|err| anyhow::anyhow!("Error: unable to parse dataset manifest '{}': {}", manifest_path, err))?;
+        let base = std::path::absolute(Path::new(manifest_path))?.parent().map(Path::to_path_buf).unwrap_or_default();
+        let dataset = std::path::absolute(Path::new(dataset_path))?;
+        if manifest.datasets.iter().any(|entry| base.join(&entry.path) == dataset) {
+            return Ok(false);
+        }
+
+        let path = dataset.strip_prefix(&base).unwrap_or(&dataset).to_string_lossy().to_string();
+        manifest.datasets.push(DatasetEntry::new(&path, Some(text_column), Some(label_column)));
+        let tmp_path = format!("{}.tmp", manifest_path);
+        fs::write(&tmp_path, serde_json::to_string_pretty(&manifest)?)?;
+        fs::rename(&tmp_path, manifest_path)?;
+        Ok(true)
+    }
+}
+
+/// The file name of the dataset at `path`.
+pub fn dataset_source(path: &str) -> String {
+    Path::new(path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| path.to_string())
+}
+
+
 pub fn split_vector<T>(vector: &[T], ratio: f64) -> (&[T], &[T]) {
     let split_index = (vector.len() as f64 * ratio) as usize;
     vector.split_at(split_index)
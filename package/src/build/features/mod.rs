@@ -0,0 +1,188 @@
+//! Hand-crafted scam signals, as a complement to the text embeddings.
+
+use std::collections::BTreeSet;
+
+use regex::Regex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+lazy_static::lazy_static! {
+    static ref URL: Regex = Regex::new(r"(?i)\b(?:https?://|www\.)[^\s<>()]+").unwrap();
+    static ref URL_DOMAIN: Regex = Regex::new(r"(?i)^(?:https?://)?(?:www\.)?([a-z0-9.-]+)").unwrap();
+    /// Candidates only, Bitcoin and Cosmos addresses are counted if their checksum is valid (see `is_crypto_address`).
+    static ref CRYPTO_ADDRESS: Regex = Regex::new(
+        r"\b(?:0x[a-fA-F0-9]{40}|bc1[a-z0-9]{25,59}|[13][a-km-zA-HJ-NP-Z1-9]{25,34}|(?:terra|cosmos|osmo|juno|kujira|secret)1[a-z0-9]{38,58})\b"
+    ).unwrap();
+    static ref REWARD: Regex = Regex::new(r"(?i)\b(?:airdrops?|claim(?:ing|ed)?|rewards?|giveaways?|bonus(?:es)?|prizes?|winners?|free|whitelist(?:ed)?|eligible)\b").unwrap();
+    static ref URGENCY: Regex = Regex::new(
+        r"(?i)\b(?:urgent(?:ly)?|immediately|now|hurry|today only|last chance|final|limited|expires?|expiring|deadline|act fast|don'?t miss)\b"
+    ).unwrap();
+    /// Digit groups with a leading `+`, an area code in parentheses, `-` or `.` separators, or space separators
+    /// after a trunk `0`; bare runs of digits (amounts, ids, timestamps) are not phone numbers.
+    /// Candidates only, see `is_phone_number`.
+    static ref PHONE_NUMBER: Regex = Regex::new(concat!(
+        r"(?:\+\d{1,3}[\s.-]?(?:\(?\d{1,5}\)?[\s.-]?)?\d{2,5}(?:[\s.-]?\d{2,5}){0,4}",
+        r"|\(\d{2,5}\)[\s.-]?\d{2,5}(?:[\s.-]\d{2,5}){0,3}",
+        r"|\b\d{2,5}(?:[.-]\d{2,5}){2,4}",
+        r"|\b0\d{1,4}(?:\s\d{2,5}){1,4})\b",
+    )).unwrap();
+}
+
+pub const SIGNAL_FEATURE_LABELS: [&str; 9] = [
+    "url_count",
+    "domain_count",
+    "crypto_address_count",
+    "reward_word_count",
+    "urgency_word_count",
+    "phone_number_count",
+    "emoji_density",
+    "all_caps_ratio",
+    "exclamation_count",
+];
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SignalFeatures {
+    pub url_count: usize,
+    /// Distinct domains of the URLs.
+    pub domains: Vec<String>,
+    pub crypto_address_count: usize,
+    pub reward_word_count: usize,
+    pub urgency_word_count: usize,
+    pub phone_number_count: usize,
+    /// Emoji per character.
+    pub emoji_density: f32,
+    /// Share of the words (two or more letters) written in capitals.
+    pub all_caps_ratio: f32,
+    pub exclamation_count: usize,
+}
+
+impl SignalFeatures {
+    pub fn extract(text: &str) -> Self {
+        let urls: Vec<&str> = URL.find_iter(text).map(|m| m.as_str()).collect();
+        let domains: BTreeSet<String> = urls.iter()
+            .filter_map(|url| URL_DOMAIN.captures(url))
+            .map(|captures| captures[1].trim_end_matches('.').to_lowercase())
+            .collect();
+
+        let char_count = text.chars().count();
+        let emoji_count = text.chars().filter(|&c| is_emoji(c)).count();
+
+        let words: Vec<&str> = text.split_whitespace()
+            .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+            .filter(|word| word.chars().filter(|c| c.is_alphabetic()).count() >= 2)
+            .collect();
+        let all_caps_count = words.iter()
+            .filter(|word| word.chars().filter(|c| c.is_alphabetic()).all(|c| c.is_uppercase()))
+            .count();
+
+        SignalFeatures {
+            url_count: urls.len(),
+            domains: domains.into_iter().collect(),
+            crypto_address_count: CRYPTO_ADDRESS.find_iter(text).filter(|m| is_crypto_address(m.as_str())).count(),
+            reward_word_count: REWARD.find_iter(text).count(),
+            urgency_word_count: URGENCY.find_iter(text).count(),
+            phone_number_count: PHONE_NUMBER.find_iter(text).filter(|m| is_phone_number(m.as_str())).count(),
+            emoji_density: if char_count == 0 { 0.0 } else { emoji_count as f32 / char_count as f32 },
+            all_caps_ratio: if words.is_empty() { 0.0 } else { all_caps_count as f32 / words.len() as f32 },
+            exclamation_count: text.matches('!').count(),
+        }
+    }
+
+    /// The features in the order of `SIGNAL_FEATURE_LABELS`.
+    pub fn to_vector(&self) -> Vec<f32> {
+        vec![
+            self.url_count as f32,
+            self.domains.len() as f32,
+            self.crypto_address_count as f32,
+            self.reward_word_count as f32,
+            self.urgency_word_count as f32,
+            self.phone_number_count as f32,
+            self.emoji_density,
+            self.all_caps_ratio,
+            self.exclamation_count as f32,
+        ]
+    }
+}
+
+/// 9 to 15 digits (E.164), and not an IPv4 address.
+fn is_phone_number(candidate: &str) -> bool {
+    let digit_count = candidate.chars().filter(char::is_ascii_digit).count();
+    let groups: Vec<&str> = candidate.split('.').collect();
+    let is_ip_address = groups.len() == 4 && groups.iter().all(|group| group.len() <= 3 && group.chars().all(|c| c.is_ascii_digit()));
+    (9..=15).contains(&digit_count) && !is_ip_address
+}
+
+/// Ethereum addresses have no mandatory checksum, Bitcoin (base58check, bech32) and Cosmos (bech32) addresses do.
+fn is_crypto_address(candidate: &str) -> bool {
+    if candidate.starts_with("0x") {
+        true
+    } else if candidate.starts_with(['1', '3']) {
+        is_base58check_address(candidate)
+    } else {
+        is_bech32(candidate)
+    }
+}
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// A version byte, a 20 byte hash and the first 4 bytes of the double SHA-256 of both.
+fn is_base58check_address(candidate: &str) -> bool {
+    let mut bytes = [0u8; 25];
+    for c in candidate.bytes() {
+        let Some(mut carry) = BASE58_ALPHABET.iter().position(|&known| known == c).map(|digit| digit as u32) else {
+            return false;
+        };
+        for byte in bytes.iter_mut().rev() {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        if carry != 0 {
+            return false;
+        }
+    }
+    let leading_zeros = candidate.bytes().take_while(|&c| c == b'1').count();
+    let checksum = Sha256::digest(Sha256::digest(&bytes[..21]));
+    bytes.iter().take_while(|&&byte| byte == 0).count() == leading_zeros && matches!(bytes[0], 0 | 5) && checksum[..4] == bytes[21..]
+}
+
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// BIP 173 (bech32) or BIP 350 (bech32m) checksum over the human readable part and the data.
+fn is_bech32(candidate: &str) -> bool {
+    let Some((hrp, data)) = candidate.rsplit_once('1') else {
+        return false;
+    };
+    let Some(data) = data.bytes().map(|c| BECH32_CHARSET.iter().position(|&known| known == c).map(|value| value as u32)).collect::<Option<Vec<u32>>>() else {
+        return false;
+    };
+    let values = hrp.bytes().map(|c| c as u32 >> 5).chain([0]).chain(hrp.bytes().map(|c| c as u32 & 31)).chain(data);
+    let checksum = values.fold(1u32, |checksum, value| {
+        let top = checksum >> 25;
+        let checksum = (checksum & 0x1ffffff) << 5 ^ value;
+        [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3].iter().enumerate()
+            .filter(|(bit, _)| top >> bit & 1 == 1)
+            .fold(checksum, |checksum, (_, generator)| checksum ^ generator)
+    });
+    checksum == 1 || checksum == 0x2bc830a3
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32,
+        0x1F300..=0x1FAFF   // pictographs, emoticons, transport, supplemental symbols
+        | 0x2600..=0x27BF   // miscellaneous symbols and dingbats
+        | 0x1F1E6..=0x1F1FF // regional indicators (flags)
+        | 0x2B00..=0x2BFF   // arrows and stars
+    )
+}
+
+/// The embedding followed by the signal features of its text.
+pub fn with_signal_features(embedding: &[f32], text: &str) -> Vec<f32> {
+    let mut features = embedding.to_vec();
+    features.extend(SignalFeatures::extract(text).to_vector());
+    features
+}
+
+pub fn signal_feature_labels() -> Vec<String> {
+    SIGNAL_FEATURE_LABELS.iter().map(|label| label.to_string()).collect()
+}
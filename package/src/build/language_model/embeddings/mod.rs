@@ -1,23 +1,94 @@
-use std::fs::File;
-use std::io::{self, Read};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use async_stream::stream;
 use futures::stream::Stream;
-use rand::prelude::SliceRandom;
-use rand::thread_rng;
+use tracing::{debug, info, warn};
+use crate::build::data::embedding_dataset::{read_embedding_records, EmbeddingRecord, RecordFilter};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Embedding {
     pub embedding: Vec<f32>,
 }
 
-pub async fn llama_cpp_embedding(text: &str) -> Result<Vec<f32>, io::Error> {
+/// Connecting to the embedding server takes at most this long.
+pub const EMBEDDING_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// An embedding request, including reading the response, takes at most this long.
+pub const EMBEDDING_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    static ref HTTP_CLIENT: reqwest::Client = embedding_client();
+}
+
+/// A client with the connect and request timeouts of the embedding requests.
+pub fn embedding_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(EMBEDDING_CONNECT_TIMEOUT)
+        .timeout(EMBEDDING_REQUEST_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+}
+
+/// Why an embedding request failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmbeddingErrorKind {
+    /// Nothing was sent for an empty text.
+    EmptyText,
+    /// The endpoint or the context size is not set.
+    Configuration,
+    /// The server could not be reached or did not answer in time.
+    Connection,
+    /// The server answered with an error status.
+    Status,
+    /// The response is not an embedding.
+    InvalidResponse,
+}
+
+impl EmbeddingErrorKind {
+    /// Name of the kind in the metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmbeddingErrorKind::EmptyText => "empty_text",
+            EmbeddingErrorKind::Configuration => "configuration",
+            EmbeddingErrorKind::Connection => "connection",
+            EmbeddingErrorKind::Status => "status",
+            EmbeddingErrorKind::InvalidResponse => "invalid_response",
+        }
+    }
+}
+
+/// Error of an embedding request, the kind can be recovered from an `anyhow::Error` by downcasting.
+#[derive(Debug)]
+pub struct EmbeddingError {
+    pub kind: EmbeddingErrorKind,
+    message: String,
+}
+
+impl EmbeddingError {
+    pub fn new(kind: EmbeddingErrorKind, message: impl Into<String>) -> Self {
+        EmbeddingError { kind, message: message.into() }
+    }
+}
+
+impl fmt::Display for EmbeddingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for EmbeddingError {}
+
+pub async fn llama_cpp_embedding(text: &str) -> Result<Vec<f32>, EmbeddingError> {
 
     let docker_embedding_dim = std::env::var("EMBEDDING_CONTEXT_SIZE")
-        .as_ref()
-        .unwrap()
-        .to_string().parse::<usize>().unwrap();
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .ok_or_else(|| EmbeddingError::new(EmbeddingErrorKind::Configuration, "EMBEDDING_CONTEXT_SIZE is not set to a number"))?;
 
     let input = text.to_string().chars().take(docker_embedding_dim*4).collect::<String>();
 
@@ -25,23 +96,27 @@ pub async fn llama_cpp_embedding(text: &str) -> Result<Vec<f32>, io::Error> {
 }
 
 
-pub async fn text_embedding_request(text: &str) -> Result<Vec<f32>, io::Error> {
+pub async fn text_embedding_request(text: &str) -> Result<Vec<f32>, EmbeddingError> {
     if text.is_empty() {
-        return Err(io::Error::new(io::ErrorKind::Other, "Invalid text embedding request: Empty string!"));
+        return Err(EmbeddingError::new(EmbeddingErrorKind::EmptyText, "Invalid text embedding request: Empty string!"));
+    }
+    let docker_embedding_endpoint = std::env::var("DOCKER_EMBEDDING_ENDPOINT")
+        .map_err(|_| EmbeddingError::new(EmbeddingErrorKind::Configuration, "DOCKER_EMBEDDING_ENDPOINT is not set"))?;
+
+    text_embedding_request_to(&HTTP_CLIENT, &docker_embedding_endpoint, text).await
+}
+
+pub async fn text_embedding_request_to(client: &reqwest::Client, endpoint: &str, text: &str) -> Result<Vec<f32>, EmbeddingError> {
+    if text.is_empty() {
+        return Err(EmbeddingError::new(EmbeddingErrorKind::EmptyText, "Invalid text embedding request: Empty string!"));
     }
-    let client = reqwest::Client::new();
 
     let json_data = json!({
         "content": text
     });
 
-    let docker_embedding_endpoint = std::env::var("DOCKER_EMBEDDING_ENDPOINT")
-        .as_ref()
-        .unwrap()
-        .to_string();
-
     let response = client
-        .post(docker_embedding_endpoint)
+        .post(endpoint)
         .header("Content-Type", "application/json")
         .body(json_data.to_string())
         .send()
@@ -50,20 +125,26 @@ pub async fn text_embedding_request(text: &str) -> Result<Vec<f32>, io::Error> {
     if let Ok(ok_response) = response {
         let debug_response = format!("{:?}",ok_response);
         if ok_response.status().is_success() {
-            if let Ok(ref json_response) =  ok_response.json::<Value>().await {
-                if let Ok(embedding) = serde_json::from_value::<Embedding>(json_response.clone()) {
-                    return Ok(embedding.embedding);
-                }else{
-                    return Err(io::Error::new(io::ErrorKind::Other, format!("Parsing failed: {:?}\n\n{}\n\n", json_response,text)))
-                }
-            }else{
-                return Err(io::Error::new(io::ErrorKind::Other, format!("Response body is not valid json: {}\n\n{}\n\n", debug_response,text)))
+            match ok_response.json::<Value>().await {
+                Ok(ref json_response) => {
+                    if let Ok(embedding) = serde_json::from_value::<Embedding>(json_response.clone()) {
+                        Ok(embedding.embedding)
+                    }else{
+                        Err(EmbeddingError::new(EmbeddingErrorKind::InvalidResponse, format!("Parsing failed: {:?}\n\n{}\n\n", json_response,text)))
+                    }
+                },
+                Err(err) if err.is_timeout() => {
+                    Err(EmbeddingError::new(EmbeddingErrorKind::Connection, format!("Command execution failed: {:?}\n\n{}\n\n", err,text)))
+                },
+                Err(_) => {
+                    Err(EmbeddingError::new(EmbeddingErrorKind::InvalidResponse, format!("Response body is not valid json: {}\n\n{}\n\n", debug_response,text)))
+                },
             }
         }else{
-            return Err(io::Error::new(io::ErrorKind::Other, format!("Got negative response status: {}\n\n{}\n\n",debug_response,text)))
+            Err(EmbeddingError::new(EmbeddingErrorKind::Status, format!("Got negative response status: {}\n\n{}\n\n",debug_response,text)))
         }
     }else{
-        return Err(io::Error::new(io::ErrorKind::Other, format!("Command execution failed: {:?}\n\n{}\n\n",response,text)))
+        Err(EmbeddingError::new(EmbeddingErrorKind::Connection, format!("Command execution failed: {:?}\n\n{}\n\n",response,text)))
     }
 }
 
@@ -82,7 +163,7 @@ pub fn extract_embeddings(dataset: Vec<(String, f32)>) -> impl Stream<Item = Res
                         }
                     )
                 ),
-                Err(err) => yield Err(anyhow::anyhow!(format!("An error occurred during embeddings generation: {:?}", err))),
+                Err(err) => yield Err(anyhow::anyhow!(format!("An error occurred during embeddings generation: {}", err))),
             }
         }
     }
@@ -90,34 +171,193 @@ pub fn extract_embeddings(dataset: Vec<(String, f32)>) -> impl Stream<Item = Res
 
 
 pub fn load_llama_cpp_embeddings_from_file(path: &str) -> anyhow::Result<(Vec<Vec<f32>>, Vec<f32>)> {
-    // Read the contents of the file
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-
-    // Initialize vectors to store embeddings and labels
-    let mut result: Vec<(Vec<f32>,f32)> = Vec::new();
-
-    for line in contents.split("\n") {
-        if let Ok(value) = serde_json::from_str::<serde_json::Value>(line){
-            if let Some(embedding) = value["embedding"].as_array() {
-                // Convert the embedding to a vector of f32
-                let embedding_vec: Vec<f32> = embedding
-                    .iter()
-                    .filter_map(|value| value.as_f64().map(|v| v as f32))
-                    .collect();
-
-                if let Some(label) = value["label"].as_f64() {
-                    result.push((embedding_vec,label as f32));
+    let (_texts, x_dataset, y_dataset) = load_llama_cpp_embeddings_with_texts_from_file(path)?;
+    Ok((x_dataset, y_dataset))
+}
+
+/// Texts, embeddings and labels of an embeddings file.
+pub type TextEmbeddingDataset = (Vec<String>, Vec<Vec<f32>>, Vec<f32>);
+
+pub fn load_llama_cpp_embeddings_with_texts_from_file(path: &str) -> anyhow::Result<TextEmbeddingDataset> {
+    let records = load_embedding_records_from_file(path)?;
+
+    let mut texts = Vec::with_capacity(records.len());
+    let mut x_dataset = Vec::with_capacity(records.len());
+    let mut y_dataset = Vec::with_capacity(records.len());
+    for record in records {
+        texts.push(record.text);
+        x_dataset.push(record.embedding);
+        y_dataset.push(record.label);
+    }
+
+    Ok((texts, x_dataset, y_dataset))
+}
+
+/// All valid records of an embeddings file in file order, invalid entries are skipped.
+pub fn load_embedding_records_from_file(path: &str) -> anyhow::Result<Vec<EmbeddingRecord>> {
+    load_embedding_records(path, RecordFilter::default())
+}
+
+/// Like `load_embedding_records_from_file`, keeping only the records selected by `filter`.
+pub fn load_embedding_records(path: &str, filter: RecordFilter) -> anyhow::Result<Vec<EmbeddingRecord>> {
+    let mut result: Vec<EmbeddingRecord> = Vec::new();
+    let mut invalid_count = 0;
+
+    for record in read_embedding_records(path, filter)? {
+        match record {
+            Ok(record) => result.push(record),
+            Err(err) => {
+                debug!(path, error = %err, "invalid entry");
+                invalid_count += 1;
+            },
+        }
+    }
+    if invalid_count > 0 {
+        warn!(path, invalid_count, "skipped invalid entries");
+    }
+
+    info!(path, entry_count = result.len(), "loaded embeddings");
+
+    Ok(result)
+}
+
+
+/// Source of text embeddings, e.g. a llama.cpp server.
+pub trait EmbeddingProvider: Send + Sync {
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<f32>>>;
+
+    /// Checks that the backend is reachable and returns embeddings.
+    fn health(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move { self.embed("health check").await.map(|_| ()) })
+    }
+
+    fn name(&self) -> String;
+}
+
+impl<P: EmbeddingProvider + ?Sized> EmbeddingProvider for Box<P> {
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<f32>>> {
+        (**self).embed(text)
+    }
+
+    fn health(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        (**self).health()
+    }
+
+    fn name(&self) -> String {
+        (**self).name()
+    }
+}
+
+pub struct LlamaCppEmbeddingProvider {
+    pub endpoint: String,
+    /// Texts are truncated to four characters per token of the context size.
+    pub context_size: usize,
+    client: reqwest::Client,
+}
+
+impl LlamaCppEmbeddingProvider {
+    pub fn new(endpoint: &str, context_size: usize) -> Self {
+        LlamaCppEmbeddingProvider { endpoint: endpoint.to_string(), context_size, client: HTTP_CLIENT.clone() }
+    }
+
+    /// Uses `DOCKER_EMBEDDING_ENDPOINT` and `EMBEDDING_CONTEXT_SIZE`, like `llama_cpp_embedding`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let endpoint = std::env::var("DOCKER_EMBEDDING_ENDPOINT")
+            .map_err(|_| anyhow::anyhow!("Error: DOCKER_EMBEDDING_ENDPOINT is not set"))?;
+        let context_size = std::env::var("EMBEDDING_CONTEXT_SIZE")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .ok_or_else(|| anyhow::anyhow!("Error: EMBEDDING_CONTEXT_SIZE is not set to a number"))?;
+        Ok(Self::new(&endpoint, context_size))
+    }
+}
+
+impl EmbeddingProvider for LlamaCppEmbeddingProvider {
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<f32>>> {
+        Box::pin(async move {
+            let input = text.chars().take(self.context_size * 4).collect::<String>();
+            Ok(text_embedding_request_to(&self.client, &self.endpoint, &input).await?)
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("llama.cpp ({})", self.endpoint)
+    }
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    embeddings: HashMap<String, Vec<f32>>,
+    /// Insertion order, the oldest entry is evicted first.
+    order: VecDeque<String>,
+}
+
+/// Keeps the embeddings of the most recently embedded texts in memory.
+pub struct CachedEmbeddingProvider<P> {
+    inner: P,
+    capacity: usize,
+    entries: Mutex<CacheEntries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<P: EmbeddingProvider> CachedEmbeddingProvider<P> {
+    pub fn new(inner: P, capacity: usize) -> Self {
+        CachedEmbeddingProvider { inner, capacity, entries: Mutex::new(CacheEntries::default()), hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    /// Number of cache hits and misses so far.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    fn record_lookup(&self, hit: bool) {
+        let (hits, misses) = self.stats();
+        crate::metrics::record_cache_lookup(hit, hits, misses);
+    }
+
+    fn get(&self, text: &str) -> Option<Vec<f32>> {
+        self.entries.lock().ok().and_then(|entries| entries.embeddings.get(text).cloned())
+    }
+
+    fn insert(&self, text: &str, embedding: &[f32]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.embeddings.insert(text.to_string(), embedding.to_vec()).is_none() {
+                entries.order.push_back(text.to_string());
+            }
+            while entries.order.len() > self.capacity {
+                if let Some(oldest) = entries.order.pop_front() {
+                    entries.embeddings.remove(&oldest);
                 }
             }
-        }else{
-            println!("Failed to parse line: '{}'",line);
         }
     }
+}
 
-    result.shuffle(&mut thread_rng());
+impl<P: EmbeddingProvider> EmbeddingProvider for CachedEmbeddingProvider<P> {
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<f32>>> {
+        Box::pin(async move {
+            if let Some(embedding) = self.get(text) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.record_lookup(true);
+                return Ok(embedding);
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.record_lookup(false);
+            let embedding = self.inner.embed(text).await?;
+            self.insert(text, &embedding);
+            Ok(embedding)
+        })
+    }
 
-    // Return the result as a tuple
-    Ok(result.into_iter().unzip())
+    fn health(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.inner.health()
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
 }
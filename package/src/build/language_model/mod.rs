@@ -1 +1,2 @@
 pub mod embeddings;
+pub mod offline;
@@ -0,0 +1,397 @@
+//! Pure Rust text embeddings for machines without a llama.cpp server: TF-IDF over hashed
+//! word and character n-grams, optionally reduced with a (randomized) truncated SVD.
+//! Far weaker than the language model, but good enough for CI and air-gapped setups.
+
+use std::collections::HashMap;
+use std::fs;
+
+use futures::future::BoxFuture;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::build::data::dedup::fnv1a_hash;
+use super::embeddings::EmbeddingProvider;
+
+pub const DEFAULT_BUCKETS: usize = 4096;
+const CHAR_NGRAM_SIZES: [usize; 3] = [3, 4, 5];
+const SVD_OVERSAMPLING: usize = 10;
+const SVD_POWER_ITERATIONS: usize = 2;
+const JACOBI_SWEEPS: usize = 30;
+
+type SparseVector = Vec<(usize, f32)>;
+
+/// Hashed n-gram TF-IDF embedder, fitted on a training corpus.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TfidfEmbedder {
+    pub buckets: usize,
+    pub document_count: usize,
+    idf: Vec<f32>,
+    /// Top right singular vectors of the corpus (one row per output dimension), if reduced.
+    #[serde(default)]
+    projection: Option<Vec<Vec<f32>>>,
+}
+
+impl TfidfEmbedder {
+    /// Fits the inverse document frequencies, and the SVD projection if `svd_dimension` is set.
+    pub fn fit(texts: &[String], buckets: usize, svd_dimension: Option<usize>, seed: u64) -> anyhow::Result<Self> {
+        if texts.is_empty() || buckets == 0 {
+            return Err(anyhow::anyhow!("Error: need a non-empty corpus and at least one bucket"));
+        }
+        let mut document_frequency = vec![0usize; buckets];
+        let counts: Vec<HashMap<usize, f32>> = texts.iter().map(|text| term_counts(text, buckets)).collect();
+        for count in &counts {
+            for &bucket in count.keys() {
+                document_frequency[bucket] += 1;
+            }
+        }
+        let document_count = texts.len();
+        let idf = document_frequency.iter()
+            .map(|&df| ((1.0 + document_count as f32) / (1.0 + df as f32)).ln() + 1.0)
+            .collect();
+
+        let mut embedder = TfidfEmbedder { buckets, document_count, idf, projection: None };
+        if let Some(dimension) = svd_dimension {
+            let rows: Vec<SparseVector> = counts.iter().map(|count| embedder.weigh(count)).collect();
+            embedder.projection = Some(truncated_svd(&rows, buckets, dimension, seed)?);
+        }
+        info!(document_count, buckets, dimension = embedder.dimension(), "fitted offline embedder");
+        Ok(embedder)
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.projection.as_ref().map_or(self.buckets, |projection| projection.len())
+    }
+
+    /// L2 normalized embedding of the text, all zeros if it has no words.
+    pub fn embed(&self, text: &str) -> Vec<f32> {
+        let tfidf = self.weigh(&term_counts(text, self.buckets));
+        match &self.projection {
+            None => {
+                let mut embedding = vec![0.0; self.buckets];
+                for (bucket, weight) in tfidf {
+                    embedding[bucket] = weight;
+                }
+                embedding
+            },
+            Some(projection) => {
+                let mut embedding: Vec<f32> = projection.iter()
+                    .map(|component| tfidf.iter().map(|&(bucket, weight)| component[bucket] * weight).sum())
+                    .collect();
+                l2_normalize(&mut embedding);
+                embedding
+            },
+        }
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path).map_err(|err| anyhow::anyhow!("Error: unable to read '{}': {}", path, err))?;
+        let embedder: TfidfEmbedder = serde_json::from_str(&contents).map_err(|err| anyhow::anyhow!("Error: unable to load '{}': {}", path, err))?;
+        let projection_fits = embedder.projection.as_ref().is_none_or(|projection| projection.iter().all(|component| component.len() == embedder.buckets));
+        if embedder.idf.len() != embedder.buckets || !projection_fits {
+            return Err(anyhow::anyhow!("Error: '{}' is not a valid offline embedder, its weights do not match its {} buckets", path, embedder.buckets));
+        }
+        Ok(embedder)
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Sublinear term frequency times inverse document frequency, L2 normalized.
+    fn weigh(&self, counts: &HashMap<usize, f32>) -> SparseVector {
+        let mut weights: SparseVector = counts.iter()
+            .map(|(&bucket, &count)| (bucket, (1.0 + count.ln()) * self.idf[bucket]))
+            .collect();
+        weights.sort_by_key(|&(bucket, _)| bucket);
+        let norm = weights.iter().map(|(_, weight)| weight * weight).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            weights.iter_mut().for_each(|(_, weight)| *weight /= norm);
+        }
+        weights
+    }
+}
+
+/// Counts of the hashed words and character n-grams (of the words, padded with a space) of a text.
+fn term_counts(text: &str, buckets: usize) -> HashMap<usize, f32> {
+    let mut counts = HashMap::new();
+    let lowercase = text.to_lowercase();
+    for word in lowercase.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+        *counts.entry(bucket(&format!("w:{}", word), buckets)).or_insert(0.0) += 1.0;
+
+        let padded: Vec<char> = format!(" {} ", word).chars().collect();
+        for &size in &CHAR_NGRAM_SIZES {
+            for gram in padded.windows(size) {
+                *counts.entry(bucket(&format!("c:{}", gram.iter().collect::<String>()), buckets)).or_insert(0.0) += 1.0;
+            }
+        }
+    }
+    counts
+}
+
+fn bucket(term: &str, buckets: usize) -> usize {
+    (fnv1a_hash(term.as_bytes()) % buckets as u64) as usize
+}
+
+fn l2_normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|value| *value /= norm);
+    }
+}
+
+/// Top `dimension` right singular vectors of the sparse `rows` (randomized range finder with power iterations).
+fn truncated_svd(rows: &[SparseVector], columns: usize, dimension: usize, seed: u64) -> anyhow::Result<Vec<Vec<f32>>> {
+    let rank = (dimension + SVD_OVERSAMPLING).min(rows.len()).min(columns);
+    if dimension == 0 || dimension > rank {
+        return Err(anyhow::anyhow!("Error: SVD dimension must be between 1 and {} for this corpus", rank.min(columns)));
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    // random columns x rank test matrix, stored column-wise
+    let omega: Vec<Vec<f64>> = (0..rank).map(|_| (0..columns).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect();
+    let mut y = multiply(rows, &omega);
+    for _ in 0..SVD_POWER_ITERATIONS {
+        orthonormalize(&mut y);
+        let mut z = multiply_transposed(rows, &y, columns);
+        orthonormalize(&mut z);
+        y = multiply(rows, &z);
+    }
+    orthonormalize(&mut y);
+
+    // b = q^T x, rank x columns
+    let b = multiply_transposed(rows, &y, columns);
+    // g = b b^T, its eigenvectors are the left singular vectors of b
+    let gram: Vec<Vec<f64>> = b.iter().map(|bi| b.iter().map(|bj| dot(bi, bj)).collect()).collect();
+    let (eigenvalues, eigenvectors) = jacobi_eigen(gram);
+
+    let mut order: Vec<usize> = (0..rank).collect();
+    order.sort_by(|&i, &j| eigenvalues[j].total_cmp(&eigenvalues[i]));
+
+    Ok(order.into_iter().take(dimension).map(|i| {
+        let singular_value = eigenvalues[i].max(0.0).sqrt();
+        (0..columns).map(|column| {
+            if singular_value <= f64::EPSILON {
+                return 0.0;
+            }
+            let value: f64 = (0..rank).map(|k| b[k][column] * eigenvectors[k][i]).sum();
+            (value / singular_value) as f32
+        }).collect()
+    }).collect())
+}
+
+/// rows x matrix, `matrix` and the result are stored column-wise.
+fn multiply(rows: &[SparseVector], matrix: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    matrix.iter().map(|column| {
+        rows.iter().map(|row| row.iter().map(|&(index, value)| value as f64 * column[index]).sum()).collect()
+    }).collect()
+}
+
+/// rows^T x matrix, `matrix` (one entry per row) and the result are stored column-wise.
+fn multiply_transposed(rows: &[SparseVector], matrix: &[Vec<f64>], columns: usize) -> Vec<Vec<f64>> {
+    matrix.iter().map(|column| {
+        let mut result = vec![0.0; columns];
+        for (row, &factor) in rows.iter().zip(column.iter()) {
+            for &(index, value) in row {
+                result[index] += value as f64 * factor;
+            }
+        }
+        result
+    }).collect()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(a, b)| a * b).sum()
+}
+
+/// Modified Gram-Schmidt on the columns, columns that become (numerically) zero are left at zero.
+fn orthonormalize(columns: &mut [Vec<f64>]) {
+    for i in 0..columns.len() {
+        for j in 0..i {
+            let projection = dot(&columns[i], &columns[j]);
+            let (previous, current) = columns.split_at_mut(i);
+            current[0].iter_mut().zip(previous[j].iter()).for_each(|(value, basis)| *value -= projection * basis);
+        }
+        let norm = dot(&columns[i], &columns[i]).sqrt();
+        columns[i].iter_mut().for_each(|value| *value = if norm > 1e-12 { *value / norm } else { 0.0 });
+    }
+}
+
+/// Eigenvalues and eigenvectors (as columns) of a symmetric matrix, cyclic Jacobi rotations.
+fn jacobi_eigen(mut a: Vec<Vec<f64>>) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = a.len();
+    let mut v: Vec<Vec<f64>> = (0..n).map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect()).collect();
+    for _ in 0..JACOBI_SWEEPS {
+        let off_diagonal: f64 = (0..n).flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j))).map(|(i, j)| a[i][j] * a[i][j]).sum();
+        if off_diagonal < 1e-18 {
+            break;
+        }
+        for p in 0..n {
+            for q in (p + 1)..n {
+                if a[p][q].abs() < 1e-15 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let t = if theta == 0.0 { 1.0 } else { t };
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for row in a.iter_mut() {
+                    let (akp, akq) = (row[p], row[q]);
+                    row[p] = c * akp - s * akq;
+                    row[q] = s * akp + c * akq;
+                }
+                let (row_p, row_q) = (a[p].clone(), a[q].clone());
+                for (k, (apk, aqk)) in row_p.into_iter().zip(row_q).enumerate() {
+                    a[p][k] = c * apk - s * aqk;
+                    a[q][k] = s * apk + c * aqk;
+                }
+                for row in v.iter_mut() {
+                    let (vkp, vkq) = (row[p], row[q]);
+                    row[p] = c * vkp - s * vkq;
+                    row[q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    ((0..n).map(|i| a[i][i]).collect(), v)
+}
+
+/// Embeds with a fitted `TfidfEmbedder`, no server needed.
+pub struct OfflineEmbeddingProvider {
+    embedder: TfidfEmbedder,
+    path: String,
+}
+
+impl OfflineEmbeddingProvider {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        Ok(OfflineEmbeddingProvider { embedder: TfidfEmbedder::load(path)?, path: path.to_string() })
+    }
+}
+
+impl EmbeddingProvider for OfflineEmbeddingProvider {
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<f32>>> {
+        Box::pin(async move { Ok(self.embedder.embed(text)) })
+    }
+
+    fn health(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn name(&self) -> String {
+        format!("offline tf-idf ({}, {} dimensions)", self.path, self.embedder.dimension())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corpus() -> Vec<String> {
+        [
+            "Claim your free airdrop tokens now",
+            "Free tokens for every wallet, claim before Friday",
+            "Your wallet was selected for the airdrop",
+            "Minutes of the community call are on the forum",
+            "The validator set grows to 150 seats next epoch",
+            "Can you review the budget proposal for next quarter?",
+            "Reminder: the chain upgrade is at block 4,200,000",
+            "Urgent: verify your seed phrase to keep your funds",
+            "Grant round results are out, congratulations to all teams",
+            "Meeting moved to 4pm, see you on the call",
+            "Send 1 ETH and get 2 ETH back, limited offer",
+            "Bridge contracts passed the security audit",
+        ].iter().map(|text| text.to_string()).collect()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn hashed_tfidf_is_normalized_and_sized_by_the_buckets() {
+        let embedder = TfidfEmbedder::fit(&corpus(), 256, None, 0).unwrap();
+        let embedding = embedder.embed("Claim your free tokens");
+        assert_eq!((embedder.dimension(), embedding.len()), (256, 256));
+        assert_close(embedding.iter().map(|value| (value * value) as f64).sum::<f64>(), 1.0);
+        // case and punctuation do not change the terms
+        assert_eq!(embedding, embedder.embed("CLAIM your free tokens!"));
+        assert!(embedder.embed("  ...  ").iter().all(|value| *value == 0.0));
+        // words seen in fewer documents weigh more
+        assert!(embedder.idf[bucket("w:forum", 256)] > embedder.idf[bucket("w:the", 256)]);
+        assert_eq!(term_counts("the the forum", 256)[&bucket("w:the", 256)], 2.0);
+        assert!(TfidfEmbedder::fit(&[], 256, None, 0).is_err());
+    }
+
+    #[test]
+    fn jacobi_eigen_decomposes_symmetric_matrices() {
+        let a = vec![vec![4.0, 1.0, 2.0], vec![1.0, 3.0, 0.5], vec![2.0, 0.5, 1.0]];
+        let (eigenvalues, eigenvectors) = jacobi_eigen(a.clone());
+        assert_close(eigenvalues.iter().sum(), 8.0);
+        let column = |i: usize| -> Vec<f64> { eigenvectors.iter().map(|row| row[i]).collect() };
+        for (i, eigenvalue) in eigenvalues.iter().enumerate() {
+            let vector = column(i);
+            // a v = lambda v
+            for row in 0..3 {
+                assert_close(dot(&a[row], &vector), eigenvalue * vector[row]);
+            }
+            for j in 0..3 {
+                assert_close(dot(&vector, &column(j)), if i == j { 1.0 } else { 0.0 });
+            }
+        }
+    }
+
+    #[test]
+    fn svd_projection_is_orthonormal_and_deterministic() {
+        let embedder = TfidfEmbedder::fit(&corpus(), 512, Some(6), 7).unwrap();
+        let projection = embedder.projection.as_ref().unwrap();
+        assert_eq!(projection.len(), 6);
+        for (i, a) in projection.iter().enumerate() {
+            assert_eq!(a.len(), 512);
+            for (j, b) in projection.iter().enumerate() {
+                let product: f64 = a.iter().zip(b.iter()).map(|(a, b)| *a as f64 * *b as f64).sum();
+                assert!((product - if i == j { 1.0 } else { 0.0 }).abs() < 1e-4, "{} {}: {}", i, j, product);
+            }
+        }
+
+        // the same seed gives the same embedder, the dimension does not depend on the text
+        let again = TfidfEmbedder::fit(&corpus(), 512, Some(6), 7).unwrap();
+        for text in ["Claim your free airdrop", "see you on the call", "an unseen text about something else", ""] {
+            assert_eq!(embedder.embed(text), again.embed(text));
+            assert_eq!(embedder.embed(text).len(), 6);
+        }
+        assert!(TfidfEmbedder::fit(&corpus(), 512, Some(0), 7).is_err());
+        assert!(TfidfEmbedder::fit(&corpus(), 512, Some(13), 7).is_err());
+    }
+
+    #[test]
+    fn saved_embedders_are_validated_on_load() {
+        let path = std::env::temp_dir().join(format!("offline_embedder_test_{}.json", std::process::id())).to_str().unwrap().to_string();
+        let mut embedder = TfidfEmbedder::fit(&corpus(), 128, Some(4), 1).unwrap();
+        embedder.save(&path).unwrap();
+        assert_eq!(TfidfEmbedder::load(&path).unwrap().embed("free tokens"), embedder.embed("free tokens"));
+
+        embedder.buckets = 256;
+        embedder.save(&path).unwrap();
+        let err = TfidfEmbedder::load(&path).unwrap_err().to_string();
+        assert!(err.contains("not a valid offline embedder"), "{}", err);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn a_model_trained_with_another_embedder_is_an_error() {
+        use crate::build::classification::bundle::ModelBundle;
+
+        // e.g. trained on llama.cpp embeddings, scored with the offline embedder
+        let x: Vec<Vec<f32>> = (0..6).map(|index| vec![index as f32; 8]).collect();
+        let y: Vec<f32> = (0..6).map(|index| (index % 2) as f32).collect();
+        let bundle = ModelBundle::fit(&x, &y, 3).unwrap();
+        let embedder = TfidfEmbedder::fit(&corpus(), 128, Some(4), 1).unwrap();
+        let err = bundle.predict(&[embedder.embed("free tokens")]).unwrap_err().to_string();
+        assert!(err.contains("dimension 4 does not match the model (8)"), "{}", err);
+    }
+}
@@ -1,21 +1,29 @@
 use serde_json;
-use std::fs::File;
-use std::io::Write;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Instant;
 use futures::Stream;
-use serde_json::{Value};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{info, instrument, warn};
 
 pub mod data;
 pub mod classification;
 pub mod language_model;
+pub mod features;
+pub mod normalization;
+pub mod active_learning;
+pub mod campaigns;
 
-use data::read_datasets;
-
-
-pub async fn save_training_data(dataset_paths: Vec<&str>) -> anyhow::Result<()> {
-
-    let dataset: Vec<(String,f32)> = read_datasets(&dataset_paths)?;
+use data::{read_datasets, DatasetManifest, DatasetRow};
+use data::dedup::fnv1a_hash;
+use language_model::embeddings::{EmbeddingProvider, LlamaCppEmbeddingProvider};
+use normalization::{normalize, NormalizationConfig};
 
 
+fn log_dataset_stats(dataset: &[(String, f32)]) {
     let spam_count = dataset.iter().filter(|x| x.1 >= 0.5).count();
     let ham_count = dataset.iter().filter(|x| x.1 < 0.5).count();
     let total_count = dataset.len();
@@ -23,21 +31,23 @@ pub async fn save_training_data(dataset_paths: Vec<&str>) -> anyhow::Result<()>
     let spam_percentage = (spam_count as f64 / total_count as f64) * 100.0;
     let ham_percentage = (ham_count as f64 / total_count as f64) * 100.0;
 
-    println!("Spam count: {}", spam_count);
-    println!("Ham count: {}", ham_count);
-    println!("===================");
-    println!("Total count: {}", total_count);
-    println!("===================");
-    println!("Spam percentage: {:.2}%", spam_percentage);
-    println!("Ham percentage: {:.2}%\n", ham_percentage);
+    info!(spam_count, ham_count, total_count, spam_percentage = format!("{:.2}", spam_percentage), ham_percentage = format!("{:.2}", ham_percentage), "dataset loaded");
+}
+
+
+pub async fn save_training_data(manifest: &DatasetManifest, output_path: &str) -> anyhow::Result<()> {
+
+    let dataset: Vec<(String,f32)> = manifest.read_texts()?;
 
 
+    log_dataset_stats(&dataset);
+
     let dataset_view: Vec<(&str,&f32)> = dataset.iter().map(|(text,label)| (text.as_str(),label)).collect();
 
     let json_data = serde_json::to_string_pretty(&dataset_view).expect("Failed to serialize to JSON");
 
-    let mut file = File::create("raw_dataset.json").expect("Failed to create file");
-    file.write_all(json_data.as_bytes()).expect("Failed to write to file");
+    let mut file = File::create(output_path)?;
+    file.write_all(json_data.as_bytes())?;
 
     Ok(())
 }
@@ -47,22 +57,177 @@ pub async fn create_embeddings(dataset_paths: Vec<&str>) -> anyhow::Result<(usiz
 
     let dataset: Vec<(String,f32)> = read_datasets(&dataset_paths)?;
 
-    let spam_count = dataset.iter().filter(|x| x.1 >= 0.5).count();
-    let ham_count = dataset.iter().filter(|x| x.1 < 0.5).count();
+    log_dataset_stats(&dataset);
     let total_count = dataset.len();
 
-    let spam_percentage = (spam_count as f64 / total_count as f64) * 100.0;
-    let ham_percentage = (ham_count as f64 / total_count as f64) * 100.0;
-
-    println!("Spam count: {}", spam_count);
-    println!("Ham count: {}", ham_count);
-    println!("===================");
-    println!("Total count: {}", total_count);
-    println!("===================");
-    println!("Spam percentage: {:.2}%", spam_percentage);
-    println!("Ham percentage: {:.2}%\n", ham_percentage);
-
     let embeddings_iter = language_model::embeddings::extract_embeddings(dataset);
 
     Ok((total_count,embeddings_iter))
 }
+
+
+#[derive(Debug, Default, Serialize)]
+pub struct EmbeddingJobReport {
+    pub total_count: usize,
+    pub skipped_count: usize,
+    pub embedded_count: usize,
+    pub failed_count: usize,
+}
+
+pub fn row_hash(text: &str) -> String {
+    format!("{:016x}", fnv1a_hash(text.as_bytes()))
+}
+
+/// Keys of the rows that are already stored in an embeddings file.
+///
+/// Lines written before rows were keyed by (dataset, row-hash) only carry the text,
+/// their hashes are returned separately and match a row from any dataset.
+struct EmbeddedRows {
+    keys: HashSet<(String, String)>,
+    legacy_hashes: HashSet<String>,
+}
+
+impl EmbeddedRows {
+    fn contains(&self, source: &str, hash: &str) -> bool {
+        self.legacy_hashes.contains(hash) || self.keys.contains(&(source.to_string(), hash.to_string()))
+    }
+}
+
+/// Scans an existing embeddings file for completed rows and truncates a partially written
+/// last line (left behind by a crash). A missing file is created empty.
+fn open_embeddings_checkpoint(path: &str) -> anyhow::Result<EmbeddedRows> {
+    let mut embedded = EmbeddedRows { keys: HashSet::new(), legacy_hashes: HashSet::new() };
+
+    if !Path::new(path).exists() {
+        // create via rename, so a crash never leaves a half-initialized file behind
+        let tmp_path = format!("{}.tmp", path);
+        File::create(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        return Ok(embedded);
+    }
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line: Vec<u8> = Vec::new();
+    let mut valid_length: u64 = 0;
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        if let Ok(value) = serde_json::from_slice::<Value>(&line) {
+            match (value["source"].as_str(), value["hash"].as_str(), value["text"].as_str()) {
+                (Some(source), Some(hash), _) => { embedded.keys.insert((source.to_string(), hash.to_string())); },
+                (_, _, Some(text)) => { embedded.legacy_hashes.insert(row_hash(text)); },
+                _ => {}
+            }
+        }
+        valid_length += read as u64;
+    }
+
+    let file = File::options().write(true).open(path)?;
+    if file.metadata()?.len() > valid_length {
+        warn!(path, valid_length, "truncating incomplete last line");
+        file.set_len(valid_length)?;
+    }
+    Ok(embedded)
+}
+
+/// Embeds every row of the datasets in the manifest and appends it to `output_path` (one JSON object per line).
+///
+/// Rows are keyed by (dataset file name, row-hash), rows already present in `output_path` are skipped,
+/// so an interrupted run can simply be restarted. Every row is written and flushed as soon as it
+/// is embedded. Rows that fail are not stored and are retried on the next run, the reason for each
+/// failure of the current run is written to `failures_path`.
+///
+/// Texts are normalized with `normalization` before they are embedded, the stored text stays unchanged.
+/// The normalization is recorded next to `output_path`, resuming with a different one is an error.
+pub async fn generate_embeddings(manifest: &DatasetManifest, output_path: &str, failures_path: &str, normalization: &NormalizationConfig) -> anyhow::Result<EmbeddingJobReport> {
+    let provider = LlamaCppEmbeddingProvider::from_env()?;
+    generate_embeddings_with(&provider, manifest, output_path, failures_path, normalization).await
+}
+
+/// Like `generate_embeddings`, but embeds with `provider` instead of the llama.cpp server from the environment.
+#[instrument(skip_all, fields(output = output_path, provider = %provider.name(), normalization = %normalization))]
+pub async fn generate_embeddings_with(provider: &dyn EmbeddingProvider, manifest: &DatasetManifest, output_path: &str, failures_path: &str, normalization: &NormalizationConfig) -> anyhow::Result<EmbeddingJobReport> {
+
+    let dataset = manifest.read_rows()?;
+    let has_rows = fs::metadata(output_path).map(|metadata| metadata.len() > 0).unwrap_or(false);
+    if has_rows {
+        let existing = normalization::load_config(output_path)?;
+        if existing != *normalization {
+            return Err(anyhow::anyhow!("Error: '{}' was embedded with normalization '{}', not '{}', resume with the same normalization or write to a new file", output_path, existing, normalization));
+        }
+    }
+    let embedded = open_embeddings_checkpoint(output_path)?;
+    normalization::save_config(output_path, normalization)?;
+
+    let mut report = EmbeddingJobReport { total_count: dataset.len(), ..Default::default() };
+
+    let pending: Vec<(String,String,DatasetRow)> = dataset.into_iter()
+        .map(|row| (row.source.clone(), row_hash(&row.text), row))
+        .filter(|(source,hash,_)| !embedded.contains(source,hash))
+        .collect();
+    report.skipped_count = report.total_count - pending.len();
+
+    info!(total_count = report.total_count, skipped_count = report.skipped_count, pending_count = pending.len(), "starting embedding job");
+
+    let mut output = File::options().append(true).open(output_path)?;
+    let mut failures = File::create(failures_path)?;
+
+    let pending_count = pending.len();
+    let start_time = Instant::now();
+    for (index, (source, hash, row)) in pending.into_iter().enumerate() {
+        let DatasetRow { text, label, categories, .. } = row;
+
+        match provider.embed(&normalize(&text, normalization)).await {
+            Ok(embedding) => {
+                let mut value = json!({
+                    "text": text,
+                    "label": label as f64,
+                    "embedding": embedding,
+                    "source": source,
+                    "hash": hash,
+                });
+                if !categories.is_empty() {
+                    value["categories"] = json!(categories);
+                }
+                let mut line = serde_json::to_string(&value)?;
+                line.push('\n');
+                // a single write per row, so only the row being written during a crash can be incomplete
+                output.write_all(line.as_bytes())?;
+                output.flush()?;
+                report.embedded_count += 1;
+            }
+            Err(err) => {
+                warn!(source = source.as_str(), hash = hash.as_str(), reason = %err, "failed to embed row");
+                let mut line = serde_json::to_string(&json!({
+                    "source": source,
+                    "hash": hash,
+                    "label": label as f64,
+                    "text": text,
+                    "reason": err.to_string(),
+                }))?;
+                line.push('\n');
+                failures.write_all(line.as_bytes())?;
+                failures.flush()?;
+                report.failed_count += 1;
+            }
+        }
+
+        let elapsed_time = start_time.elapsed().as_secs_f64();
+        let remaining_time = elapsed_time / (index as f64 + 1.0) * (pending_count - index - 1) as f64;
+        info!(
+            done = index + 1,
+            pending_count,
+            percent_complete = format!("{:.2}", ((index + 1) as f64 / pending_count as f64) * 100.0),
+            failed_count = report.failed_count,
+            eta_seconds = format!("{:.2}", remaining_time),
+            "embedding progress"
+        );
+    }
+
+    output.sync_all()?;
+    info!(embedded_count = report.embedded_count, failed_count = report.failed_count, "embedding job finished");
+    Ok(report)
+}
@@ -0,0 +1,241 @@
+//! Undoes common obfuscations (homoglyphs, invisible characters, fullwidth letters, spaced-out words,
+//! markup) before a text is embedded. The same configuration has to be used for training and inference,
+//! it is stored next to the embeddings file and in the model bundle.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+lazy_static::lazy_static! {
+    static ref HTML_COMMENT: Regex = Regex::new(r"(?s)<!--.*?-->").unwrap();
+    static ref HTML_BLOCK: Regex = Regex::new(r"(?is)<(script|style)\b.*?</(script|style)\s*>").unwrap();
+    static ref HTML_TAG: Regex = Regex::new(r"</?[a-zA-Z][^<>]*>").unwrap();
+    static ref MARKDOWN_LINK: Regex = Regex::new(r"!?\[([^\]]*)\]\(([^)\s]*)[^)]*\)").unwrap();
+    static ref MARKDOWN_LINE_PREFIX: Regex = Regex::new(r"(?m)^[ \t]*(?:#{1,6}[ \t]+|>[ \t]?|[-*+][ \t]+)").unwrap();
+    static ref MARKDOWN_EMPHASIS: Regex = Regex::new(r"\*{1,3}|_{2,3}|~~|`+").unwrap();
+}
+
+const STEP_NAMES: [&str; 6] = ["markup", "nfkc", "invisible", "confusables", "spaced_letters", "whitespace"];
+
+/// Steps applied by `normalize`, in the order of the fields.
+///
+/// The default applies nothing, which is how models trained before normalization existed saw their texts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NormalizationConfig {
+    /// Strip HTML tags and Markdown syntax, keep the text and link targets.
+    pub markup: bool,
+    /// Unicode NFKC, folds fullwidth letters, ligatures and other compatibility characters.
+    pub nfkc: bool,
+    /// Remove zero-width characters, soft hyphens, bidi controls and variation selectors.
+    pub invisible: bool,
+    /// Map Cyrillic and Greek lookalikes to Latin letters in words that mix scripts.
+    pub confusables: bool,
+    /// Join runs of three or more single letters ("c l a i m" -> "claim").
+    pub spaced_letters: bool,
+    /// Collapse runs of whitespace into a single space.
+    pub whitespace: bool,
+}
+
+impl NormalizationConfig {
+    pub fn all() -> Self {
+        NormalizationConfig { markup: true, nfkc: true, invisible: true, confusables: true, spaced_letters: true, whitespace: true }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    fn steps(&self) -> [bool; 6] {
+        [self.markup, self.nfkc, self.invisible, self.confusables, self.spaced_letters, self.whitespace]
+    }
+}
+
+/// `all`, `none` or a comma separated list of steps, e.g. `nfkc,invisible,whitespace`.
+impl FromStr for NormalizationConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value.trim() {
+            "all" => return Ok(Self::all()),
+            "none" | "" => return Ok(Self::default()),
+            _ => {}
+        }
+        let mut config = Self::default();
+        for step in value.split(',').map(|step| step.trim()) {
+            match step {
+                "markup" => config.markup = true,
+                "nfkc" => config.nfkc = true,
+                "invisible" => config.invisible = true,
+                "confusables" => config.confusables = true,
+                "spaced_letters" => config.spaced_letters = true,
+                "whitespace" => config.whitespace = true,
+                _ => return Err(anyhow::anyhow!("Error: unknown normalization step '{}', expected one of {}", step, STEP_NAMES.join(", "))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for NormalizationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps: Vec<&str> = STEP_NAMES.iter().zip(self.steps()).filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect();
+        if steps.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", steps.join(","))
+        }
+    }
+}
+
+pub fn normalize(text: &str, config: &NormalizationConfig) -> String {
+    let mut text = text.to_string();
+    if config.markup {
+        text = strip_markup(&text);
+    }
+    if config.nfkc {
+        text = text.nfkc().collect();
+    }
+    if config.invisible {
+        text = text.chars().filter(|&c| !is_invisible(c)).collect();
+    }
+    if config.confusables {
+        text = fold_confusables(&text);
+    }
+    if config.spaced_letters {
+        text = join_spaced_letters(&text);
+    }
+    if config.whitespace {
+        text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    }
+    text
+}
+
+pub fn strip_markup(text: &str) -> String {
+    let text = HTML_COMMENT.replace_all(text, " ");
+    let text = HTML_BLOCK.replace_all(&text, " ");
+    let text = HTML_TAG.replace_all(&text, " ");
+    let text = MARKDOWN_LINK.replace_all(&text, "$1 $2");
+    let text = MARKDOWN_LINE_PREFIX.replace_all(&text, "");
+    let text = MARKDOWN_EMPHASIS.replace_all(&text, "");
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn is_invisible(c: char) -> bool {
+    matches!(c as u32,
+        0x00AD              // soft hyphen
+        | 0x034F            // combining grapheme joiner
+        | 0x061C            // arabic letter mark
+        | 0x115F | 0x1160   // hangul fillers
+        | 0x17B4 | 0x17B5
+        | 0x180B..=0x180E   // mongolian variation selectors and vowel separator
+        | 0x200B..=0x200F   // zero-width space, joiners, direction marks
+        | 0x202A..=0x202E   // bidi embeddings and overrides
+        | 0x2060..=0x206F   // word joiner, invisible operators, bidi isolates
+        | 0x3164            // hangul filler
+        | 0xFE00..=0xFE0F   // variation selectors
+        | 0xFEFF            // byte order mark
+        | 0xFFA0
+        | 0xE0000..=0xE007F // tags
+    )
+}
+
+/// Latin letter that a Cyrillic or Greek character is commonly used to imitate.
+fn confusable(c: char) -> Option<char> {
+    Some(match c {
+        'а' => 'a', 'в' => 'b', 'е' => 'e', 'ё' => 'e', 'һ' => 'h', 'і' => 'i', 'ї' => 'i', 'ј' => 'j', 'к' => 'k',
+        'м' => 'm', 'н' => 'h', 'о' => 'o', 'р' => 'p', 'с' => 'c', 'т' => 't', 'у' => 'y', 'х' => 'x', 'ѕ' => 's',
+        'ԁ' => 'd', 'ԛ' => 'q', 'ԝ' => 'w', 'ո' => 'n', 'ս' => 'u', 'ɡ' => 'g', 'ɑ' => 'a', 'ı' => 'i',
+        'А' => 'A', 'В' => 'B', 'Е' => 'E', 'К' => 'K', 'М' => 'M', 'Н' => 'H', 'О' => 'O', 'Р' => 'P',
+        'С' => 'C', 'Т' => 'T', 'У' => 'Y', 'Х' => 'X', 'Ѕ' => 'S', 'І' => 'I', 'Ј' => 'J', 'Ԁ' => 'D',
+        'α' => 'a', 'ο' => 'o', 'ν' => 'v', 'ρ' => 'p', 'τ' => 't', 'υ' => 'u', 'κ' => 'k', 'ι' => 'i',
+        'Α' => 'A', 'Β' => 'B', 'Ε' => 'E', 'Ζ' => 'Z', 'Η' => 'H', 'Ι' => 'I', 'Κ' => 'K', 'Μ' => 'M',
+        'Ν' => 'N', 'Ο' => 'O', 'Ρ' => 'P', 'Τ' => 'T', 'Υ' => 'Y', 'Χ' => 'X',
+        _ => return None,
+    })
+}
+
+/// Folds lookalikes in words that contain Latin letters (e.g. "сlаim" with Cyrillic с and а),
+/// words written entirely in another script are left alone.
+pub fn fold_confusables(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut word = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            word.push(c);
+        } else {
+            push_folded(&word, &mut result);
+            word.clear();
+            result.push(c);
+        }
+    }
+    push_folded(&word, &mut result);
+    result
+}
+
+fn push_folded(word: &str, result: &mut String) {
+    if word.chars().any(|c| c.is_ascii_alphabetic()) {
+        result.extend(word.chars().map(|c| confusable(c).unwrap_or(c)));
+    } else {
+        result.push_str(word);
+    }
+}
+
+pub fn join_spaced_letters(text: &str) -> String {
+    text.split('\n').map(|line| {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let mut joined: Vec<String> = Vec::with_capacity(tokens.len());
+        let mut run: Vec<&str> = Vec::new();
+        for token in tokens {
+            let mut chars = token.chars();
+            let single_letter = matches!((chars.next(), chars.next()), (Some(c), None) if c.is_alphanumeric());
+            if single_letter {
+                run.push(token);
+                continue;
+            }
+            flush_run(&mut run, &mut joined);
+            joined.push(token.to_string());
+        }
+        flush_run(&mut run, &mut joined);
+        joined.join(" ")
+    }).collect::<Vec<String>>().join("\n")
+}
+
+fn flush_run(run: &mut Vec<&str>, joined: &mut Vec<String>) {
+    if run.len() >= 3 {
+        joined.push(run.concat());
+    } else {
+        joined.extend(run.iter().map(|token| token.to_string()));
+    }
+    run.clear();
+}
+
+/// Where the normalization of an embeddings file is recorded.
+pub fn config_path(embeddings_path: &str) -> String {
+    format!("{}.normalization.json", embeddings_path)
+}
+
+/// The normalization an embeddings file was created with, none for files written before it was recorded.
+pub fn load_config(embeddings_path: &str) -> anyhow::Result<NormalizationConfig> {
+    let path = config_path(embeddings_path);
+    if !Path::new(&path).exists() {
+        return Ok(NormalizationConfig::default());
+    }
+    let contents = fs::read_to_string(&path).map_err(|err| anyhow::anyhow!("Error: unable to read '{}': {}", path, err))?;
+    serde_json::from_str(&contents).map_err(|err| anyhow::anyhow!("Error: unable to parse '{}': {}", path, err))
+}
+
+pub fn save_config(embeddings_path: &str, config: &NormalizationConfig) -> anyhow::Result<()> {
+    fs::write(config_path(embeddings_path), serde_json::to_string_pretty(config)?)?;
+    Ok(())
+}
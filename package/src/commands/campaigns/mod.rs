@@ -0,0 +1,175 @@
+//! Commands grouping flagged texts into spam campaigns.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use tracing::{info, warn};
+
+use crate::batch;
+use crate::build::campaigns::{cluster_campaigns, flagged_row, format_timestamp, CampaignConfig, CampaignIndex, FlaggedText, DEFAULT_CAMPAIGN_DISTANCE, DEFAULT_MIN_CAMPAIGN_SIZE};
+use crate::build::normalization::{self, NormalizationConfig};
+use crate::build::language_model::embeddings::EmbeddingProvider;
+use super::{EmbeddingEndpointArgs, open_input};
+
+#[derive(Args)]
+struct FlaggedInputArgs {
+    /// JSON lines file, e.g. the output of `predict --jsonl`
+    #[arg(long)]
+    input: String,
+    /// Name of the JSON field holding the text
+    #[arg(long, default_value = "text")]
+    column: String,
+    /// Name of the JSON field holding when the text was seen (unix seconds or RFC 3339), the current time if missing
+    #[arg(long)]
+    time_field: Option<String>,
+    /// Rows with a `score` field are flagged at or above this, rows without one are all flagged
+    #[arg(long, default_value_t = 0.5)]
+    threshold: f32,
+    #[command(flatten)]
+    endpoint: EmbeddingEndpointArgs,
+}
+
+#[derive(Args)]
+pub struct ClusterCampaignsArgs {
+    #[command(flatten)]
+    input: FlaggedInputArgs,
+    /// Maximum average cosine distance between the texts of a campaign
+    #[arg(long, default_value_t = DEFAULT_CAMPAIGN_DISTANCE)]
+    distance: f32,
+    /// Smaller clusters are not reported as campaigns
+    #[arg(long, default_value_t = DEFAULT_MIN_CAMPAIGN_SIZE)]
+    min_size: usize,
+    /// Text normalization before embedding, see `embed`
+    #[arg(long, default_value = "all")]
+    normalization: NormalizationConfig,
+    /// Where to write the campaigns
+    #[arg(long, default_value = "campaigns.json")]
+    output: String,
+}
+
+#[derive(Args)]
+pub struct AssignCampaignsArgs {
+    #[command(flatten)]
+    input: FlaggedInputArgs,
+    /// Campaigns written by `cluster-campaigns`
+    #[arg(long, default_value = "campaigns.json")]
+    campaigns: String,
+    /// Write the rows with campaign and campaign_distance fields to this file instead of stdout
+    #[arg(long)]
+    output: Option<String>,
+    /// Count the assigned texts towards the size and first/last seen of their campaigns
+    #[arg(long)]
+    update: bool,
+}
+
+/// A row of the input of the campaign commands, and its flagged text or why it could not be read or embedded.
+type FlaggedInputRow = (serde_json::Value, Result<Option<FlaggedText>, String>);
+
+/// The rows of the input, with the embedded text of the flagged ones, or why a row could not be read or embedded
+/// (an empty row stands in for a line that is not valid JSON).
+async fn flagged_texts(args: &FlaggedInputArgs, normalization: &NormalizationConfig) -> anyhow::Result<Vec<FlaggedInputRow>> {
+    let provider = args.endpoint.provider()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut rows = Vec::new();
+    for (number, line) in BufReader::new(open_input(&args.input)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(row) => row,
+            Err(err) => {
+                warn!(line = number + 1, error = %err, "invalid JSON");
+                rows.push((serde_json::Value::Object(serde_json::Map::new()), Err(format!("invalid JSON: {}", err))));
+                continue;
+            },
+        };
+        let text = match flagged_row(&row, &args.column, args.time_field.as_deref(), args.threshold) {
+            Ok(Some(flagged)) => match provider.embed(&normalization::normalize(&flagged.text, normalization)).await {
+                Ok(embedding) => Ok(Some(FlaggedText { text: flagged.text, embedding, seen_at: flagged.seen_at.unwrap_or(now) })),
+                Err(err) => {
+                    warn!(line = number + 1, error = %err, "failed to embed flagged text");
+                    Err(format!("embedding failed: {}", err))
+                },
+            },
+            Ok(None) => Ok(None),
+            Err(err) => {
+                warn!(line = number + 1, error = %err, "invalid row");
+                Err(err.to_string())
+            },
+        };
+        rows.push((row, text));
+    }
+    Ok(rows)
+}
+
+pub async fn cluster_campaigns_command(args: &ClusterCampaignsArgs) -> anyhow::Result<()> {
+    let rows = flagged_texts(&args.input, &args.normalization).await?;
+    let failed_count = rows.iter().filter(|(_, text)| text.is_err()).count();
+    let texts: Vec<FlaggedText> = rows.into_iter().filter_map(|(_, text)| text.ok().flatten()).collect();
+    let config = CampaignConfig { distance: args.distance, min_size: args.min_size };
+    let index = cluster_campaigns(&texts, &config, &args.normalization)?;
+    index.save(&args.output)?;
+
+    println!("Found {} campaigns among {} flagged texts ({} not in a campaign): {}", index.campaigns.len(), index.flagged_count, index.unclustered_count, args.output);
+    if failed_count > 0 {
+        println!("Skipped {} rows that could not be read or embedded", failed_count);
+    }
+    for campaign in &index.campaigns {
+        println!("Campaign {}: {} texts ({} distinct), first seen {}, last seen {}", campaign.id, campaign.size, campaign.distinct_count, format_timestamp(campaign.first_seen), format_timestamp(campaign.last_seen));
+        for text in &campaign.representatives {
+            println!("    {}", text.replace('\n', " "));
+        }
+    }
+    Ok(())
+}
+
+pub async fn assign_campaigns_command(args: &AssignCampaignsArgs) -> anyhow::Result<()> {
+    let mut index = CampaignIndex::load(&args.campaigns)?;
+    let rows = flagged_texts(&args.input, &index.normalization).await?;
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+
+    let (mut flagged_count, mut assigned_count, mut failed_count) = (0, 0, 0);
+    for (mut row, text) in rows {
+        let (text, error) = match text {
+            Ok(text) => (text, None),
+            Err(err) => {
+                failed_count += 1;
+                (None, Some(err))
+            },
+        };
+        let assigned = match &text {
+            Some(text) => {
+                flagged_count += 1;
+                index.assign(&text.embedding)?
+            },
+            None => None,
+        };
+        if let (Some(assigned), Some(text)) = (&assigned, &text) {
+            assigned_count += 1;
+            if args.update {
+                index.record(assigned.id, text.seen_at);
+            }
+        }
+        if let serde_json::Value::Object(object) = &mut row {
+            object.insert("campaign".to_string(), assigned.as_ref().map(|assigned| assigned.id.into()).unwrap_or(serde_json::Value::Null));
+            object.insert("campaign_distance".to_string(), assigned.as_ref().map(|assigned| assigned.distance.into()).unwrap_or(serde_json::Value::Null));
+            if let Some(error) = error {
+                object.insert(batch::ERROR_COLUMN.to_string(), error.into());
+            }
+        }
+        writeln!(output, "{}", row)?;
+    }
+    output.flush()?;
+
+    if args.update {
+        index.save(&args.campaigns)?;
+    }
+    info!(flagged_count, assigned_count, failed_count, updated = args.update, "assigned campaigns");
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! Commands building the training data: embedding the datasets, fitting the offline embedder, deduplicating and converting.
+
+use clap::Args;
+use tracing::{info, warn};
+
+use crate::build::data::DatasetManifest;
+use crate::build::normalization::{self, NormalizationConfig};
+use crate::build::data::dedup::{deduplicate, DEFAULT_NEAR_DUPLICATE_THRESHOLD};
+use crate::build::data::embedding_dataset::{read_embedding_records, write_columnar_embeddings};
+use crate::build::language_model::offline::{TfidfEmbedder, DEFAULT_BUCKETS};
+use super::{DEFAULT_MANIFEST, DEFAULT_EMBEDDINGS, RecordFilterArgs, EmbeddingEndpointArgs, write_json_report};
+
+#[derive(Args)]
+pub struct EmbedArgs {
+    /// Dataset manifest (JSON)
+    #[arg(long, default_value = DEFAULT_MANIFEST)]
+    manifest: String,
+    /// Embeddings file to append to
+    #[arg(long, default_value = DEFAULT_EMBEDDINGS)]
+    output: String,
+    /// File receiving the rows that failed to embed
+    #[arg(long, default_value = "embeddings_failures.json")]
+    failures: String,
+    /// Text normalization before embedding: `all`, `none` or a comma separated list of
+    /// markup, nfkc, invisible, confusables, spaced_letters, whitespace
+    #[arg(long, default_value = "all")]
+    normalization: NormalizationConfig,
+    #[command(flatten)]
+    endpoint: EmbeddingEndpointArgs,
+}
+
+#[derive(Args)]
+pub struct FitEmbedderArgs {
+    /// Dataset manifest (JSON), the embedder is fitted on all of its texts
+    #[arg(long, default_value = DEFAULT_MANIFEST)]
+    manifest: String,
+    /// Where to write the embedder
+    #[arg(long, default_value = "offline_embedder.json")]
+    output: String,
+    /// Number of hash buckets of the n-gram features
+    #[arg(long, default_value_t = DEFAULT_BUCKETS)]
+    buckets: usize,
+    /// Reduce the TF-IDF vectors to this many dimensions with a truncated SVD
+    #[arg(long)]
+    svd_dimension: Option<usize>,
+    /// Normalization of the texts, should match the one `embed` uses
+    #[arg(long, default_value = "all")]
+    normalization: NormalizationConfig,
+    /// Seed of the randomized SVD
+    #[arg(long, default_value_t = 42)]
+    seed: u64,
+}
+
+#[derive(Args)]
+pub struct DedupArgs {
+    /// Dataset manifest (JSON)
+    #[arg(long, default_value = DEFAULT_MANIFEST)]
+    manifest: String,
+    /// Minimum Jaccard similarity of the character shingles of near-duplicates
+    #[arg(long, default_value_t = DEFAULT_NEAR_DUPLICATE_THRESHOLD)]
+    similarity: f32,
+    /// Where to write the report
+    #[arg(long, default_value = "dedup_report.json")]
+    output: String,
+    /// Write the first entry of every duplicate group to this CSV dataset (columns `text, label`)
+    #[arg(long)]
+    deduplicated: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ConvertArgs {
+    /// JSON lines embeddings file
+    #[arg(long, default_value = DEFAULT_EMBEDDINGS)]
+    input: String,
+    #[command(flatten)]
+    filter: RecordFilterArgs,
+    /// Columnar embeddings file to write
+    #[arg(long, default_value = "embeddings_dataset.bin")]
+    output: String,
+}
+
+#[derive(Args)]
+pub struct SaveTrainingDataArgs {
+    /// Dataset manifest (JSON)
+    #[arg(long, default_value = DEFAULT_MANIFEST)]
+    manifest: String,
+    /// Where to write the dataset
+    #[arg(long, default_value = "raw_dataset.json")]
+    output: String,
+}
+
+pub fn dedup_training_data(args: &DedupArgs) -> anyhow::Result<()> {
+    let dataset = DatasetManifest::load(&args.manifest)?.read_texts()?;
+    let (deduplicated, report) = deduplicate(dataset, args.similarity);
+    if let Some(path) = &args.deduplicated {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(["text", "label"])?;
+        for (text, label) in &deduplicated {
+            writer.write_record([text.as_str(), &label.to_string()])?;
+        }
+        writer.flush()?;
+        info!(count = deduplicated.len(), output = path.as_str(), "wrote deduplicated dataset");
+    }
+
+    println!("Total entries: {}", report.total_count);
+    println!("Unique entries: {}", report.unique_count);
+    println!("Exact duplicates: {}", report.exact_duplicate_count);
+    println!("Near duplicates: {}", report.near_duplicate_count);
+    println!("Duplicate groups: {} (largest: {}, with conflicting labels: {})", report.duplicate_group_count, report.largest_group, report.conflicting_label_groups);
+
+    write_json_report(&Some(args.output.clone()), &report)
+}
+
+pub async fn embed(args: &EmbedArgs) -> anyhow::Result<()> {
+    let manifest = DatasetManifest::load(&args.manifest)?;
+    let provider = args.endpoint.provider()?;
+    let report = crate::build::generate_embeddings_with(provider.as_ref(), &manifest, &args.output, &args.failures, &args.normalization).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+pub fn fit_embedder(args: &FitEmbedderArgs) -> anyhow::Result<()> {
+    let texts: Vec<String> = DatasetManifest::load(&args.manifest)?.read_texts()?.into_iter()
+        .map(|(text, _label)| normalization::normalize(&text, &args.normalization))
+        .collect();
+    let embedder = TfidfEmbedder::fit(&texts, args.buckets, args.svd_dimension, args.seed)?;
+    embedder.save(&args.output)?;
+    println!("Fitted an offline embedder with {} dimensions on {} texts: {}", embedder.dimension(), embedder.document_count, args.output);
+    Ok(())
+}
+
+pub fn convert(args: &ConvertArgs) -> anyhow::Result<()> {
+    let mut skipped_count = 0;
+    let records = read_embedding_records(&args.input, args.filter.filter()?)?.filter_map(|record| match record {
+        Ok(record) => Some(record),
+        Err(err) => {
+            warn!(path = args.input.as_str(), error = %err, "skipping invalid entry");
+            skipped_count += 1;
+            None
+        },
+    });
+    let count = write_columnar_embeddings(&args.output, records)?;
+    normalization::save_config(&args.output, &normalization::load_config(&args.input)?)?;
+    info!(count, output = args.output.as_str(), "wrote columnar embeddings");
+    println!("Converted {} entries to {}, skipped {} invalid entries", count, args.output, skipped_count);
+    Ok(())
+}
+
+pub async fn save_training_data(args: &SaveTrainingDataArgs) -> anyhow::Result<()> {
+    let manifest = DatasetManifest::load(&args.manifest)?;
+    crate::build::save_training_data(&manifest, &args.output).await
+}
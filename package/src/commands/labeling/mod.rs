@@ -0,0 +1,235 @@
+//! Commands growing the labeled data: self-training, picking texts to label and importing the labels.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use clap::Args;
+use serde::Serialize;
+use tracing::{info, info_span, warn};
+
+use crate::batch;
+use crate::build::classification::{calculate_metrics, compute_metrics, ThresholdMetrics, THRESHOLDS};
+use crate::build::active_learning::{batch_text_hashes, import_labeled_batch, select_for_labeling, write_labeling_batch, SelectionConfig};
+use crate::build::classification::bundle::ModelBundle;
+use crate::build::classification::feedback;
+use crate::build::classification::self_training::{self_train, SelfTrainingConfig, SelfTrainingReport};
+use crate::build::row_hash;
+use crate::build::normalization::{self, NormalizationConfig};
+use crate::build::data::embedding_dataset::EmbeddingRecord;
+use crate::build::language_model::embeddings::{load_embedding_records_from_file, EmbeddingProvider};
+use super::{DEFAULT_MANIFEST, TrainArgs, EmbeddingEndpointArgs, load_embeddings, split_dataset, to_dataset, write_json_report, open_input};
+
+#[derive(Args)]
+struct UnlabeledArgs {
+    /// Unlabeled texts, one per line
+    #[arg(long)]
+    unlabeled: Option<String>,
+    /// Directory of unlabeled texts, one per file
+    #[arg(long, conflicts_with = "unlabeled")]
+    unlabeled_dir: Option<String>,
+    /// Embeddings of the unlabeled texts: read if the file exists, written after embedding them otherwise
+    #[arg(long)]
+    unlabeled_embeddings: Option<String>,
+    #[command(flatten)]
+    endpoint: EmbeddingEndpointArgs,
+}
+
+#[derive(Args)]
+pub struct SelfTrainArgs {
+    #[command(flatten)]
+    train: TrainArgs,
+    #[command(flatten)]
+    unlabeled: UnlabeledArgs,
+    /// Unlabeled texts scoring at or above this are pseudo-labeled as spam
+    #[arg(long, default_value_t = 0.9)]
+    spam_threshold: f32,
+    /// Unlabeled texts scoring at or below this are pseudo-labeled as ham
+    #[arg(long, default_value_t = 0.1)]
+    ham_threshold: f32,
+    /// Maximum number of pseudo-labeling rounds
+    #[arg(long, default_value_t = 5)]
+    max_rounds: usize,
+    /// Maximum number of texts pseudo-labeled per round and class (the most confident first)
+    #[arg(long)]
+    max_per_round: Option<usize>,
+    /// Write the pseudo-labeled entries to this file (JSON lines, like the embeddings file)
+    #[arg(long)]
+    pseudo_labels: Option<String>,
+    /// Share of the labeled entries used for training, the rest is held out to compare the model with one trained on the labeled entries only
+    #[arg(long, default_value_t = 0.8)]
+    train_ratio: f64,
+    /// Seed of the train/held-out split
+    #[arg(long, default_value_t = 42)]
+    seed: u64,
+    /// Operating threshold to report
+    #[arg(long, default_value_t = 0.5)]
+    threshold: f32,
+    /// Where to write the report
+    #[arg(long, default_value = "self_training_report.json")]
+    output: String,
+}
+
+#[derive(Args)]
+pub struct SelectForLabelingArgs {
+    /// Trained KNN model
+    #[arg(long, default_value = crate::DEFAULT_MODEL_PATH)]
+    model: String,
+    #[command(flatten)]
+    unlabeled: UnlabeledArgs,
+    /// Texts scoring close to this threshold are the most uncertain
+    #[arg(long, default_value_t = 0.5)]
+    threshold: f32,
+    /// Number of texts to label
+    #[arg(long, default_value_t = 50)]
+    batch_size: usize,
+    /// The batch is spread out over the `batch_size * pool_factor` most uncertain texts
+    #[arg(long, default_value_t = 10)]
+    pool_factor: usize,
+    /// Earlier labeling batches, their texts are not selected again
+    #[arg(long, value_delimiter = ',')]
+    exclude: Vec<String>,
+    /// Where to write the labeling batch
+    #[arg(long, default_value = "labeling_batch.csv")]
+    output: String,
+}
+
+#[derive(Args)]
+pub struct ImportLabelsArgs {
+    /// Labeling batch with a filled in `label` column (0 to 1, spam or ham)
+    batch: String,
+    /// Dataset manifest to add the dataset to
+    #[arg(long, default_value = DEFAULT_MANIFEST)]
+    manifest: String,
+    /// CSV dataset the labeled texts are appended to
+    #[arg(long, default_value = "./dataset/labeled_batches.csv")]
+    dataset: String,
+}
+
+#[derive(Serialize)]
+struct SelfTrainReport {
+    #[serde(flatten)]
+    training: SelfTrainingReport,
+    held_out_count: usize,
+    /// Metrics of the self-trained model on the held-out labeled entries.
+    self_trained: Vec<ThresholdMetrics>,
+    /// Metrics of a model trained on the labeled entries only, on the same held-out entries.
+    labeled_only: Vec<ThresholdMetrics>,
+}
+
+pub async fn self_train_command(args: &SelfTrainArgs) -> anyhow::Result<()> {
+    let (labeled, held_out) = split_dataset(load_embeddings(&args.train.embeddings, &args.train.filter)?, args.train_ratio, args.seed);
+    if held_out.is_empty() {
+        return Err(anyhow::anyhow!("Error: no labeled entries are held out for evaluation, lower --train-ratio (got {})", args.train_ratio));
+    }
+    let normalization = normalization::load_config(&args.train.embeddings)?;
+    let unlabeled = unlabeled_records(&args.unlabeled, &normalization).await?;
+
+    let config = SelfTrainingConfig {
+        k: args.train.k,
+        spam_threshold: args.spam_threshold,
+        ham_threshold: args.ham_threshold,
+        max_rounds: args.max_rounds,
+        max_per_round: args.max_per_round,
+    };
+    let (mut bundle, pseudo_labeled, report) = self_train(&labeled, unlabeled, &config, &normalization)?;
+    bundle.fit_categories(args.train.category_head.into())?;
+    bundle.save(&args.train.model)?;
+    if let Some(path) = &args.pseudo_labels {
+        write_embedding_records(path, &pseudo_labeled)?;
+        normalization::save_config(path, &normalization)?;
+    }
+
+    println!("Pseudo-labeled {} spam and {} ham of {} unlabeled texts", report.pseudo_spam_count, report.pseudo_ham_count, report.unlabeled_count);
+    for round in &report.rounds {
+        println!("Round {}: +{} spam, +{} ham, {} training entries, {} unlabeled left", round.round, round.spam_added, round.ham_added, round.training_count, round.unlabeled_remaining);
+    }
+
+    let (x_test, y_test) = to_dataset(&held_out);
+    let baseline = ModelBundle::fit_records(&labeled, args.train.k, &normalization)?;
+    let mut metrics = Vec::with_capacity(2);
+    for (name, model) in [("self-trained", &bundle), ("labeled-only", &baseline)] {
+        let y_hat = model.predict(&x_test)?;
+        if let Some(m) = compute_metrics(&y_test, &y_hat, &[args.threshold]).pop() {
+            println!("{} on {} held-out entries, threshold >= {}: Precision = {:.3}, Recall = {:.3}, F-Score = {:.3}", name, held_out.len(), m.threshold, m.precision, m.recall, m.f_score);
+        }
+        metrics.push(info_span!("evaluate", model = name).in_scope(|| calculate_metrics(&y_test, &y_hat, &THRESHOLDS)));
+    }
+    let (labeled_only, self_trained) = (metrics.pop().unwrap_or_default(), metrics.pop().unwrap_or_default());
+    write_json_report(&Some(args.output.clone()), &SelfTrainReport { training: report, held_out_count: held_out.len(), self_trained, labeled_only })
+}
+
+pub async fn select_for_labeling_command(args: &SelectForLabelingArgs) -> anyhow::Result<()> {
+    let bundle = feedback::load_bundle(&args.model)?;
+    let mut excluded = batch_text_hashes(&args.exclude)?;
+    excluded.extend(bundle.all_examples().map(|example| row_hash(&example.text)));
+
+    let records: Vec<EmbeddingRecord> = unlabeled_records(&args.unlabeled, &bundle.metadata.normalization).await?.into_iter()
+        .filter(|record| !excluded.contains(&row_hash(&record.text)))
+        .collect();
+    let scores = bundle.predict(&to_dataset(&records).0)?;
+    let config = SelectionConfig { threshold: args.threshold, batch_size: args.batch_size, pool_factor: args.pool_factor };
+    let candidates = select_for_labeling(&records, &scores, &config)?;
+    write_labeling_batch(&args.output, &candidates)?;
+
+    let uncertainty = candidates.iter().map(|candidate| candidate.uncertainty).fold(0.0f32, f32::max);
+    println!("Selected {} of {} unlabeled texts (scores within {:.3} of {}): {}", candidates.len(), records.len(), uncertainty, args.threshold, args.output);
+    Ok(())
+}
+
+/// The cached unlabeled embeddings, or the embedded unlabeled texts (texts that fail to embed are skipped).
+async fn unlabeled_records(args: &UnlabeledArgs, normalization: &NormalizationConfig) -> anyhow::Result<Vec<EmbeddingRecord>> {
+    if let Some(path) = args.unlabeled_embeddings.as_deref().filter(|path| Path::new(path).exists()) {
+        let cached = normalization::load_config(path)?;
+        if cached != *normalization {
+            return Err(anyhow::anyhow!("Error: '{}' was embedded with normalization '{}', the labeled embeddings with '{}'", path, cached, normalization));
+        }
+        return load_embedding_records_from_file(path);
+    }
+
+    let inputs = match (&args.unlabeled, &args.unlabeled_dir) {
+        (_, Some(dir)) => batch::read_directory(dir)?,
+        (Some(path), None) => batch::read_texts(open_input(path)?, false)?,
+        (None, None) => return Err(anyhow::anyhow!("Error: pass --unlabeled, --unlabeled-dir or an existing --unlabeled-embeddings file")),
+    };
+    let provider = args.endpoint.provider()?;
+    let mut records = Vec::with_capacity(inputs.len());
+    for (id, text) in inputs {
+        let text = match text {
+            Ok(text) if !text.trim().is_empty() => text,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!(id = id.as_str(), error = err.as_str(), "skipping unreadable input");
+                continue;
+            },
+        };
+        match provider.embed(&normalization::normalize(&text, normalization)).await {
+            Ok(embedding) => records.push(EmbeddingRecord { text, label: 0.0, source: "unlabeled".to_string(), embedding, categories: Vec::new() }),
+            Err(err) => warn!(id = id.as_str(), error = %err, "failed to embed unlabeled text"),
+        }
+    }
+    info!(count = records.len(), "embedded unlabeled texts");
+
+    if let Some(path) = &args.unlabeled_embeddings {
+        write_embedding_records(path, &records)?;
+        normalization::save_config(path, normalization)?;
+    }
+    Ok(records)
+}
+
+/// Writes the records as JSON lines, readable like an embeddings file.
+fn write_embedding_records(path: &str, records: &[EmbeddingRecord]) -> anyhow::Result<()> {
+    let mut output = BufWriter::new(File::create(path)?);
+    for record in records {
+        serde_json::to_writer(&mut output, record)?;
+        output.write_all(b"\n")?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+pub fn import_labels(args: &ImportLabelsArgs) -> anyhow::Result<()> {
+    let report = import_labeled_batch(&args.batch, &args.manifest, &args.dataset)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
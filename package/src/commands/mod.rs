@@ -0,0 +1,172 @@
+//! The commands of the `llm_fraud_detection` binary, and the arguments they share.
+
+use std::fs::File;
+use std::io::Write;
+
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use tracing::info;
+
+use crate::build::classification::KNN_DEFAULT_K;
+use crate::build::classification::categories::CategoryHead;
+use crate::build::features::with_signal_features;
+use crate::build::data::dedup::split_records;
+use crate::build::data::embedding_dataset::{EmbeddingRecord, RecordFilter};
+use crate::build::language_model::embeddings::{load_embedding_records, EmbeddingProvider, LlamaCppEmbeddingProvider};
+use crate::build::language_model::offline::OfflineEmbeddingProvider;
+
+pub mod campaigns;
+pub mod data;
+pub mod labeling;
+pub mod scoring;
+pub mod training;
+
+pub const DEFAULT_MANIFEST: &str = "./dataset/manifest.json";
+pub const DEFAULT_EMBEDDINGS: &str = "embeddings_dataset.json";
+
+#[derive(Args)]
+struct TrainArgs {
+    /// Embeddings file (JSON lines or columnar)
+    #[arg(long, default_value = DEFAULT_EMBEDDINGS)]
+    embeddings: String,
+    #[command(flatten)]
+    filter: RecordFilterArgs,
+    /// Where to write the model
+    #[arg(long, default_value = crate::DEFAULT_MODEL_PATH)]
+    model: String,
+    /// Number of neighbors
+    #[arg(long, default_value_t = KNN_DEFAULT_K)]
+    k: usize,
+    /// Head predicting the fraud categories, if the embeddings have categories
+    #[arg(long, value_enum, default_value_t = CategoryHeadKind::Knn)]
+    category_head: CategoryHeadKind,
+}
+
+#[derive(Args)]
+struct RecordFilterArgs {
+    /// Only use the entries of these dataset files (comma separated, their path relative to the manifest or their file name)
+    #[arg(long, value_delimiter = ',')]
+    sources: Vec<String>,
+    /// Only use the spam or the ham entries
+    #[arg(long, value_enum)]
+    only: Option<LabelKind>,
+    /// Use a random share of the entries (between 0 and 1)
+    #[arg(long)]
+    sample_rate: Option<f64>,
+    /// Seed of the sample
+    #[arg(long, default_value_t = 42)]
+    sample_seed: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LabelKind {
+    Spam,
+    Ham,
+}
+
+impl RecordFilterArgs {
+    fn filter(&self) -> anyhow::Result<RecordFilter> {
+        if let Some(rate) = self.sample_rate.filter(|rate| !(*rate > 0.0 && *rate <= 1.0)) {
+            return Err(anyhow::anyhow!("Error: --sample-rate must be above 0 and at most 1, got {}", rate));
+        }
+        Ok(RecordFilter {
+            sources: if self.sources.is_empty() { None } else { Some(self.sources.clone()) },
+            spam: self.only.map(|only| matches!(only, LabelKind::Spam)),
+            sample_rate: self.sample_rate,
+            seed: self.sample_seed,
+        })
+    }
+}
+
+#[derive(Args)]
+struct EmbeddingEndpointArgs {
+    /// llama.cpp embedding endpoint
+    #[arg(long, env = "DOCKER_EMBEDDING_ENDPOINT")]
+    embedding_endpoint: Option<String>,
+    /// Context size of the embedding model (texts are truncated to four characters per token)
+    #[arg(long, env = "EMBEDDING_CONTEXT_SIZE", default_value_t = 1024)]
+    context_size: usize,
+    /// Embed with this offline embedder (see `fit-embedder`) instead of the llama.cpp server
+    #[arg(long)]
+    offline_embedder: Option<String>,
+}
+
+impl EmbeddingEndpointArgs {
+    /// The offline embedder if one is given, else the llama.cpp server if one is configured.
+    fn configured_provider(&self) -> anyhow::Result<Option<Box<dyn EmbeddingProvider>>> {
+        Ok(match (&self.offline_embedder, &self.embedding_endpoint) {
+            (Some(path), _) => Some(Box::new(OfflineEmbeddingProvider::load(path)?)),
+            (None, Some(endpoint)) => Some(Box::new(LlamaCppEmbeddingProvider::new(endpoint, self.context_size))),
+            (None, None) => None,
+        })
+    }
+
+    fn provider(&self) -> anyhow::Result<Box<dyn EmbeddingProvider>> {
+        self.configured_provider()?
+            .ok_or_else(|| anyhow::anyhow!("Error: set --embedding-endpoint (or DOCKER_EMBEDDING_ENDPOINT) or --offline-embedder"))
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CategoryHeadKind {
+    Knn,
+    Linear,
+}
+
+impl From<CategoryHeadKind> for CategoryHead {
+    fn from(kind: CategoryHeadKind) -> Self {
+        match kind {
+            CategoryHeadKind::Knn => CategoryHead::Knn,
+            CategoryHeadKind::Linear => CategoryHead::Linear,
+        }
+    }
+}
+
+type Dataset = (Vec<Vec<f32>>, Vec<f32>);
+
+fn load_embeddings(path: &str, filter: &RecordFilterArgs) -> anyhow::Result<Vec<EmbeddingRecord>> {
+    let records = load_embedding_records(path, filter.filter()?)?;
+    if records.is_empty() {
+        return Err(anyhow::anyhow!("Error: no embeddings found in '{}'", path));
+    }
+
+    let spam_count = records.iter().filter(|record| record.label >= 0.5).count();
+    let ham_count = records.len() - spam_count;
+
+    info!(spam_count, ham_count, total_count = records.len(), "loaded embeddings");
+
+    Ok(records)
+}
+
+/// Duplicate-aware split, reproducible for a given seed.
+fn split_dataset(records: Vec<EmbeddingRecord>, train_ratio: f64, seed: u64) -> (Vec<EmbeddingRecord>, Vec<EmbeddingRecord>) {
+    let (train_records, test_records) = split_records(records, train_ratio, seed);
+    info!(train_count = train_records.len(), test_count = test_records.len(), "split dataset");
+    (train_records, test_records)
+}
+
+fn to_dataset(records: &[EmbeddingRecord]) -> Dataset {
+    (records.iter().map(|record| record.embedding.clone()).collect(), records.iter().map(|record| record.label).collect())
+}
+
+/// The embeddings, followed by the signal features of each text if `signal_features` is set.
+fn to_features(records: &[EmbeddingRecord], signal_features: bool) -> Dataset {
+    let (x_dataset, y_dataset) = to_dataset(records);
+    if !signal_features {
+        return (x_dataset, y_dataset);
+    }
+    let x_dataset = records.iter().zip(x_dataset.iter()).map(|(record, embedding)| with_signal_features(embedding, &record.text)).collect();
+    (x_dataset, y_dataset)
+}
+
+fn write_json_report<T: Serialize>(path: &Option<String>, report: &T) -> anyhow::Result<()> {
+    if let Some(path) = path {
+        File::create(path)?.write_all(serde_json::to_string_pretty(report)?.as_bytes())?;
+        info!(path = path.as_str(), "wrote report");
+    }
+    Ok(())
+}
+
+fn open_input(path: &str) -> anyhow::Result<File> {
+    File::open(path).map_err(|err| anyhow::anyhow!("Error: unable to open '{}': {}", path, err))
+}
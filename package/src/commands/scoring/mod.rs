@@ -0,0 +1,206 @@
+//! Commands scoring texts with a trained model, locally or over HTTP, and feeding labels back into it.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Args;
+use tracing::info;
+
+use crate::batch::{self, BatchOptions};
+use crate::build::classification::conformal::validate_error_level;
+use crate::build::classification::feedback;
+use crate::build::language_model::embeddings::CachedEmbeddingProvider;
+use crate::detector::FraudDetector;
+use crate::service::{self, ServiceConfig, ServiceState};
+use super::{EmbeddingEndpointArgs, open_input};
+
+pub const SENTENCES: [&str;6] = [
+    "Lose up to 19% weight. Special promotion on our new weightloss.",
+    "Hi Bob, can you send me your machine learning homework?",
+    "Don't forget our special promotion: -30% on men shoes, only today!",
+    "Hi Bob, don't forget our meeting today at 4pm.",
+    "⚠️ FINAL: LAST TERRA PHOENIX AIRDROP 🌎 ✅ CLAIM NOW All participants in this vote will receive a reward..",
+    "Social KYC oracle (TYC)  PFC is asking for 20k Luna to build a social KYC protocol.."
+    ];
+
+#[derive(Args)]
+pub struct PredictArgs {
+    /// Texts to score (scores a few example sentences if no input is given)
+    texts: Vec<String>,
+    /// Read texts from stdin, one per line
+    #[arg(long, conflicts_with_all = ["dir", "csv", "jsonl"])]
+    stdin: bool,
+    /// With --stdin: score all of stdin as a single text
+    #[arg(long, requires = "stdin")]
+    whole: bool,
+    /// Score every file in this directory
+    #[arg(long, conflicts_with_all = ["csv", "jsonl"])]
+    dir: Option<String>,
+    /// Score a column of this CSV file (the output is the CSV with score, verdict, novelty, out_of_distribution,
+    /// prediction_set, categories, signals and error columns)
+    #[arg(long, requires = "column", conflicts_with = "jsonl")]
+    csv: Option<String>,
+    /// Score a field of this JSON lines file (the output adds the same fields as the CSV columns)
+    #[arg(long, requires = "column")]
+    jsonl: Option<String>,
+    /// Name of the CSV column or JSON field holding the text
+    #[arg(long)]
+    column: Option<String>,
+    /// Write the results to this file instead of stdout
+    #[arg(long)]
+    output: Option<String>,
+    /// Trained model
+    #[arg(long, default_value = crate::DEFAULT_MODEL_PATH)]
+    model: String,
+    /// Scores at or above the threshold are reported as fraud
+    #[arg(long, default_value_t = 0.5)]
+    threshold: f32,
+    /// Texts with a novelty at or above this are flagged as out of distribution
+    #[arg(long, default_value_t = crate::build::classification::novelty::DEFAULT_NOVELTY_THRESHOLD)]
+    novelty_threshold: f32,
+    /// Error level of the prediction sets, defaults to the one the model was calibrated for
+    #[arg(long)]
+    error_level: Option<f32>,
+    /// Number of texts scored and written at a time
+    #[arg(long, default_value_t = 64)]
+    batch_size: usize,
+    #[command(flatten)]
+    endpoint: EmbeddingEndpointArgs,
+}
+
+#[derive(Args)]
+pub struct FeedbackArgs {
+    /// The labeled text
+    text: String,
+    /// 1 for fraud, 0 for ham (or anything in between)
+    #[arg(long)]
+    label: f32,
+    /// Trained KNN model, the text is appended to its feedback log
+    #[arg(long, default_value = crate::DEFAULT_MODEL_PATH)]
+    model: String,
+    #[command(flatten)]
+    endpoint: EmbeddingEndpointArgs,
+}
+
+#[derive(Args)]
+pub struct CompactFeedbackArgs {
+    /// Trained KNN model
+    #[arg(long, default_value = crate::DEFAULT_MODEL_PATH)]
+    model: String,
+}
+
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    bind: std::net::SocketAddr,
+    /// Trained model
+    #[arg(long, default_value = crate::DEFAULT_MODEL_PATH)]
+    model: String,
+    /// Scores at or above the threshold are reported as fraud
+    #[arg(long, default_value_t = 0.5)]
+    threshold: f32,
+    /// Texts with a novelty at or above this are flagged as out of distribution
+    #[arg(long, default_value_t = crate::build::classification::novelty::DEFAULT_NOVELTY_THRESHOLD)]
+    novelty_threshold: f32,
+    /// Error level of the prediction sets, defaults to the one the model was calibrated for
+    #[arg(long)]
+    error_level: Option<f32>,
+    #[command(flatten)]
+    endpoint: EmbeddingEndpointArgs,
+    /// Number of embeddings kept in memory for repeated texts
+    #[arg(long, default_value_t = 1024)]
+    embedding_cache_size: usize,
+    /// Maximum request body size in bytes
+    #[arg(long, default_value_t = 1024 * 1024)]
+    max_body_bytes: usize,
+    /// Maximum number of texts per request
+    #[arg(long, default_value_t = 64)]
+    max_batch_size: usize,
+    /// Enables POST /admin/reload and /admin/feedback, authorized with this bearer token
+    #[arg(long, env = "ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    /// Reload the model when its file changes, checking every N seconds
+    #[arg(long)]
+    watch_interval: Option<u64>,
+}
+
+pub async fn predict(args: &PredictArgs) -> anyhow::Result<()> {
+    validate_detector_options(args.novelty_threshold, args.error_level)?;
+    let options = BatchOptions {
+        model_path: args.model.clone(),
+        threshold: args.threshold,
+        novelty_threshold: args.novelty_threshold,
+        error_level: args.error_level,
+        batch_size: args.batch_size,
+        provider: args.endpoint.configured_provider()?.map(Arc::from),
+    };
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+
+    let column = args.column.as_deref().unwrap_or_default();
+    let summary = if let Some(path) = &args.csv {
+        batch::predict_csv(open_input(path)?, &mut output, column, &options).await?
+    } else if let Some(path) = &args.jsonl {
+        batch::predict_jsonl(BufReader::new(open_input(path)?), &mut output, column, &options).await?
+    } else {
+        let inputs = if let Some(dir) = &args.dir {
+            batch::read_directory(dir)?
+        } else if args.stdin {
+            batch::read_texts(std::io::stdin().lock(), args.whole)?
+        } else if args.texts.is_empty() {
+            SENTENCES.iter().map(|text| (text.to_string(), Ok(text.to_string()))).collect()
+        } else {
+            args.texts.iter().map(|text| (text.clone(), Ok(text.clone()))).collect()
+        };
+        batch::predict_texts(inputs, &mut output, &options).await?
+    };
+    output.flush()?;
+
+    info!(scored_count = summary.scored_count, fraud_count = summary.fraud_count, failed_count = summary.failed_count, "prediction finished");
+    Ok(())
+}
+
+fn validate_detector_options(novelty_threshold: f32, error_level: Option<f32>) -> anyhow::Result<()> {
+    if !(0.0..=1.0).contains(&novelty_threshold) {
+        return Err(anyhow::anyhow!("Error: --novelty-threshold must be between 0 and 1, got {}", novelty_threshold));
+    }
+    if let Some(error_level) = error_level {
+        validate_error_level(error_level)?;
+    }
+    Ok(())
+}
+
+pub async fn serve(args: &ServeArgs) -> anyhow::Result<()> {
+    let provider = CachedEmbeddingProvider::new(args.endpoint.provider()?, args.embedding_cache_size);
+    validate_detector_options(args.novelty_threshold, args.error_level)?;
+    let mut detector = FraudDetector::load(Arc::new(provider), &args.model, args.threshold)?;
+    detector.novelty_threshold = args.novelty_threshold;
+    detector.error_level = args.error_level;
+    let config = ServiceConfig {
+        bind: args.bind,
+        max_body_bytes: args.max_body_bytes,
+        max_batch_size: args.max_batch_size,
+        admin_token: args.admin_token.clone(),
+        watch_interval: args.watch_interval.map(Duration::from_secs),
+    };
+
+    service::serve(Arc::new(ServiceState { detector, config })).await
+}
+
+pub async fn feedback_command(args: &FeedbackArgs) -> anyhow::Result<()> {
+    let detector = FraudDetector::load(Arc::from(args.endpoint.provider()?), &args.model, 0.5)?;
+    let (entry, model) = detector.add_feedback(detector.feedback_entry(&args.text, args.label).await?)?;
+    println!("Added {} with label {} ({} training examples, {} from feedback)", entry.id(), entry.label, model.metadata().training_count, model.metadata().feedback_count);
+    Ok(())
+}
+
+pub fn compact_feedback(args: &CompactFeedbackArgs) -> anyhow::Result<()> {
+    let report = feedback::compact(&args.model)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -0,0 +1,352 @@
+//! Commands training and evaluating models on an embeddings file.
+
+use std::sync::Arc;
+
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use smartcore::linalg::naive::dense_matrix::DenseMatrix;
+use tracing::{info, info_span, warn};
+
+use crate::build::classification::{compute_metrics, embedding_feature_labels, permutation_importance, ClassificationMockModel, ModelType, fit_knn_regression_model, predict_knn_regression_model, test_knn_regression_model, test_random_forest_regression_model, update_random_forest_regression_model_from_records, model_inputs, ThresholdMetrics};
+use crate::build::classification::bundle::ModelBundle;
+use crate::build::classification::categories::{category_metrics, CategoryMetrics, CategoryProbabilities};
+use crate::build::classification::conformal::{validate_error_level, CoverageReport, DEFAULT_ERROR_LEVEL};
+use crate::build::features::signal_feature_labels;
+use crate::build::normalization::{self, NormalizationConfig};
+use crate::build::data::embedding_dataset::EmbeddingRecord;
+use crate::build::language_model::embeddings::CachedEmbeddingProvider;
+use crate::detector::FraudDetector;
+use crate::robustness::{evaluate_robustness, Perturbation};
+use super::{DEFAULT_EMBEDDINGS, TrainArgs, RecordFilterArgs, EmbeddingEndpointArgs, load_embeddings, split_dataset, to_dataset, to_features, write_json_report};
+
+#[derive(Args)]
+pub struct TrainCommandArgs {
+    #[command(flatten)]
+    train: TrainArgs,
+    #[arg(long, value_enum, default_value_t = ModelKind::Knn)]
+    model_type: ModelKind,
+    /// Append the hand-crafted scam signals to the embeddings (random forest only)
+    #[arg(long)]
+    signal_features: bool,
+    #[command(flatten)]
+    calibration: CalibrationArgs,
+}
+
+#[derive(Args)]
+struct CalibrationArgs {
+    /// Share of the training entries held out to calibrate conformal prediction sets (KNN only, 0 disables them)
+    #[arg(long, default_value_t = 0.0)]
+    calibration_ratio: f64,
+    /// Error level the prediction sets are calibrated for, the service can use another one
+    #[arg(long, default_value_t = DEFAULT_ERROR_LEVEL)]
+    error_level: f32,
+}
+
+#[derive(Args)]
+pub struct EvalArgs {
+    #[command(flatten)]
+    train: TrainArgs,
+    /// Share of the entries used for training
+    #[arg(long, default_value_t = 0.8)]
+    train_ratio: f64,
+    /// Seed of the train/test split
+    #[arg(long, default_value_t = 42)]
+    seed: u64,
+    /// Operating threshold to report
+    #[arg(long, default_value_t = 0.5)]
+    threshold: f32,
+    #[command(flatten)]
+    calibration: CalibrationArgs,
+    /// Write the metrics as JSON to this file
+    #[arg(long)]
+    output: Option<String>,
+}
+
+#[derive(Args)]
+pub struct TuneArgs {
+    /// Embeddings file (JSON lines or columnar)
+    #[arg(long, default_value = DEFAULT_EMBEDDINGS)]
+    embeddings: String,
+    #[command(flatten)]
+    filter: RecordFilterArgs,
+    /// Candidate numbers of neighbors
+    #[arg(long, value_delimiter = ',', default_value = "1,3,5,7,9,15")]
+    k_values: Vec<usize>,
+    /// Share of the entries used for training
+    #[arg(long, default_value_t = 0.8)]
+    train_ratio: f64,
+    /// Seed of the train/test split
+    #[arg(long, default_value_t = 42)]
+    seed: u64,
+    /// Write the results as JSON to this file
+    #[arg(long)]
+    output: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ImportanceArgs {
+    /// Embeddings file (JSON lines or columnar)
+    #[arg(long, default_value = DEFAULT_EMBEDDINGS)]
+    embeddings: String,
+    #[command(flatten)]
+    filter: RecordFilterArgs,
+    /// Model to evaluate
+    #[arg(long, default_value = crate::DEFAULT_MODEL_PATH)]
+    model: String,
+    #[arg(long, value_enum, default_value_t = ModelKind::Knn)]
+    model_type: ModelKind,
+    /// Number of randomly chosen entries to compute the importance on
+    #[arg(long, default_value_t = 500)]
+    sample_size: usize,
+    /// Seed of the sample
+    #[arg(long, default_value_t = 42)]
+    seed: u64,
+    /// The model was trained with the hand-crafted scam signals appended to the embeddings
+    /// (only needed for models saved before this was recorded in the model file)
+    #[arg(long)]
+    signal_features: bool,
+    /// Where to write the features sorted by importance
+    #[arg(long, default_value = "feature_importance.json")]
+    output: String,
+}
+
+#[derive(Args)]
+pub struct RobustnessArgs {
+    /// Embeddings file the model was evaluated on (JSON lines or columnar)
+    #[arg(long, default_value = DEFAULT_EMBEDDINGS)]
+    embeddings: String,
+    /// Must match the training run
+    #[command(flatten)]
+    filter: RecordFilterArgs,
+    /// Model trained on the same split, e.g. by `eval`
+    #[arg(long, default_value = crate::DEFAULT_MODEL_PATH)]
+    model: String,
+    /// Share of the entries used for training, must match the training run
+    #[arg(long, default_value_t = 0.8)]
+    train_ratio: f64,
+    /// Seed of the train/test split and the perturbations
+    #[arg(long, default_value_t = 42)]
+    seed: u64,
+    /// Operating threshold
+    #[arg(long, default_value_t = 0.5)]
+    threshold: f32,
+    /// Maximum number of held-out spam samples to perturb
+    #[arg(long, default_value_t = 200)]
+    max_samples: usize,
+    /// Perturbations to apply
+    #[arg(long, value_delimiter = ',', default_value = "homoglyph,zero_width,synonym_swap,benign_padding,url_split")]
+    perturbations: Vec<Perturbation>,
+    #[command(flatten)]
+    endpoint: EmbeddingEndpointArgs,
+    /// Where to write the report
+    #[arg(long, default_value = "robustness_report.json")]
+    output: String,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ModelKind {
+    Knn,
+    RandomForest,
+}
+
+impl From<ModelKind> for ModelType {
+    fn from(kind: ModelKind) -> Self {
+        match kind {
+            ModelKind::Knn => ModelType::KNN,
+            ModelKind::RandomForest => ModelType::RandomForest,
+        }
+    }
+}
+
+pub fn train(args: &TrainCommandArgs) -> anyhow::Result<()> {
+    let records = load_embeddings(&args.train.embeddings, &args.train.filter)?;
+
+    match args.model_type {
+        ModelKind::Knn => {
+            if args.signal_features {
+                // the distances would be dominated by the unscaled signal counts
+                return Err(anyhow::anyhow!("Error: signal features are only supported for random forest models"));
+            }
+            let (x_dataset, y_dataset) = to_dataset(&records);
+            let normalization = normalization::load_config(&args.train.embeddings)?;
+            fit_knn_bundle(&records, &args.train, &normalization, &args.calibration, CALIBRATION_SEED)?.save(&args.train.model)?;
+            test_knn_regression_model(&args.train.model, &x_dataset, &y_dataset)?;
+        },
+        ModelKind::RandomForest => {
+            if args.calibration.calibration_ratio > 0.0 {
+                return Err(anyhow::anyhow!("Error: conformal calibration is only supported for KNN models"));
+            }
+            if records.iter().any(|record| !record.categories.is_empty()) {
+                warn!("category heads are only fitted for KNN models, the categories are ignored");
+            }
+            let normalization = normalization::load_config(&args.train.embeddings)?;
+            let (x_dataset, y_dataset) = to_features(&records, args.signal_features);
+            update_random_forest_regression_model_from_records(&args.train.model, &records, args.signal_features, &normalization)?;
+            test_random_forest_regression_model(&args.train.model, &x_dataset, &y_dataset)?;
+        },
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct EvalReport {
+    train: Vec<ThresholdMetrics>,
+    test: Vec<ThresholdMetrics>,
+    operating_point: Option<ThresholdMetrics>,
+    /// Coverage of the conformal prediction sets on the test data.
+    conformal: Option<Vec<CoverageReport>>,
+    categories: Option<Vec<CategoryMetrics>>,
+}
+
+/// Seed of the calibration split of `train`, `eval` uses its `--seed`.
+const CALIBRATION_SEED: u64 = 42;
+
+/// Fits a KNN bundle and its category heads, holding out `--calibration-ratio` of the records
+/// (duplicate-aware, like `eval`) to calibrate its conformal prediction sets.
+fn fit_knn_bundle(records: &[EmbeddingRecord], train: &TrainArgs, normalization: &NormalizationConfig, calibration: &CalibrationArgs, seed: u64) -> anyhow::Result<ModelBundle> {
+    let fit = |records: &[EmbeddingRecord]| -> anyhow::Result<ModelBundle> {
+        let mut bundle = ModelBundle::fit_records(records, train.k, normalization)?;
+        bundle.fit_categories(train.category_head.into())?;
+        if let Some(categories) = &bundle.categories {
+            info!(categories = ?categories.categories, counts = ?categories.counts, multi_label = categories.multi_label, "fitted category heads");
+        }
+        Ok(bundle)
+    };
+    if calibration.calibration_ratio <= 0.0 {
+        return fit(records);
+    }
+    if calibration.calibration_ratio >= 1.0 {
+        return Err(anyhow::anyhow!("Error: --calibration-ratio must be below 1, got {}", calibration.calibration_ratio));
+    }
+    validate_error_level(calibration.error_level)?;
+
+    let (fit_records, calibration_records) = split_dataset(records.to_vec(), 1.0 - calibration.calibration_ratio, seed);
+    if fit_records.is_empty() || calibration_records.is_empty() {
+        return Err(anyhow::anyhow!("Error: not enough entries to hold out a calibration set"));
+    }
+    let mut bundle = fit(&fit_records)?;
+    bundle.calibrate(&calibration_records, calibration.error_level)?;
+    if let Some(conformal) = &bundle.conformal {
+        info!(calibration_count = calibration_records.len(), error_level = conformal.error_level, quantile = conformal.quantile(conformal.error_level), "calibrated conformal prediction sets");
+    }
+    Ok(bundle)
+}
+
+pub fn eval(args: &EvalArgs) -> anyhow::Result<()> {
+    let (train_records, test_records) = split_dataset(load_embeddings(&args.train.embeddings, &args.train.filter)?, args.train_ratio, args.seed);
+    let ((x_train, y_train), (x_test, y_test)) = (to_dataset(&train_records), to_dataset(&test_records));
+
+    let bundle = fit_knn_bundle(&train_records, &args.train, &normalization::load_config(&args.train.embeddings)?, &args.calibration, args.seed)?;
+    bundle.save(&args.train.model)?;
+    let train = info_span!("evaluate", data = "train").in_scope(|| test_knn_regression_model(&args.train.model, &x_train, &y_train))?;
+    let test = info_span!("evaluate", data = "test").in_scope(|| test_knn_regression_model(&args.train.model, &x_test, &y_test))?;
+
+    let y_hat = predict_knn_regression_model(&args.train.model, &x_test)?;
+    let operating_point = compute_metrics(&y_test, &y_hat, &[args.threshold]).pop();
+    if let Some(m) = &operating_point {
+        println!("Operating threshold >= {}: Precision = {:.3}, Recall = {:.3}, F-Score = {:.3}", m.threshold, m.precision, m.recall, m.f_score);
+    }
+
+    let conformal = bundle.conformal.as_ref().map(|conformal| conformal.coverage_reports(&y_hat, &y_test));
+    for report in conformal.iter().flatten() {
+        println!("Error level {}: coverage = {:.3} (at least {:.3} expected), {} single-label, {} uncertain and {} empty prediction sets",
+            report.error_level, report.coverage, 1.0 - report.error_level, report.single_count, report.both_count, report.empty_count);
+    }
+
+    let categories = match &bundle.categories {
+        Some(model) => {
+            let predictions = x_test.iter()
+                .map(|x| bundle.category_probabilities(x).map(Option::unwrap_or_default))
+                .collect::<anyhow::Result<Vec<CategoryProbabilities>>>()?;
+            let truth: Vec<Vec<String>> = test_records.iter().map(|record| record.categories.clone()).collect();
+            let metrics = category_metrics(&model.categories, &predictions, &truth);
+            for m in &metrics {
+                println!("Category {}: Precision = {:.3}, Recall = {:.3}, F-Score = {:.3} ({} test examples)", m.category, m.precision, m.recall, m.f_score, m.support);
+            }
+            Some(metrics)
+        },
+        None => None,
+    };
+
+    write_json_report(&args.output, &EvalReport { train, test, operating_point, conformal, categories })
+}
+
+#[derive(Serialize)]
+struct TuneResult {
+    k: usize,
+    best: ThresholdMetrics,
+}
+
+pub fn tune(args: &TuneArgs) -> anyhow::Result<()> {
+    let (train_records, test_records) = split_dataset(load_embeddings(&args.embeddings, &args.filter)?, args.train_ratio, args.seed);
+    let ((x_train, y_train), (x_test, y_test)) = (to_dataset(&train_records), to_dataset(&test_records));
+    if x_train.is_empty() || x_test.is_empty() {
+        return Err(anyhow::anyhow!("Error: not enough entries to split into train and test data"));
+    }
+
+    let thresholds: Vec<f32> = (1..20).map(|i| i as f32 * 0.05).collect();
+    let x = DenseMatrix::from_2d_array(&x_test.iter().map(|x| &x[..]).collect::<Vec<&[f32]>>()[..]);
+
+    let mut results: Vec<TuneResult> = Vec::new();
+    for &k in &args.k_values {
+        let regressor = fit_knn_regression_model(&x_train, &y_train, k)?;
+        let y_hat = regressor.predict(&x).map_err(|err| anyhow::anyhow!("Error: prediction failed: {}", err))?;
+        let best = compute_metrics(&y_test, &y_hat, &thresholds).into_iter()
+            .filter(|m| m.f_score.is_finite())
+            .max_by(|a, b| a.f_score.total_cmp(&b.f_score));
+        if let Some(best) = best {
+            println!("k = {}: best threshold >= {:.2}, Precision = {:.3}, Recall = {:.3}, F-Score = {:.3}", k, best.threshold, best.precision, best.recall, best.f_score);
+            results.push(TuneResult { k, best });
+        }
+    }
+
+    let best = results.iter().max_by(|a, b| a.best.f_score.total_cmp(&b.best.f_score))
+        .ok_or_else(|| anyhow::anyhow!("Error: no candidate produced a valid F-Score"))?;
+    println!("Best: k = {}, threshold >= {:.2} (F-Score = {:.3})", best.k, best.best.threshold, best.best.f_score);
+
+    write_json_report(&args.output, &results)
+}
+
+pub fn importance(args: &ImportanceArgs) -> anyhow::Result<()> {
+    let records = load_embeddings(&args.embeddings, &args.filter)?;
+    let signal_features = args.signal_features || model_inputs(&args.model, &args.model_type.into())?.signal_features;
+    let (x_dataset, y_dataset) = to_features(&records, signal_features);
+    let mut feature_labels = embedding_feature_labels(records[0].embedding.len());
+    if signal_features {
+        feature_labels.extend(signal_feature_labels());
+    }
+
+    let model = ClassificationMockModel { label: args.model.clone(), model_type: args.model_type.into() };
+    let result = permutation_importance(&model, &x_dataset, &y_dataset, &feature_labels, args.sample_size, args.seed)?;
+
+    for feature in result.iter().take(10) {
+        println!("{}: {:.5}", feature.feature, feature.importance);
+    }
+    write_json_report(&Some(args.output.clone()), &result)
+}
+
+pub async fn robustness(args: &RobustnessArgs) -> anyhow::Result<()> {
+    let (_train_records, test_records) = split_dataset(load_embeddings(&args.embeddings, &args.filter)?, args.train_ratio, args.seed);
+    let spam_texts: Vec<String> = test_records.into_iter()
+        .filter(|record| record.label >= 0.5)
+        .take(args.max_samples)
+        .map(|record| record.text)
+        .collect();
+    if spam_texts.is_empty() {
+        return Err(anyhow::anyhow!("Error: no held-out spam to perturb"));
+    }
+
+    let provider = CachedEmbeddingProvider::new(args.endpoint.provider()?, spam_texts.len());
+    let detector = FraudDetector::load(Arc::new(provider), &args.model, args.threshold)?;
+    let report = evaluate_robustness(&detector, &spam_texts, &args.perturbations, args.seed).await;
+
+    println!("Detected {} of {} held-out spam samples at threshold >= {}", report.detected_count, report.spam_count, report.threshold);
+    for perturbation in &report.perturbations {
+        println!(
+            "{}: {} of {} flipped to ham ({:.1}%), {} changed, {} failed, mean score change {:.3}",
+            perturbation.perturbation, perturbation.flipped_count, perturbation.sample_count, perturbation.flip_rate * 100.0,
+            perturbation.changed_count, perturbation.failed_count, perturbation.mean_score_change
+        );
+    }
+    write_json_report(&Some(args.output.clone()), &report)
+}
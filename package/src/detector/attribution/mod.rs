@@ -0,0 +1,128 @@
+//! Attribution by occlusion: every span of a text is removed in turn and the text is re-scored,
+//! the drop in the score is how much the span contributed to it.
+
+use serde::{Deserialize, Serialize};
+
+use super::FraudDetector;
+
+/// Upper bound on the number of re-scored texts per attribution.
+pub const MAX_SPANS: usize = 64;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Segmentation {
+    /// Sentences, ending at `.`, `!`, `?` or a line break.
+    Sentences,
+    /// Consecutive windows of `size` words.
+    Words { size: usize },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SpanAttribution {
+    /// Byte offsets into the original text.
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+    /// Score of the text without this span, `None` if nothing is left to score.
+    pub score_without: Option<f32>,
+    /// `score - score_without`, positive if the span pushes the text towards fraud.
+    pub delta: Option<f32>,
+    /// Why the text without this span could not be scored, the span is left unattributed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Attribution {
+    pub score: f32,
+    pub fraud: bool,
+    pub spans: Vec<SpanAttribution>,
+}
+
+pub fn sentence_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let next_is_boundary = chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+        if c == '\n' || (matches!(c, '.' | '!' | '?') && next_is_boundary) {
+            let end = index + c.len_utf8();
+            push_trimmed(text, start, end, &mut spans);
+            start = end;
+        }
+    }
+    push_trimmed(text, start, text.len(), &mut spans);
+    spans
+}
+
+pub fn word_spans(text: &str, size: usize) -> Vec<(usize, usize)> {
+    let words: Vec<(usize, usize)> = text.split_whitespace()
+        .map(|word| {
+            let start = word.as_ptr() as usize - text.as_ptr() as usize;
+            (start, start + word.len())
+        })
+        .collect();
+    words.chunks(size.max(1)).map(|chunk| (chunk[0].0, chunk[chunk.len() - 1].1)).collect()
+}
+
+fn push_trimmed(text: &str, start: usize, end: usize, spans: &mut Vec<(usize, usize)>) {
+    let span = &text[start..end];
+    let trimmed = span.trim();
+    if !trimmed.is_empty() {
+        let offset = start + (span.len() - span.trim_start().len());
+        spans.push((offset, offset + trimmed.len()));
+    }
+}
+
+fn without_span(text: &str, (start, end): (usize, usize)) -> String {
+    format!("{} {}", text[..start].trim_end(), text[end..].trim_start()).trim().to_string()
+}
+
+impl FraudDetector {
+    /// Scores `text` and every variant of it with one span removed.
+    ///
+    /// The original text goes through the embedding provider as well, so with a
+    /// `CachedEmbeddingProvider` explaining a text that was just scored costs one request per span.
+    /// The variants are not recorded in the score and verdict metrics, and a variant that can not
+    /// be scored leaves its span unattributed instead of failing the attribution.
+    pub async fn attribute(&self, text: &str, segmentation: Segmentation) -> anyhow::Result<Attribution> {
+        let spans = match segmentation {
+            Segmentation::Sentences => sentence_spans(text),
+            Segmentation::Words { size } => word_spans(text, size),
+        };
+        if spans.len() > MAX_SPANS {
+            return Err(anyhow::anyhow!("Error: text has {} spans, at most {} are supported", spans.len(), MAX_SPANS));
+        }
+
+        let occluded: Vec<String> = spans.iter().map(|&span| without_span(text, span)).collect();
+        let mut texts: Vec<&str> = vec![text];
+        texts.extend(occluded.iter().filter(|text| !text.is_empty()).map(|text| text.as_str()));
+
+        let model = self.model();
+        let embeddings = self.embed_with(&model, &texts).await;
+        let mut scores = self.score_embeddings_with(&model, embeddings).into_iter();
+        let score = scores.next().ok_or_else(|| anyhow::anyhow!("missing prediction"))??;
+
+        let spans = spans.into_iter().zip(occluded.iter()).map(|((start, end), occluded)| {
+            let without = if occluded.is_empty() {
+                Ok(None)
+            } else {
+                scores.next().unwrap_or_else(|| Err(anyhow::anyhow!("missing prediction"))).map(|without| Some(without.score))
+            };
+            let (score_without, error) = match without {
+                Ok(score_without) => (score_without, None),
+                Err(err) => (None, Some(err.to_string())),
+            };
+            SpanAttribution {
+                start,
+                end,
+                text: text[start..end].to_string(),
+                score_without,
+                delta: score_without.map(|without| score.score - without),
+                error,
+            }
+        }).collect();
+
+        Ok(Attribution { score: score.score, fraud: score.fraud, spans })
+    }
+}
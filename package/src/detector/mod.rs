@@ -0,0 +1,296 @@
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use tracing::{debug, instrument};
+
+use crate::build::classification::bundle::{BundleMetadata, ModelBundle, Neighbor};
+use crate::build::classification::feedback::{self, FeedbackEntry};
+use crate::build::classification::categories::CategoryProbabilities;
+use crate::build::classification::conformal::ConformalLabel;
+use crate::build::classification::novelty::DEFAULT_NOVELTY_THRESHOLD;
+use crate::build::classification::KNN_DEFAULT_K;
+use crate::build::language_model::embeddings::EmbeddingProvider;
+use crate::build::normalization::{normalize, NormalizationConfig};
+use crate::metrics;
+
+pub mod attribution;
+
+/// Number of embedding requests in flight per batch.
+const EMBEDDING_CONCURRENCY: usize = 4;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FraudScore {
+    pub score: f32,
+    pub fraud: bool,
+    /// From 0 (like the training examples) to 1, `None` for models without a novelty calibration.
+    pub novelty: Option<f32>,
+    /// The novelty is at or above the novelty threshold, the score should not be trusted.
+    pub out_of_distribution: bool,
+    /// The labels that can not be ruled out at the error level, `None` for models without a conformal calibration.
+    pub prediction_set: Option<Vec<ConformalLabel>>,
+    /// Probability of every fraud category, `None` for models trained without categories.
+    pub categories: Option<CategoryProbabilities>,
+}
+
+/// A score together with the training examples closest to the input.
+#[derive(Clone, Debug, Serialize)]
+pub struct Explanation {
+    #[serde(flatten)]
+    pub score: FraudScore,
+    pub neighbors: Vec<Neighbor>,
+}
+
+/// A model bundle and where it was loaded from.
+pub struct LoadedModel {
+    pub bundle: ModelBundle,
+    pub path: String,
+    /// Seconds since the unix epoch.
+    pub loaded_at: u64,
+    modified: Option<SystemTime>,
+}
+
+impl LoadedModel {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        Ok(LoadedModel { bundle: feedback::load_bundle(path)?, path: path.to_string(), loaded_at: unix_time(), modified })
+    }
+
+    pub fn metadata(&self) -> &BundleMetadata {
+        &self.bundle.metadata
+    }
+
+    /// Loaded from a model file or trained on examples, an empty bundle can not score anything.
+    pub fn is_loaded(&self) -> bool {
+        !self.path.is_empty() || self.bundle.metadata.training_count > 0
+    }
+}
+
+/// `None` for inputs that could not be scored.
+fn record_score(score: Option<&FraudScore>) {
+    let verdict = match score {
+        Some(score) => {
+            metrics::SCORES.observe(score.score as f64);
+            if score.out_of_distribution {
+                metrics::OUT_OF_DISTRIBUTION.inc();
+            }
+            if score.fraud { "fraud" } else { "ham" }
+        },
+        None => "error",
+    };
+    metrics::VERDICTS.with_label_values(&[verdict]).inc();
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Checks that `candidate` can replace `current`: same embedding dimension and a sane prediction.
+fn validate_bundle(candidate: &ModelBundle, current: &ModelBundle) -> anyhow::Result<()> {
+    let candidate_dimension = candidate.metadata.embedding_dimension;
+    let current_dimension = current.metadata.embedding_dimension;
+    if let (Some(candidate_dimension), Some(current_dimension)) = (candidate_dimension, current_dimension) {
+        if candidate_dimension != current_dimension {
+            return Err(anyhow::anyhow!("Error: embedding dimension changed from {} to {}", current_dimension, candidate_dimension));
+        }
+    }
+    if let Some(dimension) = candidate_dimension.or(current_dimension) {
+        let scores = candidate.predict(&[vec![0.0; dimension]])?;
+        if scores.len() != 1 || !scores[0].is_finite() {
+            return Err(anyhow::anyhow!("Error: model returned an invalid prediction: {:?}", scores));
+        }
+    }
+    Ok(())
+}
+
+/// Scores texts with a model bundle, using any embedding provider.
+///
+/// The bundle can be replaced while the detector is in use, requests that already
+/// started keep using the bundle they started with.
+pub struct FraudDetector {
+    provider: Arc<dyn EmbeddingProvider>,
+    model: RwLock<Arc<LoadedModel>>,
+    /// Serializes reloads and feedback, so neither replaces the model the other just built.
+    updates: Mutex<()>,
+    pub threshold: f32,
+    /// Inputs with a novelty at or above this are flagged as out of distribution.
+    pub novelty_threshold: f32,
+    /// Error level of the prediction sets, defaults to the one the model was calibrated for.
+    pub error_level: Option<f32>,
+}
+
+impl FraudDetector {
+    pub fn new(provider: Arc<dyn EmbeddingProvider>, bundle: ModelBundle, threshold: f32) -> Self {
+        let model = LoadedModel { bundle, path: String::new(), loaded_at: unix_time(), modified: None };
+        FraudDetector { provider, model: RwLock::new(Arc::new(model)), updates: Mutex::new(()), threshold, novelty_threshold: DEFAULT_NOVELTY_THRESHOLD, error_level: None }
+    }
+
+    pub fn load(provider: Arc<dyn EmbeddingProvider>, model_path: &str, threshold: f32) -> anyhow::Result<Self> {
+        Ok(FraudDetector {
+            provider,
+            model: RwLock::new(Arc::new(LoadedModel::load(model_path)?)),
+            updates: Mutex::new(()),
+            threshold,
+            novelty_threshold: DEFAULT_NOVELTY_THRESHOLD,
+            error_level: None,
+        })
+    }
+
+    /// The model used for new requests.
+    pub fn model(&self) -> Arc<LoadedModel> {
+        match self.model.read() {
+            Ok(model) => Arc::clone(&model),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+        }
+    }
+
+    /// Loads and validates the bundle at `path` (or the current path) and swaps it in.
+    /// If loading or validation fails, the current model stays in use and the error is returned.
+    pub fn reload(&self, path: Option<&str>) -> anyhow::Result<Arc<LoadedModel>> {
+        let _updates = self.updates.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let current = self.model();
+        let path = path.unwrap_or(&current.path);
+        let candidate = LoadedModel::load(path)?;
+        validate_bundle(&candidate.bundle, &current.bundle)?;
+        Ok(self.swap(candidate))
+    }
+
+    fn swap(&self, candidate: LoadedModel) -> Arc<LoadedModel> {
+        let candidate = Arc::new(candidate);
+        match self.model.write() {
+            Ok(mut model) => *model = Arc::clone(&candidate),
+            Err(poisoned) => *poisoned.into_inner() = Arc::clone(&candidate),
+        }
+        candidate
+    }
+
+    /// Embeds a labeled text (e.g. a scam confirmed by a moderator) the way the current model embeds its inputs.
+    pub async fn feedback_entry(&self, text: &str, label: f32) -> anyhow::Result<FeedbackEntry> {
+        let normalization = self.model().metadata().normalization;
+        let embedding = self.embed_text(text, &normalization).await?;
+        FeedbackEntry::new(text, label, embedding)
+    }
+
+    /// Adds the entry to the training examples of the current model, so it affects scores right away,
+    /// replacing any training example with the same text. It is appended to the feedback log of the model file as well,
+    /// which is replayed when the model is loaded again, until the log is compacted into the bundle.
+    ///
+    /// Syncs the log to disk, async code should call it on a blocking thread.
+    pub fn add_feedback(&self, entry: FeedbackEntry) -> anyhow::Result<(FeedbackEntry, Arc<LoadedModel>)> {
+        let _updates = self.updates.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let current = self.model();
+        let bundle = feedback::apply(&current.bundle, std::slice::from_ref(&entry))?;
+        if !current.path.is_empty() {
+            feedback::append(&current.path, &entry)?;
+        }
+        let model = self.swap(LoadedModel { bundle, path: current.path.clone(), loaded_at: current.loaded_at, modified: current.modified });
+        Ok((entry, model))
+    }
+
+    /// Reloads the current model file if it changed on disk since it was loaded.
+    pub fn reload_if_modified(&self) -> anyhow::Result<Option<Arc<LoadedModel>>> {
+        let current = self.model();
+        if current.path.is_empty() {
+            return Ok(None);
+        }
+        let modified = fs::metadata(&current.path).and_then(|metadata| metadata.modified()).ok();
+        if modified.is_none() || modified == current.modified {
+            return Ok(None);
+        }
+        self.reload(None).map(Some)
+    }
+
+    pub fn provider(&self) -> &Arc<dyn EmbeddingProvider> {
+        &self.provider
+    }
+
+    async fn embed_text(&self, text: &str, normalization: &NormalizationConfig) -> anyhow::Result<Vec<f32>> {
+        let text = normalize(text, normalization);
+        let text = text.as_str();
+        if text.trim().is_empty() {
+            metrics::EMBEDDING_FAILURES.with_label_values(&["empty_text"]).inc();
+            return Err(anyhow::anyhow!("empty text"));
+        }
+        let timer = metrics::EMBEDDING_DURATION.start_timer();
+        let result = self.provider.embed(text).await;
+        timer.observe_duration();
+        match result {
+            Ok(embedding) if embedding.is_empty() => {
+                metrics::EMBEDDING_FAILURES.with_label_values(&["empty_embedding"]).inc();
+                Err(anyhow::anyhow!("embedding failed: empty embedding"))
+            },
+            Ok(embedding) => Ok(embedding),
+            Err(err) => {
+                let kind = metrics::embedding_failure_kind(&err);
+                debug!(kind, error = %err, "embedding failed");
+                metrics::EMBEDDING_FAILURES.with_label_values(&[kind]).inc();
+                Err(anyhow::anyhow!("embedding failed: {}", err))
+            },
+        }
+    }
+
+    /// Embeds the texts after normalizing them the way the current model expects.
+    pub async fn embed(&self, texts: &[&str]) -> Vec<anyhow::Result<Vec<f32>>> {
+        self.embed_with(&self.model(), texts).await
+    }
+
+    async fn embed_with(&self, model: &LoadedModel, texts: &[&str]) -> Vec<anyhow::Result<Vec<f32>>> {
+        let normalization = model.metadata().normalization;
+        let requests: Vec<_> = texts.iter().map(|text| self.embed_text(text, &normalization)).collect();
+        stream::iter(requests)
+            .buffered(EMBEDDING_CONCURRENCY)
+            .collect()
+            .await
+    }
+
+    /// Scores every text on its own, a text that fails gets an error instead of failing the batch.
+    #[instrument(skip_all, fields(batch_size = texts.len()))]
+    pub async fn score(&self, texts: &[&str]) -> Vec<anyhow::Result<FraudScore>> {
+        // hold on to the model, so a reload during embedding does not affect this request
+        let model = self.model();
+        let timer = metrics::SCORING_DURATION.start_timer();
+        metrics::BATCH_SIZE.observe(texts.len() as f64);
+        let embeddings = self.embed_with(&model, texts).await;
+        let scores: Vec<anyhow::Result<FraudScore>> = self.score_embeddings_with(&model, embeddings).into_iter().inspect(|score| record_score(score.as_ref().ok())).collect();
+        timer.observe_duration();
+        scores
+    }
+
+    /// Scores the texts and returns the `k` nearest training examples of each,
+    /// `k` defaults to the number of neighbors the model was trained with.
+    pub async fn explain(&self, texts: &[&str], k: Option<usize>) -> Vec<anyhow::Result<Explanation>> {
+        let model = self.model();
+        let k = k.or(model.metadata().k).unwrap_or(KNN_DEFAULT_K);
+        let embeddings = self.embed_with(&model, texts).await;
+        self.assess_embeddings_with(&model, embeddings, k).into_iter().inspect(|explanation| record_score(explanation.as_ref().ok().map(|explanation| &explanation.score)))
+            .collect()
+    }
+
+    pub fn score_embeddings(&self, embeddings: Vec<anyhow::Result<Vec<f32>>>) -> Vec<anyhow::Result<FraudScore>> {
+        self.score_embeddings_with(&self.model(), embeddings).into_iter().inspect(|score| record_score(score.as_ref().ok())).collect()
+    }
+
+    /// Scores the embeddings without recording the scores and verdicts in the metrics.
+    fn score_embeddings_with(&self, model: &LoadedModel, embeddings: Vec<anyhow::Result<Vec<f32>>>) -> Vec<anyhow::Result<FraudScore>> {
+        self.assess_embeddings_with(model, embeddings, 0).into_iter().map(|explanation| explanation.map(|explanation| explanation.score)).collect()
+    }
+
+    /// Every embedding is scored on its own with a single neighbor search, which gives the score,
+    /// the novelty, the categories and the `explain` nearest training examples.
+    fn assess_embeddings_with(&self, model: &LoadedModel, embeddings: Vec<anyhow::Result<Vec<f32>>>, explain: usize) -> Vec<anyhow::Result<Explanation>> {
+        embeddings.into_iter().map(|embedding| {
+            let assessment = model.bundle.assess(&embedding?, explain)?;
+            let score = assessment.score;
+            let novelty = assessment.novelty.map(|novelty| novelty.score);
+            let out_of_distribution = novelty.is_some_and(|novelty| novelty >= self.novelty_threshold);
+            let prediction_set = model.bundle.conformal.as_ref()
+                .map(|conformal| conformal.prediction_set(score, self.error_level.unwrap_or(conformal.error_level)));
+            Ok(Explanation {
+                score: FraudScore { score, fraud: score >= self.threshold, novelty, out_of_distribution, prediction_set, categories: assessment.categories },
+                neighbors: assessment.neighbors,
+            })
+        }).collect()
+    }
+}
@@ -1,32 +1,80 @@
 
 pub mod build;
+pub mod commands;
+pub mod batch;
+pub mod detector;
+pub mod metrics;
+pub mod robustness;
+pub mod service;
 
 use build::classification::*;
-use rayon::iter::IntoParallelIterator;
-use rayon::iter::ParallelIterator;
-use importance::score::Model;
+use build::language_model::embeddings::EmbeddingProvider;
+use build::features::with_signal_features;
+use build::normalization::normalize;
 
 
+pub const DEFAULT_MODEL_PATH: &str = "./KNNRegressor.bin";
+
 pub async fn fraud_probabilities(texts: &[&str]) ->  anyhow::Result<Vec<f32>> {
+    fraud_probabilities_with_model(texts, DEFAULT_MODEL_PATH).await
+}
 
-    let mut text_embeddings: Vec<Vec<f32>> = Vec::new();
-    for text in texts {
+/// Fails if any of the texts can not be scored, use `fraud_probability_results` to score the others anyway.
+pub async fn fraud_probabilities_with_model(texts: &[&str], model_path: &str) ->  anyhow::Result<Vec<f32>> {
+    fraud_probability_results(texts, model_path).await.into_iter().collect()
+}
 
-       let embedding = build::language_model::embeddings::llama_cpp_embedding(text).await?;
-       text_embeddings.push(embedding);
+/// Scores every text on its own: a text that can not be embedded gets an error instead of failing the whole batch.
+pub async fn fraud_probability_results(texts: &[&str], model_path: &str) -> Vec<anyhow::Result<f32>> {
+    fraud_probability_results_from(texts, model_path, None).await
+}
 
-    }
+/// Like `fraud_probability_results`, but embeds with `provider` (e.g. the offline embedder) instead of the llama.cpp server.
+pub async fn fraud_probability_results_with_provider(texts: &[&str], model_path: &str, provider: &dyn EmbeddingProvider) -> Vec<anyhow::Result<f32>> {
+    fraud_probability_results_from(texts, model_path, Some(provider)).await
+}
 
-    let model = ClassificationMockModel { label: "./KNNRegressor.bin".to_string(), model_type: ModelType::KNN};
-    let x_dataset = text_embeddings.into_par_iter().map(|vec| {
-         if vec.len()>0 {
-            model.predict(&vec![vec])
-         }else{
-            vec
-         }
-       }).collect::<Vec<Vec<f32>>>();
+async fn fraud_probability_results_from(texts: &[&str], model_path: &str, provider: Option<&dyn EmbeddingProvider>) -> Vec<anyhow::Result<f32>> {
 
-    Ok(x_dataset.into_iter().flatten().collect())
-}
+    let model_type = match model_type_of(model_path) {
+        Ok(model_type) => model_type,
+        Err(err) => {
+            let message = err.to_string();
+            return texts.iter().map(|_| Err(anyhow::anyhow!("{}", message))).collect();
+        }
+    };
+    let inputs = match model_inputs(model_path, &model_type) {
+        Ok(inputs) => inputs,
+        Err(err) => {
+            let message = err.to_string();
+            return texts.iter().map(|_| Err(anyhow::anyhow!("{}", message))).collect();
+        }
+    };
+    let mut text_embeddings: Vec<anyhow::Result<Vec<f32>>> = Vec::new();
+    for text in texts {
+        let normalized = normalize(text, &inputs.normalization);
+        let embedding = match provider {
+            Some(provider) => provider.embed(&normalized).await,
+            None => build::language_model::embeddings::llama_cpp_embedding(&normalized).await.map_err(anyhow::Error::from),
+        };
+        let embedding = embedding
+            .map_err(|err| anyhow::anyhow!("embedding failed: {}", err))
+            .and_then(|embedding| if embedding.is_empty() { Err(anyhow::anyhow!("embedding failed: empty embedding")) } else { Ok(embedding) })
+            .map(|embedding| if inputs.signal_features { with_signal_features(&embedding, text) } else { embedding });
+        text_embeddings.push(embedding);
+    }
 
+    let x_dataset: Vec<Vec<f32>> = text_embeddings.iter().filter_map(|embedding| embedding.as_ref().ok().cloned()).collect();
+    if x_dataset.is_empty() {
+        return text_embeddings.into_iter().map(|embedding| embedding.map(|_| 0.0)).collect();
+    }
+    let mut scores = match predict_with_model(model_path, &model_type, &x_dataset) {
+        Ok(scores) => scores.into_iter(),
+        Err(err) => {
+            let message = err.to_string();
+            return text_embeddings.into_iter().map(|embedding| embedding.and_then(|_| Err(anyhow::anyhow!("{}", message)))).collect();
+        }
+    };
 
+    text_embeddings.into_iter().map(|embedding| embedding.map(|_| scores.next().unwrap_or(f32::NAN))).collect()
+}
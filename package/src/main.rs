@@ -1,143 +1,111 @@
-use std::env;
-use std::fs::File;
-use std::io::Write;
-use std::sync::Arc;
-use rust_bert_fraud_detection_tools::build::create_embeddings;
-use rust_bert_fraud_detection_tools::build::data::{split_vector};
-use rust_bert_fraud_detection_tools::build::language_model::embeddings::load_llama_cpp_embeddings_from_file;
-use futures_util::StreamExt;
-use tokio::sync::Mutex;
-
-const CSV_DATASET: [&str;6] = [
-    "./dataset/youtubeSpamCollection.csv",
-    "./dataset/enronSpamSubset.csv",
-    "./dataset/lingSpam.csv",
-    "./dataset/smsspamcollection.csv",
-    "./dataset/completeSpamAssassin.csv",
-    "./dataset/governance_proposal_spam_likelihood.csv"
-];
-
-pub const SENTENCES: [&str;6] = [
-    "Lose up to 19% weight. Special promotion on our new weightloss.",
-    "Hi Bob, can you send me your machine learning homework?",
-    "Don't forget our special promotion: -30% on men shoes, only today!",
-    "Hi Bob, don't forget our meeting today at 4pm.",
-    "⚠️ FINAL: LAST TERRA PHOENIX AIRDROP 🌎 ✅ CLAIM NOW All participants in this vote will receive a reward..",
-    "Social KYC oracle (TYC)  PFC is asking for 20k Luna to build a social KYC protocol.."
-    ];
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() <= 1 {
-        println!("No command specified.");
-        return Ok(());
-    }
-
-    let command = &args[1];
-
-    match command.as_str() {
-        "train_and_test_text_embedding_knn_regressor" => {train_and_test_text_embedding_knn_regressor(false)?;},
-        "train_and_test_text_embedding_knn_regressor_eval" => {train_and_test_text_embedding_knn_regressor(true)?;},
-
-        "save_training_data" => { rust_bert_fraud_detection_tools::build::save_training_data(CSV_DATASET.to_vec()).await?;},
-
-        "generate_embeddings" => {generate_embeddings().await?;},
-        "predict" => {
-
-                      let fraud_probabilities = rust_bert_fraud_detection_tools::fraud_probabilities(&SENTENCES).await?;
-                      println!("Predictions:\n{:?}",fraud_probabilities);
-                      println!("Labels:\n[1.0, 0.0, 1.0, 0.0, 1.0, 0.0]");
-        },
-        _ => {panic!()}
-    }
-
-    Ok(())
+use clap::{Parser, Subcommand, ValueEnum};
+use rust_bert_fraud_detection_tools::commands::{campaigns, data, labeling, scoring, training};
+use rust_bert_fraud_detection_tools::commands::campaigns::{AssignCampaignsArgs, ClusterCampaignsArgs};
+use rust_bert_fraud_detection_tools::commands::data::{ConvertArgs, DedupArgs, EmbedArgs, FitEmbedderArgs, SaveTrainingDataArgs};
+use rust_bert_fraud_detection_tools::commands::labeling::{ImportLabelsArgs, SelectForLabelingArgs, SelfTrainArgs};
+use rust_bert_fraud_detection_tools::commands::scoring::{CompactFeedbackArgs, FeedbackArgs, PredictArgs, ServeArgs};
+use rust_bert_fraud_detection_tools::commands::training::{EvalArgs, ImportanceArgs, RobustnessArgs, TrainCommandArgs, TuneArgs};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser)]
+#[command(name = "llm_fraud_detection", version, about = "Fraud detection on llama.cpp text embeddings")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Log output format
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Human, env = "LOG_FORMAT")]
+    log_format: LogFormat,
+    /// Log filter, e.g. `info` or `rust_bert_fraud_detection_tools=debug`
+    #[arg(long, global = true, default_value = "info", env = "LOG_LEVEL")]
+    log_level: String,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Human,
+    Json,
+}
 
-fn train_and_test_text_embedding_knn_regressor(eval: bool) -> anyhow::Result<()> {
-
-    let (x_dataset, y_dataset) = load_llama_cpp_embeddings_from_file("embeddings_dataset.json")?;
-
-    let spam_count = y_dataset.iter().filter(|&&label| label == 1.0).count();
-    let ham_count = y_dataset.iter().filter(|&&label| label == 0.0).count();
-    let total_count = y_dataset.len();
-
-    println!("Number of Spam entries: {}", spam_count);
-    println!("Number of Ham entries: {}", ham_count);
-    println!("Total entries: {}", total_count);
-
-    if !eval {
-        rust_bert_fraud_detection_tools::build::classification::update_knn_regression_model(&x_dataset,&y_dataset)?;
-        rust_bert_fraud_detection_tools::build::classification::test_knn_regression_model(&x_dataset,&y_dataset)?;
-    }else {
-        let (x_train, x_test) = split_vector(&x_dataset, 0.8);
-        let x_train = x_train.to_vec();
-        let x_test = x_test.to_vec();
-        let (y_train, y_test) = split_vector(&y_dataset, 0.8);
-        let y_train = y_train.to_vec();
-        let y_test = y_test.to_vec();
-
-        rust_bert_fraud_detection_tools::build::classification::update_knn_regression_model(&x_train,&y_train)?;
-        rust_bert_fraud_detection_tools::build::classification::test_knn_regression_model(&x_train,&y_train)?;
-        rust_bert_fraud_detection_tools::build::classification::test_knn_regression_model(&x_test,&y_test)?;
+/// Logs go to stderr, so stdout only carries the results of a command.
+fn init_logging(format: LogFormat, filter: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(filter).map_err(|err| anyhow::anyhow!("Error: invalid log filter '{}': {}", filter, err))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    match format {
+        LogFormat::Human => builder.init(),
+        LogFormat::Json => builder.json().init(),
     }
     Ok(())
 }
-use tokio::time::Instant;
-
-async fn generate_embeddings() -> anyhow::Result<()> {
-    let buffer_size = 100;
-    let buffer = Arc::new(Mutex::new(Vec::with_capacity(buffer_size)));
-
-    let file = Arc::new(Mutex::new(File::options()
-        .write(true)
-        .append(true)
-        .open("embeddings_dataset.json")
-        .expect("Failed to open file")));
-
-    let (total_count, embeddings) = create_embeddings(CSV_DATASET.into()).await?;
 
-    let start_time = Instant::now();
-    let fut = embeddings.enumerate().for_each(|(index, embedding_result)| {
-        let file = file.clone();
-        let buffer = buffer.clone();
-        async move {
-            let elapsed_time = start_time.elapsed().as_secs_f64();
-            let estimated_time = elapsed_time * (total_count as f64 / (index as f64 + 1.0));
-            let remaining_time = estimated_time - elapsed_time;
-            println!("{}/{} ({:.2}% complete, estimated time remaining: {:.2} seconds)", index, total_count, (index as f64 / total_count as f64) * 100.0, remaining_time);
-
-            if let Ok(embedding) = embedding_result {
-                let json_data = serde_json::to_string(&embedding).expect("Failed to serialize to JSON");
-                let mut buffer_lock = buffer.lock().await;
-                buffer_lock.push(json_data);
-
-                if buffer_lock.len() >= buffer_size {
-                    let mut file = file.lock().await;
-                    for json_data in buffer_lock.drain(..) {
-                        file.write_all(json_data.as_bytes()).expect("Failed to write to file");
-                        file.write_all(b"\n").expect("Failed to write newline");
-                    }
-                }
-            } else {
-                println!("{:?}", embedding_result);
-            }
-        }
-    });
-
-    fut.await;
+#[derive(Subcommand)]
+enum Command {
+    /// Embed all datasets of the manifest (resumes where the last run stopped)
+    Embed(EmbedArgs),
+    /// Fit an offline TF-IDF embedder on the datasets of the manifest, for use without a llama.cpp server
+    FitEmbedder(FitEmbedderArgs),
+    /// Train a model on all embeddings
+    Train(TrainCommandArgs),
+    /// Train on labeled embeddings plus pseudo-labeled unlabeled texts (self-training)
+    SelfTrain(SelfTrainArgs),
+    /// Pick the unlabeled texts to label next and write them as a labeling batch (CSV)
+    SelectForLabeling(SelectForLabelingArgs),
+    /// Add the labeled rows of a labeling batch to a dataset of the manifest
+    ImportLabels(ImportLabelsArgs),
+    /// Train on a duplicate-aware split and evaluate on the held-out part
+    Eval(EvalArgs),
+    /// Perturb held-out spam and report how often it evades the model
+    Robustness(RobustnessArgs),
+    /// Search the number of neighbors and the threshold on a held-out split
+    Tune(TuneArgs),
+    /// Permutation importance of the model inputs
+    Importance(ImportanceArgs),
+    /// Score texts with a trained model
+    Predict(PredictArgs),
+    /// Add a labeled text to a trained KNN model without retraining
+    Feedback(FeedbackArgs),
+    /// Fold the feedback log of a model into its bundle
+    CompactFeedback(CompactFeedbackArgs),
+    /// Serve the model over HTTP
+    Serve(ServeArgs),
+    /// Report exact and near-duplicates in the datasets
+    Dedup(DedupArgs),
+    /// Cluster flagged texts into spam campaigns
+    ///
+    /// At most 10000 distinct texts are clustered per run: their pairwise distances are held in memory,
+    /// which takes about 200 MB at that limit (the memory grows with the square of the number of texts).
+    ClusterCampaigns(ClusterCampaignsArgs),
+    /// Assign flagged texts to the campaigns found by `cluster-campaigns`
+    AssignCampaigns(AssignCampaignsArgs),
+    /// Convert a JSON lines embeddings file into the columnar format
+    Convert(ConvertArgs),
+    /// Write the datasets of the manifest into a single JSON file
+    SaveTrainingData(SaveTrainingDataArgs),
+}
 
-    // Write any remaining items in the buffer
-    let mut buffer_lock = buffer.lock().await;
-    if !buffer_lock.is_empty() {
-        let mut file = file.lock().await;
-        for json_data in buffer_lock.drain(..) {
-            file.write_all(json_data.as_bytes()).expect("Failed to write to file");
-            file.write_all(b"\n").expect("Failed to write newline");
-        }
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    init_logging(cli.log_format, &cli.log_level)?;
+
+    match cli.command {
+        Command::Embed(args) => data::embed(&args).await,
+        Command::FitEmbedder(args) => data::fit_embedder(&args),
+        Command::Train(args) => training::train(&args),
+        Command::SelfTrain(args) => labeling::self_train_command(&args).await,
+        Command::SelectForLabeling(args) => labeling::select_for_labeling_command(&args).await,
+        Command::ImportLabels(args) => labeling::import_labels(&args),
+        Command::Eval(args) => training::eval(&args),
+        Command::Tune(args) => training::tune(&args),
+        Command::Robustness(args) => training::robustness(&args).await,
+        Command::Importance(args) => training::importance(&args),
+        Command::Predict(args) => scoring::predict(&args).await,
+        Command::Feedback(args) => scoring::feedback_command(&args).await,
+        Command::CompactFeedback(args) => scoring::compact_feedback(&args),
+        Command::Serve(args) => scoring::serve(&args).await,
+        Command::Dedup(args) => data::dedup_training_data(&args),
+        Command::ClusterCampaigns(args) => campaigns::cluster_campaigns_command(&args).await,
+        Command::AssignCampaigns(args) => campaigns::assign_campaigns_command(&args).await,
+        Command::Convert(args) => data::convert(&args),
+        Command::SaveTrainingData(args) => data::save_training_data(&args).await,
     }
-
-    return Ok(());
 }
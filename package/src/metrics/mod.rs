@@ -0,0 +1,67 @@
+//! Prometheus metrics of embedding and scoring, rendered by the `/metrics` endpoint of the service.
+
+use prometheus::{Encoder, Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+
+use crate::build::language_model::embeddings::EmbeddingError;
+
+const LATENCY_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+const BATCH_SIZE_BUCKETS: [f64; 9] = [1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+const SCORE_BUCKETS: [f64; 10] = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
+
+lazy_static::lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    pub static ref EMBEDDING_DURATION: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("fraud_detection_embedding_duration_seconds", "Time to embed a single text.").buckets(LATENCY_BUCKETS.to_vec())
+    ));
+    pub static ref EMBEDDING_FAILURES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("fraud_detection_embedding_failures_total", "Texts that could not be embedded, by kind of failure."), &["kind"]
+    ));
+    pub static ref SCORING_DURATION: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("fraud_detection_scoring_duration_seconds", "Time to score a batch, including embedding.").buckets(LATENCY_BUCKETS.to_vec())
+    ));
+    pub static ref BATCH_SIZE: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("fraud_detection_batch_size", "Number of texts per scored batch.").buckets(BATCH_SIZE_BUCKETS.to_vec())
+    ));
+    pub static ref VERDICTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("fraud_detection_verdicts_total", "Scored texts by verdict."), &["verdict"]
+    ));
+    pub static ref SCORES: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("fraud_detection_score", "Distribution of fraud scores.").buckets(SCORE_BUCKETS.to_vec())
+    ));
+    pub static ref OUT_OF_DISTRIBUTION: IntCounter = register(IntCounter::with_opts(
+        Opts::new("fraud_detection_out_of_distribution_total", "Scored texts flagged as out of distribution.")
+    ));
+    pub static ref CACHE_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("fraud_detection_embedding_cache_requests_total", "Embedding cache lookups by result."), &["result"]
+    ));
+    pub static ref CACHE_HIT_RATIO: Gauge = register(Gauge::with_opts(
+        Opts::new("fraud_detection_embedding_cache_hit_ratio", "Share of embedding cache lookups that were hits.")
+    ));
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+    let metric = metric.expect("Failed to create metric.");
+    REGISTRY.register(Box::new(metric.clone())).expect("Failed to register metric.");
+    metric
+}
+
+/// The kind of an `EmbeddingError` anywhere in the chain of `err`, "other" for any other error.
+pub fn embedding_failure_kind(err: &anyhow::Error) -> &'static str {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<EmbeddingError>())
+        .map(|err| err.kind.as_str())
+        .unwrap_or("other")
+}
+
+pub fn record_cache_lookup(hit: bool, hits: u64, misses: u64) {
+    CACHE_REQUESTS.with_label_values(&[if hit { "hit" } else { "miss" }]).inc();
+    CACHE_HIT_RATIO.set(hits as f64 / (hits + misses).max(1) as f64);
+}
+
+/// All metrics in the Prometheus text exposition format.
+pub fn render() -> anyhow::Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
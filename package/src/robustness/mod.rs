@@ -0,0 +1,239 @@
+//! Evasion attempts against the detector: held-out spam is perturbed and re-scored,
+//! a sample that drops below the threshold has been evaded.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use regex::Regex;
+use serde::Serialize;
+use tracing::info;
+
+use crate::detector::FraudDetector;
+
+const FLIP_EXAMPLES: usize = 5;
+const EXAMPLE_SNIPPET_LENGTH: usize = 280;
+/// Share of the letters with a lookalike that are replaced.
+const HOMOGLYPH_RATE: f64 = 0.3;
+/// A zero-width space is inserted after every n-th letter of a word.
+const ZERO_WIDTH_INTERVAL: usize = 2;
+
+const BENIGN_PADDING: [&str; 4] = [
+    "Hi Bob, don't forget our meeting today at 4pm.",
+    "Thanks for the update on the governance discussion, the community call notes are attached.",
+    "The weather was lovely this weekend and we finally finished painting the kitchen.",
+    "Please review the quarterly report before Friday so we can share it with the team.",
+];
+
+const SYNONYMS: [(&str, &str); 24] = [
+    ("claim", "collect"), ("free", "complimentary"), ("reward", "perk"), ("rewards", "perks"),
+    ("airdrop", "token distribution"), ("win", "receive"), ("winner", "recipient"), ("prize", "gift"),
+    ("urgent", "time-sensitive"), ("now", "right away"), ("click", "tap"), ("offer", "deal"),
+    ("money", "funds"), ("cash", "funds"), ("limited", "exclusive"), ("verify", "confirm"),
+    ("account", "profile"), ("wallet", "address"), ("guaranteed", "assured"), ("bonus", "extra"),
+    ("giveaway", "distribution"), ("hurry", "be quick"), ("promotion", "campaign"), ("eligible", "qualified"),
+];
+
+lazy_static::lazy_static! {
+    static ref URL: Regex = Regex::new(r"(?i)\b(?:https?://|www\.)[^\s<>()]+").unwrap();
+    static ref WORD: Regex = Regex::new(r"\b[A-Za-z]+\b").unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Perturbation {
+    /// Latin letters replaced by Cyrillic lookalikes.
+    Homoglyph,
+    /// Zero-width spaces inside words.
+    ZeroWidth,
+    /// Typical scam words replaced by harmless-sounding synonyms.
+    SynonymSwap,
+    /// Benign sentences before and after the text.
+    BenignPadding,
+    /// URLs broken up ("hxxps://scam [.] io / claim").
+    UrlSplit,
+}
+
+impl Perturbation {
+    pub const ALL: [Perturbation; 5] = [Perturbation::Homoglyph, Perturbation::ZeroWidth, Perturbation::SynonymSwap, Perturbation::BenignPadding, Perturbation::UrlSplit];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Perturbation::Homoglyph => "homoglyph",
+            Perturbation::ZeroWidth => "zero_width",
+            Perturbation::SynonymSwap => "synonym_swap",
+            Perturbation::BenignPadding => "benign_padding",
+            Perturbation::UrlSplit => "url_split",
+        }
+    }
+
+    pub fn apply(&self, text: &str, rng: &mut StdRng) -> String {
+        match self {
+            Perturbation::Homoglyph => text.chars().map(|c| match homoglyph(c) {
+                Some(lookalike) if rng.gen_bool(HOMOGLYPH_RATE) => lookalike,
+                _ => c,
+            }).collect(),
+            Perturbation::ZeroWidth => WORD.replace_all(text, |captures: &regex::Captures| {
+                let mut word = String::new();
+                for (i, c) in captures[0].chars().enumerate() {
+                    if i > 0 && i % ZERO_WIDTH_INTERVAL == 0 {
+                        word.push('\u{200B}');
+                    }
+                    word.push(c);
+                }
+                word
+            }).into_owned(),
+            Perturbation::SynonymSwap => WORD.replace_all(text, |captures: &regex::Captures| {
+                let word = &captures[0];
+                match SYNONYMS.iter().find(|(scam, _)| scam.eq_ignore_ascii_case(word)) {
+                    Some((_, synonym)) => match_case(word, synonym),
+                    None => word.to_string(),
+                }
+            }).into_owned(),
+            Perturbation::BenignPadding => {
+                let before = BENIGN_PADDING.choose(rng).unwrap_or(&BENIGN_PADDING[0]);
+                let after = BENIGN_PADDING.choose(rng).unwrap_or(&BENIGN_PADDING[0]);
+                format!("{} {} {}", before, text, after)
+            },
+            Perturbation::UrlSplit => URL.replace_all(text, |captures: &regex::Captures| {
+                captures[0].replacen("https://", "hxxps://", 1).replacen("http://", "hxxp://", 1)
+                    .replace('.', " [.] ")
+                    .replace('/', " / ")
+                    .replace(" /  / ", "//")
+            }).into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Perturbation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Perturbation {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        Perturbation::ALL.iter().find(|perturbation| perturbation.name() == value).copied()
+            .ok_or_else(|| anyhow::anyhow!("Error: unknown perturbation '{}', expected one of {}", value, Perturbation::ALL.map(|p| p.name()).join(", ")))
+    }
+}
+
+fn homoglyph(c: char) -> Option<char> {
+    Some(match c {
+        'a' => 'а', 'c' => 'с', 'e' => 'е', 'i' => 'і', 'o' => 'о', 'p' => 'р', 'x' => 'х', 'y' => 'у',
+        'A' => 'А', 'B' => 'В', 'C' => 'С', 'E' => 'Е', 'H' => 'Н', 'K' => 'К', 'M' => 'М', 'O' => 'О', 'P' => 'Р', 'T' => 'Т', 'X' => 'Х',
+        _ => return None,
+    })
+}
+
+fn match_case(original: &str, replacement: &str) -> String {
+    if original.len() > 1 && original.chars().all(|c| c.is_uppercase()) {
+        replacement.to_uppercase()
+    } else if original.chars().next().is_some_and(|c| c.is_uppercase()) {
+        let mut chars = replacement.chars();
+        chars.next().map(|first| first.to_uppercase().chain(chars).collect()).unwrap_or_default()
+    } else {
+        replacement.to_string()
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FlipExample {
+    pub original: String,
+    pub perturbed: String,
+    pub original_score: f32,
+    pub perturbed_score: f32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PerturbationReport {
+    pub perturbation: Perturbation,
+    /// Detected spam samples the perturbation was applied to.
+    pub sample_count: usize,
+    /// Samples the perturbation actually changed (e.g. url_split needs a URL).
+    pub changed_count: usize,
+    /// Samples that dropped below the threshold.
+    pub flipped_count: usize,
+    pub flip_rate: f32,
+    /// Samples that could not be scored after the perturbation.
+    pub failed_count: usize,
+    pub mean_score_change: f32,
+    pub examples: Vec<FlipExample>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RobustnessReport {
+    pub threshold: f32,
+    pub spam_count: usize,
+    /// Spam samples scored at or above the threshold before any perturbation, only these are perturbed.
+    pub detected_count: usize,
+    pub failed_count: usize,
+    pub perturbations: Vec<PerturbationReport>,
+}
+
+/// Scores the spam texts, then every detected one again after each perturbation.
+pub async fn evaluate_robustness(detector: &FraudDetector, spam_texts: &[String], perturbations: &[Perturbation], seed: u64) -> RobustnessReport {
+    let texts: Vec<&str> = spam_texts.iter().map(|text| text.as_str()).collect();
+    let scores = detector.score(&texts).await;
+    let failed_count = scores.iter().filter(|score| score.is_err()).count();
+    let detected: Vec<(&str, f32)> = texts.iter().zip(scores.iter())
+        .filter_map(|(text, score)| match score {
+            Ok(score) if score.fraud => Some((*text, score.score)),
+            _ => None,
+        })
+        .collect();
+    info!(spam_count = texts.len(), detected_count = detected.len(), failed_count, "scored unperturbed spam");
+
+    let mut reports = Vec::with_capacity(perturbations.len());
+    for perturbation in perturbations {
+        let perturbed: Vec<String> = detected.iter().enumerate()
+            .map(|(i, (text, _))| perturbation.apply(text, &mut StdRng::seed_from_u64(seed.wrapping_add(i as u64))))
+            .collect();
+        let perturbed_texts: Vec<&str> = perturbed.iter().map(|text| text.as_str()).collect();
+        let perturbed_scores = detector.score(&perturbed_texts).await;
+
+        let mut report = PerturbationReport {
+            perturbation: *perturbation,
+            sample_count: detected.len(),
+            changed_count: detected.iter().zip(perturbed.iter()).filter(|((original, _), perturbed)| original != perturbed).count(),
+            flipped_count: 0,
+            flip_rate: 0.0,
+            failed_count: 0,
+            mean_score_change: 0.0,
+            examples: Vec::new(),
+        };
+        let mut score_change_sum = 0.0;
+        for (((original, original_score), perturbed), score) in detected.iter().zip(perturbed.iter()).zip(perturbed_scores) {
+            match score {
+                Ok(score) => {
+                    score_change_sum += score.score - original_score;
+                    if !score.fraud {
+                        report.flipped_count += 1;
+                        if report.examples.len() < FLIP_EXAMPLES {
+                            report.examples.push(FlipExample {
+                                original: original.chars().take(EXAMPLE_SNIPPET_LENGTH).collect(),
+                                perturbed: perturbed.chars().take(EXAMPLE_SNIPPET_LENGTH).collect(),
+                                original_score: *original_score,
+                                perturbed_score: score.score,
+                            });
+                        }
+                    }
+                },
+                Err(_) => report.failed_count += 1,
+            }
+        }
+        let scored_count = report.sample_count - report.failed_count;
+        if scored_count > 0 {
+            report.flip_rate = report.flipped_count as f32 / scored_count as f32;
+            report.mean_score_change = score_change_sum / scored_count as f32;
+        }
+        info!(perturbation = perturbation.name(), flipped_count = report.flipped_count, flip_rate = report.flip_rate, "evaluated perturbation");
+        reports.push(report);
+    }
+
+    RobustnessReport { threshold: detector.threshold, spam_count: texts.len(), detected_count: detected.len(), failed_count, perturbations: reports }
+}
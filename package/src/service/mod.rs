@@ -0,0 +1,350 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::header::{HeaderName, AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::build::classification::categories::CategoryProbabilities;
+use crate::build::classification::conformal::ConformalLabel;
+use crate::build::classification::feedback::FeedbackEntry;
+use crate::build::features::SignalFeatures;
+use crate::detector::attribution::{Attribution, Segmentation};
+use crate::detector::{Explanation, FraudDetector, LoadedModel};
+
+const READINESS_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_EXPLAIN_NEIGHBORS: usize = 50;
+
+#[derive(Clone, Debug)]
+pub struct ServiceConfig {
+    pub bind: SocketAddr,
+    /// Requests with a larger body are rejected with 413.
+    pub max_body_bytes: usize,
+    /// Batches with more texts are rejected with 413.
+    pub max_batch_size: usize,
+    /// Enables `POST /admin/reload` and `POST /admin/feedback` for requests with `Authorization: Bearer <token>`.
+    pub admin_token: Option<String>,
+    /// Reloads the model when its file changes, checked at this interval.
+    pub watch_interval: Option<Duration>,
+}
+
+pub struct ServiceState {
+    pub detector: FraudDetector,
+    pub config: ServiceConfig,
+}
+
+#[derive(Default, Deserialize)]
+struct ReloadRequest {
+    /// Load the bundle from another path instead of re-reading the current one.
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FeedbackRequest {
+    text: String,
+    /// 1 for fraud, 0 for ham.
+    label: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScoreRequest {
+    Batch { texts: Vec<String> },
+    Single { text: String },
+}
+
+#[derive(Deserialize)]
+struct ExplainRequest {
+    #[serde(flatten)]
+    texts: ScoreRequest,
+    /// Number of neighbors per text, defaults to the k of the model.
+    k: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct AttributeRequest {
+    text: String,
+    /// Defaults to sentences.
+    segmentation: Option<Segmentation>,
+}
+
+#[derive(Serialize)]
+struct ScoreResult {
+    score: Option<f32>,
+    verdict: &'static str,
+    novelty: Option<f32>,
+    out_of_distribution: bool,
+    prediction_set: Option<Vec<ConformalLabel>>,
+    categories: Option<CategoryProbabilities>,
+    error: Option<String>,
+    signals: SignalFeatures,
+}
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+pub fn router(state: Arc<ServiceState>) -> Router {
+    let max_body_bytes = state.config.max_body_bytes;
+    let mut router = Router::new()
+        .route("/v1/score", post(score))
+        .route("/v1/explain", post(explain))
+        .route("/v1/attribute", post(attribute))
+        .route("/v1/model", get(model))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics));
+    if state.config.admin_token.is_some() {
+        router = router
+            .route("/admin/reload", post(reload))
+            .route("/admin/feedback", post(feedback));
+    }
+    router
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .with_state(state)
+}
+
+async fn reload_model(state: Arc<ServiceState>, path: Option<String>) -> anyhow::Result<Arc<LoadedModel>> {
+    tokio::task::spawn_blocking(move || state.detector.reload(path.as_deref())).await?
+}
+
+async fn add_feedback(state: Arc<ServiceState>, request: FeedbackRequest) -> anyhow::Result<(FeedbackEntry, Arc<LoadedModel>)> {
+    let entry = state.detector.feedback_entry(&request.text, request.label).await?;
+    tokio::task::spawn_blocking(move || state.detector.add_feedback(entry)).await?
+}
+
+/// Polls the model file and swaps in the new bundle when it changes.
+async fn watch_model(state: Arc<ServiceState>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let watched = Arc::clone(&state);
+        match tokio::task::spawn_blocking(move || watched.detector.reload_if_modified()).await {
+            Ok(Ok(Some(model))) => info!(path = model.path.as_str(), "reloaded model"),
+            Ok(Ok(None)) => {},
+            Ok(Err(err)) => warn!(error = %err, "model reload failed, keeping the current model"),
+            Err(err) => warn!(error = %err, "model reload failed, keeping the current model"),
+        }
+    }
+}
+
+/// Serves until ctrl-c or SIGTERM, then stops accepting connections and lets in-flight requests finish.
+pub async fn serve(state: Arc<ServiceState>) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(state.config.bind).await
+        .map_err(|err| anyhow::anyhow!("Error: unable to bind {}: {}", state.config.bind, err))?;
+    info!(address = %listener.local_addr()?, "listening");
+
+    let watcher = state.config.watch_interval.map(|interval| tokio::spawn(watch_model(Arc::clone(&state), interval)));
+
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+    if let Some(watcher) = watcher {
+        watcher.abort();
+    }
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => { signal.recv().await; },
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("shutting down");
+}
+
+/// The texts of a request and whether it was a batch, rejects batches over the limit.
+fn request_texts(state: &ServiceState, request: ScoreRequest) -> Result<(Vec<String>, bool), ApiError> {
+    let (texts, batch) = match request {
+        ScoreRequest::Batch { texts } => (texts, true),
+        ScoreRequest::Single { text } => (vec![text], false),
+    };
+    if texts.len() > state.config.max_batch_size {
+        return Err(ApiError(StatusCode::PAYLOAD_TOO_LARGE, format!("batch of {} texts exceeds the limit of {}", texts.len(), state.config.max_batch_size)));
+    }
+    Ok((texts, batch))
+}
+
+fn batch_response<T: Serialize>(results: Vec<T>, batch: bool) -> Result<Json<Value>, ApiError> {
+    if batch {
+        Ok(Json(json!({ "results": results })))
+    } else {
+        Ok(Json(serde_json::to_value(&results[0]).map_err(|err| ApiError(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?))
+    }
+}
+
+async fn score(State(state): State<Arc<ServiceState>>, Json(request): Json<ScoreRequest>) -> Result<Json<Value>, ApiError> {
+    let (texts, batch) = request_texts(&state, request)?;
+
+    let texts: Vec<&str> = texts.iter().map(|text| text.as_str()).collect();
+    let results: Vec<ScoreResult> = state.detector.score(&texts).await.into_iter().zip(texts.iter()).map(|(result, text)| {
+        let signals = SignalFeatures::extract(text);
+        match result {
+            Ok(score) => ScoreResult {
+                score: Some(score.score),
+                verdict: if score.fraud { "fraud" } else { "ham" },
+                novelty: score.novelty,
+                out_of_distribution: score.out_of_distribution,
+                prediction_set: score.prediction_set,
+                categories: score.categories,
+                error: None,
+                signals,
+            },
+            Err(err) => ScoreResult { score: None, verdict: "error", novelty: None, out_of_distribution: false, prediction_set: None, categories: None, error: Some(err.to_string()), signals },
+        }
+    }).collect();
+
+    batch_response(results, batch)
+}
+
+async fn explain(State(state): State<Arc<ServiceState>>, Json(request): Json<ExplainRequest>) -> Result<Json<Value>, ApiError> {
+    if let Some(k) = request.k {
+        if k == 0 || k > MAX_EXPLAIN_NEIGHBORS {
+            return Err(ApiError(StatusCode::UNPROCESSABLE_ENTITY, format!("k must be between 1 and {}", MAX_EXPLAIN_NEIGHBORS)));
+        }
+    }
+    let (texts, batch) = request_texts(&state, request.texts)?;
+
+    let texts: Vec<&str> = texts.iter().map(|text| text.as_str()).collect();
+    let results: Vec<Value> = state.detector.explain(&texts, request.k).await.into_iter().map(|result| match result {
+        Ok(Explanation { score, neighbors }) => json!({
+            "score": score.score,
+            "verdict": if score.fraud { "fraud" } else { "ham" },
+            "novelty": score.novelty,
+            "out_of_distribution": score.out_of_distribution,
+            "prediction_set": score.prediction_set,
+            "categories": score.categories,
+            "neighbors": neighbors,
+            "error": null,
+        }),
+        Err(err) => json!({ "score": null, "verdict": "error", "novelty": null, "out_of_distribution": false, "prediction_set": null, "categories": null, "neighbors": [], "error": err.to_string() }),
+    }).collect();
+
+    batch_response(results, batch)
+}
+
+async fn attribute(State(state): State<Arc<ServiceState>>, Json(request): Json<AttributeRequest>) -> Result<Json<Attribution>, ApiError> {
+    let segmentation = request.segmentation.unwrap_or(Segmentation::Sentences);
+    if let Segmentation::Words { size: 0 } = segmentation {
+        return Err(ApiError(StatusCode::UNPROCESSABLE_ENTITY, "size must be at least 1".to_string()));
+    }
+    let attribution = state.detector.attribute(&request.text, segmentation).await
+        .map_err(|err| ApiError(StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    Ok(Json(attribution))
+}
+
+async fn model(State(state): State<Arc<ServiceState>>) -> Json<Value> {
+    let model = state.detector.model();
+    Json(json!({
+        "path": model.path,
+        "loaded_at": model.loaded_at,
+        "threshold": state.detector.threshold,
+        "novelty_threshold": state.detector.novelty_threshold,
+        "novelty_calibrated": model.bundle.novelty.is_some(),
+        "categories": model.bundle.categories.as_ref().map(|categories| &categories.categories),
+        "error_level": state.detector.error_level.or(model.bundle.conformal.as_ref().map(|conformal| conformal.error_level)),
+        "embedding_provider": state.detector.provider().name(),
+        "metadata": model.metadata(),
+    }))
+}
+
+fn authorize(state: &ServiceState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let authorized = match (&state.config.admin_token, headers.get(AUTHORIZATION).and_then(|value| value.to_str().ok())) {
+        (Some(token), Some(value)) => value.strip_prefix("Bearer ") == Some(token.as_str()),
+        _ => false,
+    };
+    if !authorized {
+        return Err(ApiError(StatusCode::UNAUTHORIZED, "invalid admin token".to_string()));
+    }
+    Ok(())
+}
+
+async fn reload(State(state): State<Arc<ServiceState>>, headers: HeaderMap, body: Bytes) -> Result<Json<Value>, ApiError> {
+    authorize(&state, &headers)?;
+    let request: ReloadRequest = if body.is_empty() {
+        ReloadRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|err| ApiError(StatusCode::BAD_REQUEST, err.to_string()))?
+    };
+
+    match reload_model(Arc::clone(&state), request.path).await {
+        Ok(model) => {
+            info!(path = model.path.as_str(), "reloaded model");
+            Ok(Json(json!({ "path": model.path, "loaded_at": model.loaded_at, "metadata": model.metadata() })))
+        },
+        Err(err) => {
+            warn!(error = %err, "model reload failed, keeping the current model");
+            Err(ApiError(StatusCode::UNPROCESSABLE_ENTITY, format!("reload failed, keeping the current model: {}", err)))
+        },
+    }
+}
+
+async fn feedback(State(state): State<Arc<ServiceState>>, headers: HeaderMap, body: Bytes) -> Result<Json<Value>, ApiError> {
+    authorize(&state, &headers)?;
+    let request: FeedbackRequest = serde_json::from_slice(&body).map_err(|err| ApiError(StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    match add_feedback(Arc::clone(&state), request).await {
+        Ok((entry, model)) => {
+            info!(id = entry.id().as_str(), label = entry.label, "added feedback");
+            Ok(Json(json!({
+                "id": entry.id(),
+                "label": entry.label,
+                "training_count": model.metadata().training_count,
+                "feedback_count": model.metadata().feedback_count,
+            })))
+        },
+        Err(err) => {
+            warn!(error = %err, "feedback rejected");
+            Err(ApiError(StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))
+        },
+    }
+}
+
+async fn metrics() -> Result<([(HeaderName, &'static str); 1], String), ApiError> {
+    let body = crate::metrics::render().map_err(|err| ApiError(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(([(CONTENT_TYPE, "text/plain; version=0.0.4")], body))
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+async fn readyz(State(state): State<Arc<ServiceState>>) -> (StatusCode, Json<Value>) {
+    let embedding_backend = match tokio::time::timeout(READINESS_TIMEOUT, state.detector.provider().health()).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    };
+    let model_loaded = state.detector.model().is_loaded();
+    let status = if model_loaded && embedding_backend.is_ok() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(json!({
+        "model_loaded": model_loaded,
+        "embedding_backend": embedding_backend.is_ok(),
+        "error": embedding_backend.err(),
+    })))
+}
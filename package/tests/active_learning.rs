@@ -0,0 +1,41 @@
+//! Importing labeling batches: only new texts long enough to be trained on are added, in the layout
+//! the dataset is registered with.
+mod support;
+
+use rust_bert_fraud_detection_tools::build::active_learning::import_labeled_batch;
+use rust_bert_fraud_detection_tools::build::data::DatasetManifest;
+
+fn temp_path(name: &str) -> String {
+    support::temp_path("active_learning", name)
+}
+
+#[test]
+fn labeled_batches_are_imported_with_the_registered_columns() {
+    let (manifest_path, dataset_path, batch_path) = (temp_path("manifest.json"), temp_path("reviewed.csv"), temp_path("batch.csv"));
+    std::fs::write(&dataset_path, "label,text\n1,Claim the airdrop of your tokens right here\n").unwrap();
+    std::fs::write(&manifest_path, format!(r#"{{"datasets": [{{"path": "{}", "text_column": 1, "label_column": 0}}]}}"#, dataset_path)).unwrap();
+    std::fs::write(&batch_path, "id,text,score,label\n\
+        a,Claim the airdrop of your tokens right here,0.5,spam\n\
+        b,Too short,0.5,ham\n\
+        c,Minutes of the last community call are up,0.4,ham\n\
+        d,Vote on the treasury proposal before Friday,0.5,\n\
+        e,Upgrade your wallet to the new contract now,0.6,maybe\n").unwrap();
+
+    let report = import_labeled_batch(&batch_path, &manifest_path, &dataset_path).unwrap();
+    assert_eq!((report.labeled_count, report.too_short_count, report.duplicate_count, report.added_count), (3, 1, 1, 1));
+    assert_eq!((report.unlabeled_count, report.invalid_count, report.registered), (1, 1, false));
+
+    // the new row is appended with the label first, as registered, and read back from the manifest
+    let contents = std::fs::read_to_string(&dataset_path).unwrap();
+    assert!(contents.ends_with("0,Minutes of the last community call are up\n"), "{}", contents);
+    let texts = DatasetManifest::load(&manifest_path).unwrap().read_texts().unwrap();
+    assert_eq!(texts.last().unwrap(), &("Minutes of the last community call are up".to_string(), 0.0));
+
+    // importing the batch again adds nothing
+    let report = import_labeled_batch(&batch_path, &manifest_path, &dataset_path).unwrap();
+    assert_eq!((report.duplicate_count, report.added_count), (2, 0));
+
+    for path in [manifest_path, dataset_path, batch_path] {
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,94 @@
+//! Batch predictions: every input row comes out, with an error instead of a score if it could not be scored.
+mod support;
+
+use std::sync::Arc;
+
+use rust_bert_fraud_detection_tools::batch::{predict_csv, predict_jsonl, BatchOptions, OUTPUT_COLUMNS};
+use rust_bert_fraud_detection_tools::build::classification::bundle::ModelBundle;
+use rust_bert_fraud_detection_tools::build::classification::categories::CategoryHead;
+use rust_bert_fraud_detection_tools::build::classification::novelty::DEFAULT_NOVELTY_THRESHOLD;
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::EmbeddingRecord;
+use rust_bert_fraud_detection_tools::build::normalization::NormalizationConfig;
+use serde_json::{json, Value};
+use support::{record, temp_path, LengthEmbedder};
+
+/// A KNN model over the text length: texts longer than 4 characters are fraud of the category "long",
+/// with a novelty and a conformal calibration.
+fn options(test: &str) -> BatchOptions {
+    let model_path = temp_path("batch", &format!("{}.bin", test));
+    let records: Vec<EmbeddingRecord> = (1..=8).map(|length| EmbeddingRecord {
+        categories: if length > 4 { vec!["long".to_string()] } else { Vec::new() },
+        ..record(&"x".repeat(length), (length > 4) as u8 as f32, vec![length as f32, 1.0])
+    }).collect();
+    let mut bundle = ModelBundle::fit_records(&records, 3, &NormalizationConfig::default()).unwrap();
+    bundle.calibrate(&records, 0.2).unwrap();
+    bundle.fit_categories(CategoryHead::Knn).unwrap();
+    bundle.save(&model_path).unwrap();
+    BatchOptions {
+        model_path,
+        threshold: 0.5,
+        novelty_threshold: DEFAULT_NOVELTY_THRESHOLD,
+        error_level: None,
+        batch_size: 2,
+        provider: Some(Arc::new(LengthEmbedder)),
+    }
+}
+
+#[tokio::test]
+async fn a_malformed_csv_row_does_not_abort_the_batch() {
+    let options = options("csv");
+    let input = "id,text\n1,ab\n2,too,many,fields\n3,abcdefgh\n4,\n";
+    let mut output = Vec::new();
+    let summary = predict_csv(input.as_bytes(), &mut output, "text", &options).await.unwrap();
+    assert_eq!((summary.scored_count, summary.fraud_count, summary.failed_count), (2, 1, 2));
+
+    let mut reader = csv::Reader::from_reader(output.as_slice());
+    let headers = reader.headers().unwrap().clone();
+    assert_eq!(headers, ["id", "text"].into_iter().chain(OUTPUT_COLUMNS).collect::<Vec<&str>>());
+    let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+    let column = |row: usize, name: &str| rows[row][headers.iter().position(|header| header == name).unwrap()].to_string();
+    let verdicts: Vec<(String, String)> = (0..rows.len()).map(|row| (column(row, "id"), column(row, "verdict"))).collect();
+    assert_eq!(verdicts, [("1", "ham"), ("", "error"), ("3", "fraud"), ("4", "error")].map(|(id, verdict)| (id.to_string(), verdict.to_string())));
+    assert!(column(1, "error").starts_with("invalid record"), "{:?}", rows[1]);
+
+    // the scored rows carry the novelty, prediction set, categories and signals, failed rows leave them empty
+    assert!(column(0, "novelty").parse::<f32>().is_ok(), "{:?}", rows[0]);
+    assert_eq!(column(0, "out_of_distribution"), "false");
+    assert!(serde_json::from_str::<Vec<String>>(&column(2, "prediction_set")).unwrap().contains(&"fraud".to_string()));
+    let categories: Value = serde_json::from_str(&column(2, "categories")).unwrap();
+    assert!(categories["long"].as_f64().unwrap() > 0.5, "{}", categories);
+    let signals: Value = serde_json::from_str(&column(2, "signals")).unwrap();
+    assert_eq!(signals["url_count"], json!(0));
+    for name in ["score", "novelty", "out_of_distribution", "prediction_set", "categories", "signals"] {
+        assert_eq!(column(1, name), "", "{}", name);
+    }
+    let _ = std::fs::remove_file(options.model_path);
+}
+
+#[tokio::test]
+async fn jsonl_rows_that_are_not_objects_keep_their_input() {
+    let options = options("jsonl");
+    let input = "{\"id\": 1, \"text\": \"abcdefgh\"}\nnot json\n[1, 2]\n{\"id\": 4}\n{\"id\": 5, \"text\": \"visit http://example.com today\"}\n";
+    let mut output = Vec::new();
+    predict_jsonl(input.as_bytes(), &mut output, "text", &options).await.unwrap();
+
+    let rows: Vec<Value> = String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(rows.len(), 5);
+    assert_eq!((&rows[0]["id"], &rows[0]["verdict"]), (&json!(1), &json!("fraud")));
+    assert_eq!((&rows[1]["input"], &rows[1]["verdict"]), (&json!("not json"), &json!("error")));
+    assert!(rows[1]["error"].as_str().unwrap().starts_with("invalid JSON"));
+    assert_eq!(rows[2]["input"], json!([1, 2]));
+    assert_eq!((&rows[3]["id"], rows[3].get("input")), (&json!(4), None));
+    assert!(rows[3]["error"].as_str().unwrap().contains("missing string field 'text'"));
+
+    // the same fields as the scoring service, as typed values
+    assert!(rows[0]["novelty"].is_number() && rows[0]["error"].is_null());
+    assert_eq!(rows[0]["out_of_distribution"], json!(false));
+    assert!(rows[0]["prediction_set"].as_array().unwrap().contains(&json!("fraud")));
+    assert!(rows[0]["categories"]["long"].as_f64().unwrap() > 0.5);
+    // a text far longer than any training example is out of distribution
+    assert_eq!((&rows[4]["verdict"], &rows[4]["out_of_distribution"]), (&json!("fraud"), &json!(true)));
+    assert_eq!(rows[4]["signals"]["url_count"], json!(1));
+    assert!(rows[3]["score"].is_null() && rows[3]["signals"].is_null() && rows[3]["prediction_set"].is_null());
+    let _ = std::fs::remove_file(options.model_path);
+}
@@ -0,0 +1,83 @@
+//! Spam campaigns: clustering flagged texts, reporting the campaigns and assigning new texts to them.
+mod support;
+
+use rust_bert_fraud_detection_tools::build::campaigns::{cluster_campaigns, flagged_row, format_timestamp, parse_timestamp, CampaignConfig, FlaggedText};
+use rust_bert_fraud_detection_tools::build::normalization::NormalizationConfig;
+use serde_json::json;
+use support::temp_path;
+
+fn flagged(text: &str, embedding: Vec<f32>, seen_at: u64) -> FlaggedText {
+    FlaggedText { text: text.to_string(), embedding, seen_at }
+}
+
+/// Two waves along the first two axes (one with a repeated text), and a lone text along the third.
+fn flagged_texts() -> Vec<FlaggedText> {
+    let mut texts: Vec<FlaggedText> = (0..5).map(|index| {
+        flagged(&format!("airdrop variant {}", index), vec![1.0, 0.02 * index as f32, 0.0], 1_000 + index as u64)
+    }).collect();
+    texts.extend((0..3).map(|index| flagged(&format!("upgrade variant {}", index), vec![0.03 * index as f32, 2.0, 0.0], 5_000 - index as u64)));
+    texts.push(flagged("upgrade variant 0", vec![0.0, 2.0, 0.0], 6_000));
+    texts.push(flagged("a lone scam", vec![0.0, 0.0, 1.0], 3_000));
+    texts
+}
+
+#[test]
+fn campaigns_of_flagged_texts() {
+    let index = cluster_campaigns(&flagged_texts(), &CampaignConfig { distance: 0.2, min_size: 3 }, &NormalizationConfig::default()).unwrap();
+    assert_eq!((index.flagged_count, index.unclustered_count), (10, 1));
+    let summary: Vec<(usize, usize, usize, u64, u64)> = index.campaigns.iter()
+        .map(|campaign| (campaign.id, campaign.size, campaign.distinct_count, campaign.first_seen, campaign.last_seen))
+        .collect();
+    assert_eq!(summary, vec![(1, 5, 5, 1_000, 1_004), (2, 4, 3, 4_998, 6_000)]);
+    // the text nearest the centroid of the first wave is the one in its middle
+    assert_eq!(index.campaigns[0].representatives[0], "airdrop variant 2");
+    assert_eq!(index.campaigns[1].representatives.len(), 3);
+
+    // a tighter cut-off splits the waves into smaller clusters
+    let tight = cluster_campaigns(&flagged_texts(), &CampaignConfig { distance: 1e-4, min_size: 2 }, &NormalizationConfig::default()).unwrap();
+    assert_eq!(tight.campaigns.iter().map(|campaign| campaign.size).collect::<Vec<usize>>(), vec![2]);
+    assert!(cluster_campaigns(&flagged_texts(), &CampaignConfig { distance: 0.0, min_size: 2 }, &NormalizationConfig::default()).is_err());
+}
+
+#[test]
+fn new_texts_are_assigned_to_the_nearest_campaign() {
+    let mut index = cluster_campaigns(&flagged_texts(), &CampaignConfig::default(), &NormalizationConfig::default()).unwrap();
+    let path = temp_path("campaigns", "campaigns.json");
+    index.save(&path).unwrap();
+    index = rust_bert_fraud_detection_tools::build::campaigns::CampaignIndex::load(&path).unwrap();
+    let _ = std::fs::remove_file(path);
+
+    assert_eq!(index.assign(&[2.0, 0.1, 0.0]).unwrap().unwrap().id, 1);
+    assert_eq!(index.assign(&[0.1, 1.0, 0.05]).unwrap().unwrap().id, 2);
+    assert!(index.assign(&[0.0, 0.0, 1.0]).unwrap().is_none());
+    assert!(index.assign(&[1.0, 0.0]).is_err());
+
+    index.record(2, 9_000);
+    index.record(2, 100);
+    let campaign = &index.campaigns[1];
+    assert_eq!((campaign.size, campaign.first_seen, campaign.last_seen), (6, 100, 9_000));
+}
+
+#[test]
+fn flagged_rows_and_timestamps() {
+    let row = json!({"message": "Claim now", "score": 0.93, "verdict": "fraud", "date": "2024-03-01T12:00:00+01:00"});
+    let flagged = flagged_row(&row, "message", Some("date"), 0.5).unwrap().unwrap();
+    assert_eq!((flagged.text.as_str(), flagged.seen_at), ("Claim now", Some(1_709_290_800)));
+
+    assert!(flagged_row(&json!({"message": "Hi", "score": 0.1}), "message", None, 0.5).unwrap().is_none());
+    assert!(flagged_row(&json!({"message": "Hi", "score": null, "verdict": "error"}), "message", None, 0.5).unwrap().is_none());
+    assert_eq!(flagged_row(&json!({"message": "Hi", "date": 42}), "message", Some("date"), 0.5).unwrap().unwrap().seen_at, Some(42));
+    assert!(flagged_row(&json!({"text": "Hi"}), "message", None, 0.5).is_err());
+
+    assert_eq!(parse_timestamp("1970-01-02").unwrap(), 86_400);
+    assert_eq!(parse_timestamp("2000-02-29 23:59:59Z").unwrap(), 951_868_799);
+    assert_eq!(format_timestamp(951_868_799), "2000-02-29T23:59:59Z");
+    assert!(parse_timestamp("yesterday").is_err());
+    assert!(parse_timestamp("1969-12-31").is_err());
+    for timestamp in ["2024-03-01T11:00:00Z", "2024-03-01t11:00:00.250z", "2024-03-01 12:00:00+0100", "2024-03-01T11:00", "2024-03-01 06:00:00-05:00"] {
+        assert_eq!(parse_timestamp(timestamp).unwrap(), 1_709_290_800, "{}", timestamp);
+    }
+    for timestamp in ["2023-02-29", "2024-03-01T24:00:00Z", "2024-13-01", "1709290800.5"] {
+        assert!(parse_timestamp(timestamp).is_err(), "{}", timestamp);
+    }
+}
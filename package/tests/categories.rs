@@ -0,0 +1,89 @@
+//! Fraud categories: reading category columns from the manifest, storing them with the embeddings
+//! and predicting them with KNN and linear heads.
+mod support;
+
+use rust_bert_fraud_detection_tools::build::classification::bundle::ModelBundle;
+use rust_bert_fraud_detection_tools::build::classification::categories::{category_metrics, CategoryHead};
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::{read_embedding_records, write_columnar_embeddings, EmbeddingRecord, RecordFilter};
+use rust_bert_fraud_detection_tools::build::data::DatasetManifest;
+use rust_bert_fraud_detection_tools::build::normalization::NormalizationConfig;
+use support::record;
+
+fn temp_path(name: &str) -> String {
+    support::temp_path("categories", name)
+}
+
+#[test]
+fn manifest_category_columns() {
+    let dataset = temp_path("dataset.csv");
+    std::fs::write(&dataset, "text,label,category,is_phishing,is_marketing\n\
+        Claim the airdrop of your tokens right here,1,airdrop;phishing,1,0\n\
+        Upgrade your wallet to the new contract now,1,fake_upgrade,yes,0\n\
+        Best yield farming newsletter in the space,1,,0,true\n\
+        Minutes of the last community call are up,0,,0,0\n").unwrap();
+    let manifest_path = temp_path("manifest.json");
+    std::fs::write(&manifest_path, format!(r#"{{"datasets": [{{"path": "{}", "text_column": 0, "label_column": 1,
+        "category_column": 2, "category_columns": {{"phishing": 3, "marketing": 4}}}}]}}"#, dataset)).unwrap();
+
+    let rows = DatasetManifest::load(&manifest_path).unwrap().read_rows().unwrap();
+    let categories: Vec<Vec<&str>> = rows.iter().map(|row| row.categories.iter().map(String::as_str).collect()).collect();
+    assert_eq!(categories, vec![vec!["airdrop", "phishing"], vec!["fake_upgrade", "phishing"], vec!["marketing"], vec![]]);
+    // manifests without category columns read as before
+    assert_eq!(DatasetManifest::from_paths(&[&dataset]).read().unwrap().len(), 4);
+
+    let _ = std::fs::remove_file(dataset);
+    let _ = std::fs::remove_file(manifest_path);
+}
+
+/// Spam in two categories (and a few in both) along the first two axes, ham around the origin.
+fn categorized_records() -> Vec<EmbeddingRecord> {
+    (0..90).map(|index| {
+        let jitter = (index % 10) as f32 * 0.02;
+        let (embedding, categories): (Vec<f32>, Vec<&str>) = match index % 3 {
+            0 => (vec![jitter, jitter], vec![]),
+            1 => (vec![2.0 + jitter, jitter], vec!["airdrop"]),
+            _ if index % 9 == 2 => (vec![2.0 + jitter, 2.0 + jitter], vec!["airdrop", "fake_upgrade"]),
+            _ => (vec![jitter, 2.0 + jitter], vec!["fake_upgrade"]),
+        };
+        EmbeddingRecord {
+            categories: categories.iter().map(|category| category.to_string()).collect(),
+            ..record(&format!("example {}", index), if categories.is_empty() { 0.0 } else { 1.0 }, embedding)
+        }
+    }).collect()
+}
+
+#[test]
+fn category_heads() {
+    let records = categorized_records();
+    let probes = [vec![0.05, 0.05], vec![2.05, 0.05], vec![0.05, 2.05]];
+    let truth = vec![vec![], vec!["airdrop".to_string()], vec!["fake_upgrade".to_string()]];
+
+    for head in [CategoryHead::Knn, CategoryHead::Linear] {
+        let mut bundle = ModelBundle::fit_records(&records, 3, &NormalizationConfig::default()).unwrap();
+        assert!(bundle.category_probabilities(&probes[0]).unwrap().is_none());
+        bundle.fit_categories(head).unwrap();
+
+        let model = bundle.categories.as_ref().unwrap();
+        assert_eq!(model.categories, vec!["airdrop", "fake_upgrade"]);
+        assert!(model.multi_label);
+
+        let predictions: Vec<_> = probes.iter().map(|probe| bundle.category_probabilities(probe).unwrap().unwrap()).collect();
+        assert!(predictions[0].values().all(|probability| *probability < 0.5), "{:?} {:?}", head, predictions);
+        assert!(predictions[1]["airdrop"] > 0.5 && predictions[1]["fake_upgrade"] < 0.5, "{:?} {:?}", head, predictions);
+        assert!(predictions[2]["fake_upgrade"] > 0.5 && predictions[2]["airdrop"] < 0.5, "{:?} {:?}", head, predictions);
+
+        let metrics = category_metrics(&model.categories, &predictions, &truth);
+        assert!(metrics.iter().all(|metrics| metrics.support == 1 && metrics.f_score == 1.0));
+        assert!(bundle.category_probabilities(&[1.0, 1.0, 1.0]).is_err());
+    }
+}
+
+#[test]
+fn columnar_files_keep_categories() {
+    let path = temp_path("embeddings.bin");
+    let records = categorized_records();
+    write_columnar_embeddings(&path, records.clone().into_iter()).unwrap();
+    let read: Vec<EmbeddingRecord> = read_embedding_records(&path, RecordFilter::default()).unwrap().map(Result::unwrap).collect();
+    assert_eq!(read, records);
+    let _ = std::fs::remove_file(path);
+}
@@ -0,0 +1,70 @@
+//! Split-conformal prediction sets: quantiles, set construction and empirical coverage.
+mod support;
+
+use rust_bert_fraud_detection_tools::build::classification::bundle::ModelBundle;
+use rust_bert_fraud_detection_tools::build::classification::conformal::{ConformalCalibration, ConformalLabel};
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::EmbeddingRecord;
+use rust_bert_fraud_detection_tools::build::normalization::NormalizationConfig;
+use support::record;
+
+/// Deterministic scores in [0, 1] that lean towards the label, wrong for about a fifth of the examples.
+fn noisy_scores(count: usize, offset: usize) -> (Vec<f32>, Vec<f32>) {
+    (0..count).map(|index| {
+        let label = if (index + offset).is_multiple_of(3) { 1.0 } else { 0.0 };
+        let noise = ((index + offset) * 7919 % 1000) as f32 / 1000.0;
+        let score = if label == 1.0 { 1.0 - noise * 0.6 } else { noise * 0.6 };
+        (score, label)
+    }).unzip()
+}
+
+#[test]
+fn quantile_and_prediction_sets() {
+    let scores: Vec<f32> = (0..99).map(|index| index as f32 / 100.0).collect();
+    let labels = vec![0.0; 99];
+    let calibration = ConformalCalibration::fit(&scores, &labels, 0.1).unwrap();
+    // ceil((99 + 1) * 0.9) = 90th smallest nonconformity
+    assert_eq!(calibration.quantile(0.1), 0.89);
+    // 99 examples can not guarantee an error level below 1 / 100
+    assert_eq!(calibration.quantile(0.005), f32::INFINITY);
+
+    assert_eq!(calibration.prediction_set(0.05, 0.1), vec![ConformalLabel::Ham]);
+    assert_eq!(calibration.prediction_set(0.5, 0.1), vec![ConformalLabel::Fraud, ConformalLabel::Ham]);
+    assert_eq!(calibration.prediction_set(0.95, 0.1), vec![ConformalLabel::Fraud]);
+    assert_eq!(calibration.prediction_set(0.5, 0.005).len(), 2);
+
+    assert!(ConformalCalibration::fit(&scores, &labels, 0.0).is_err());
+    assert!(ConformalCalibration::fit(&[], &[], 0.1).is_err());
+}
+
+#[test]
+fn coverage_holds_on_exchangeable_data() {
+    let (calibration_scores, calibration_labels) = noisy_scores(500, 0);
+    let (test_scores, test_labels) = noisy_scores(2000, 500);
+    let calibration = ConformalCalibration::fit(&calibration_scores, &calibration_labels, 0.1).unwrap();
+
+    let reports = calibration.coverage_reports(&test_scores, &test_labels);
+    assert_eq!(reports.iter().map(|report| report.error_level).collect::<Vec<f32>>(), vec![0.01, 0.05, 0.1, 0.2]);
+    for report in &reports {
+        assert_eq!(report.single_count + report.both_count + report.empty_count, 2000);
+        assert!(report.coverage >= 1.0 - report.error_level - 0.03, "{:?}", report);
+    }
+    // a lower error level never gives smaller sets
+    assert!(reports[0].both_count >= reports[3].both_count);
+}
+
+#[test]
+fn bundles_keep_their_calibration() {
+    let example = |index: usize| record(&format!("example {}", index), (index % 2) as f32, vec![(index % 2) as f32 * 5.0 + index as f32 * 0.01, 0.0]);
+    let training: Vec<EmbeddingRecord> = (0..40).map(example).collect();
+    let held_out: Vec<EmbeddingRecord> = (40..60).map(example).collect();
+
+    let mut bundle = ModelBundle::fit_records(&training, 3, &NormalizationConfig::default()).unwrap();
+    assert!(bundle.conformal.is_none());
+    bundle.calibrate(&held_out, 0.1).unwrap();
+    let calibration = bundle.conformal.as_ref().unwrap();
+    assert_eq!(calibration.nonconformity.len(), 20);
+    assert_eq!(calibration.prediction_set(1.0, 0.1), vec![ConformalLabel::Fraud]);
+
+    let updated = bundle.with_feedback(&[bundle.examples[0].clone()]).unwrap().compacted().unwrap();
+    assert_eq!(updated.conformal.unwrap().nonconformity, calibration.nonconformity);
+}
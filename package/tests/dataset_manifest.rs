@@ -0,0 +1,41 @@
+//! Dataset manifests: rows are keyed by the path of their dataset relative to the manifest.
+mod support;
+
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::{EmbeddingRecord, RecordFilter};
+use rust_bert_fraud_detection_tools::build::data::DatasetManifest;
+use rust_bert_fraud_detection_tools::build::language_model::embeddings::load_embedding_records;
+use support::record;
+
+#[test]
+fn datasets_with_the_same_file_name_keep_apart() {
+    let base = std::path::PathBuf::from(support::temp_path("dataset_manifest", "datasets"));
+    for (directory, text) in [("forum", "Claim the airdrop of your tokens right here"), ("chat", "Minutes of the last community call are up")] {
+        std::fs::create_dir_all(base.join(directory)).unwrap();
+        std::fs::write(base.join(directory).join("spam.csv"), format!("text,label\n{},1\n", text)).unwrap();
+    }
+    let absolute = base.join("chat").join("spam.csv");
+    let manifest_path = base.join("manifest.json");
+    std::fs::write(&manifest_path, format!(r#"{{"datasets": [{{"path": "./forum/spam.csv", "text_column": 0, "label_column": 1}},
+        {{"path": "{}", "text_column": 0, "label_column": 1}}]}}"#, absolute.display())).unwrap();
+
+    let rows = DatasetManifest::load(manifest_path.to_str().unwrap()).unwrap().read().unwrap();
+    let sources: Vec<&str> = rows.iter().map(|(source, _, _)| source.as_str()).collect();
+    assert_eq!(sources, vec!["forum/spam.csv", "chat/spam.csv"]);
+    // without a manifest the file name is the source
+    assert_eq!(DatasetManifest::from_paths(&[absolute.to_str().unwrap()]).read().unwrap()[0].0, "spam.csv");
+
+    // embeddings can be filtered by either
+    let embeddings_path = base.join("embeddings.jsonl");
+    let lines: Vec<String> = rows.iter().map(|(source, text, label)| serde_json::to_string(&EmbeddingRecord {
+        source: source.clone(),
+        ..record(text, *label, vec![1.0])
+    }).unwrap()).collect();
+    std::fs::write(&embeddings_path, lines.join("\n")).unwrap();
+    let read = |sources: &[&str]| load_embedding_records(embeddings_path.to_str().unwrap(), RecordFilter {
+        sources: Some(sources.iter().map(|source| source.to_string()).collect()),
+        ..Default::default()
+    }).unwrap().len();
+    assert_eq!((read(&["chat/spam.csv"]), read(&["spam.csv"]), read(&["forum"])), (1, 2, 0));
+
+    let _ = std::fs::remove_dir_all(base);
+}
@@ -0,0 +1,93 @@
+//! Duplicate detection: text normalization, MinHash similarity and duplicate-aware splits.
+mod support;
+
+use std::io::Write;
+
+use rust_bert_fraud_detection_tools::build::data::dedup::{deduplicate, duplicate_groups, estimated_jaccard, group_split, minhash_signature, normalize_text, split_records, DEFAULT_NEAR_DUPLICATE_THRESHOLD};
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::EmbeddingRecord;
+use rust_bert_fraud_detection_tools::build::language_model::embeddings::load_embedding_records_from_file;
+use support::{record, temp_path};
+
+const CAMPAIGN: &str = "Congratulations! Your wallet was selected for the community airdrop, claim 500 tokens before Friday at the official portal and keep them safe";
+
+/// A wave of variants drifting away from the original a little more each time, and unrelated texts.
+fn texts() -> Vec<String> {
+    let mut texts = vec![
+        CAMPAIGN.to_string(),
+        CAMPAIGN.replace("500", "750"),
+        CAMPAIGN.replace("500", "750").replace("Friday", "Sunday"),
+        CAMPAIGN.replace("500", "750").replace("Friday", "Sunday").replace("keep them safe", "stake them"),
+        format!("  {}!!", CAMPAIGN.to_uppercase()),
+    ];
+    texts.extend([
+        "Minutes of the last community call are up on the forum",
+        "Proposal to raise the validator set to 150 seats",
+        "Grant round three results and the budget for next quarter",
+        "Reminder: the chain upgrade is scheduled for block 4,200,000",
+        "Hi Bob, can you send me your machine learning homework?",
+        "Signalling proposal: fund a security audit of the bridge contracts",
+    ].map(str::to_string));
+    texts
+}
+
+#[test]
+fn normalization_and_minhash() {
+    assert_eq!(normalize_text("  Claim   NOW!!! www.Example.com "), "claim now www example com");
+    let original = minhash_signature(CAMPAIGN);
+    assert_eq!(original, minhash_signature(&CAMPAIGN.to_uppercase()));
+    // the true Jaccard similarities are 0.91 and 0
+    assert!(estimated_jaccard(&original, &minhash_signature(&CAMPAIGN.replace("500", "750"))) > 0.7);
+    assert!(estimated_jaccard(&original, &minhash_signature("Minutes of the last community call are up")) < 0.1);
+}
+
+#[test]
+fn near_duplicates_land_in_the_same_split() {
+    let texts = texts();
+    let refs: Vec<&str> = texts.iter().map(String::as_str).collect();
+    let groups = duplicate_groups(&refs, DEFAULT_NEAR_DUPLICATE_THRESHOLD);
+    assert!(groups[..5].iter().all(|group| *group == 0), "{:?}", groups);
+    assert!(groups[5..].iter().all(|group| *group != 0), "{:?}", groups);
+
+    for seed in 0..20 {
+        let (train, test) = group_split(&groups, 0.5, seed);
+        assert_eq!(train.len() + test.len(), texts.len());
+        let campaign_in_train = train.iter().filter(|index| **index < 5).count();
+        assert!(campaign_in_train == 0 || campaign_in_train == 5, "seed {}: {:?} {:?}", seed, train, test);
+        // the same seed gives the same split
+        assert_eq!(group_split(&groups, 0.5, seed), (train, test));
+    }
+
+    let dataset: Vec<(String, f32)> = texts.iter().map(|text| (text.clone(), 1.0)).collect();
+    let (deduplicated, report) = deduplicate(dataset, DEFAULT_NEAR_DUPLICATE_THRESHOLD);
+    assert_eq!(deduplicated.len(), texts.len() - 4);
+    assert_eq!((report.exact_duplicate_count, report.largest_group), (1, 5));
+}
+
+#[test]
+fn splits_are_reproducible() {
+    let records: Vec<EmbeddingRecord> = texts().iter().enumerate()
+        .map(|(index, text)| record(text, (index < 5) as u8 as f32, vec![index as f32]))
+        .collect();
+    let write = |name: &str, records: &[EmbeddingRecord]| {
+        let path = temp_path("dedup", name);
+        let mut file = std::fs::File::create(&path).unwrap();
+        for record in records {
+            writeln!(file, "{}", serde_json::to_string(record).unwrap()).unwrap();
+        }
+        path
+    };
+    let path = write("embeddings.jsonl", &records);
+    let reversed: Vec<EmbeddingRecord> = records.iter().rev().cloned().collect();
+    let reversed_path = write("reversed.jsonl", &reversed);
+
+    let test_texts = |path: &str, seed| -> Vec<String> {
+        split_records(load_embedding_records_from_file(path).unwrap(), 0.7, seed).1.into_iter().map(|record| record.text).collect()
+    };
+    assert!(!test_texts(&path, 42).is_empty());
+    assert_eq!(test_texts(&path, 42), test_texts(&path, 42));
+    // the order of the file does not matter
+    assert_eq!(test_texts(&path, 42), test_texts(&reversed_path, 42));
+    assert!((0..10).any(|seed| test_texts(&path, seed) != test_texts(&path, 42)));
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(reversed_path);
+}
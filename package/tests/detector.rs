@@ -0,0 +1,53 @@
+//! Scoring with the fraud detector: every item of a batch succeeds or fails on its own, and scores,
+//! novelty and explanations come from the same search of the training examples.
+mod support;
+
+use std::sync::Arc;
+
+use rust_bert_fraud_detection_tools::build::classification::bundle::ModelBundle;
+use rust_bert_fraud_detection_tools::build::normalization::NormalizationConfig;
+use rust_bert_fraud_detection_tools::detector::FraudDetector;
+use support::{record, temp_path, LengthEmbedder};
+
+fn detector() -> FraudDetector {
+    let records: Vec<_> = (1..=8).map(|length| record(&"x".repeat(length), (length > 4) as u8 as f32, vec![length as f32, 1.0])).collect();
+    FraudDetector::new(Arc::new(LengthEmbedder), ModelBundle::fit_records(&records, 3, &NormalizationConfig::default()).unwrap(), 0.5)
+}
+
+#[test]
+fn an_embedding_of_the_wrong_dimension_fails_only_its_item() {
+    let scores = detector().score_embeddings(vec![
+        Ok(vec![8.0, 1.0]),
+        Ok(vec![8.0, 1.0, 0.0]),
+        Err(anyhow::anyhow!("embedding failed")),
+        Ok(vec![1.0, 1.0]),
+    ]);
+    assert!(scores[0].as_ref().unwrap().fraud);
+    let err = scores[1].as_ref().unwrap_err().to_string();
+    assert!(err.contains("dimension 3 does not match the model (2)"), "{}", err);
+    assert!(scores[2].is_err());
+    assert!(!scores[3].as_ref().unwrap().fraud);
+}
+
+#[tokio::test]
+async fn explanations_agree_with_the_scores() {
+    let detector = detector();
+    let texts = ["xx", "xxxxxxx", "xxxxxxxxxxxxxxxxxxxx"];
+    let scores: Vec<_> = detector.score(&texts).await.into_iter().map(Result::unwrap).collect();
+    let explanations: Vec<_> = detector.explain(&texts, Some(5)).await.into_iter().map(Result::unwrap).collect();
+    for (score, explanation) in scores.iter().zip(&explanations) {
+        assert_eq!((score.score, score.novelty), (explanation.score.score, explanation.score.novelty));
+        assert_eq!(explanation.neighbors.len(), 5);
+        assert!(explanation.neighbors.windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+    }
+    assert_eq!(explanations[0].neighbors[0].text, "xx");
+    assert!(scores[2].out_of_distribution && !scores[1].out_of_distribution);
+
+    // the embeddings are stored once, with the training examples
+    let path = temp_path("detector", "model.bin");
+    detector.model().bundle.save(&path).unwrap();
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert!(saved.get("regressor").is_none());
+    assert_eq!(ModelBundle::load(&path).unwrap().predict(&[vec![7.0, 1.0]]).unwrap()[0], scores[1].score);
+    let _ = std::fs::remove_file(path);
+}
@@ -0,0 +1,87 @@
+//! Embeddings files: streaming the columnar format, rejecting corrupt files and invalid records and filtering records.
+mod support;
+
+use std::io::{Seek, SeekFrom, Write};
+
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::{read_embedding_records, write_columnar_embeddings, ColumnarRecordReader, EmbeddingRecord, RecordFilter};
+use support::record;
+
+fn temp_path(name: &str) -> String {
+    support::temp_path("embedding_dataset", name)
+}
+
+fn records() -> Vec<EmbeddingRecord> {
+    (0..50).map(|index| EmbeddingRecord {
+        source: if index < 20 { "a.csv" } else { "b.csv" }.to_string(),
+        ..record(&format!("text {}", index), (index % 2) as f32, vec![index as f32, 1.0, -1.0])
+    }).collect()
+}
+
+#[test]
+fn columnar_files_are_streamed_and_filtered() {
+    let path = temp_path("stream.bin");
+    write_columnar_embeddings(&path, records().into_iter()).unwrap();
+
+    let reader = ColumnarRecordReader::open(&path).unwrap();
+    assert_eq!((reader.row_count(), reader.dimension()), (50, 3));
+    assert_eq!(reader.map(Result::unwrap).collect::<Vec<EmbeddingRecord>>(), records());
+
+    let filter = RecordFilter { sources: Some(vec!["b.csv".to_string()]), spam: Some(true), ..Default::default() };
+    let read: Vec<EmbeddingRecord> = read_embedding_records(&path, filter).unwrap().map(Result::unwrap).collect();
+    assert_eq!(read.len(), 15);
+    assert!(read.iter().all(|record| record.source == "b.csv" && record.label == 1.0));
+
+    let sample = |seed| read_embedding_records(&path, RecordFilter { sample_rate: Some(0.5), seed, ..Default::default() }).unwrap()
+        .map(|record| record.unwrap().text).collect::<Vec<String>>();
+    assert_eq!(sample(7), sample(7));
+    assert!(sample(7).len() < 50);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn corrupt_columnar_files_are_rejected() {
+    let path = temp_path("corrupt.bin");
+    write_columnar_embeddings(&path, records().into_iter()).unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    // a row count far beyond the file size fails before anything is allocated
+    let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(8)).unwrap();
+    file.write_all(&u64::MAX.to_le_bytes()).unwrap();
+    let err = ColumnarRecordReader::open(&path).err().unwrap().to_string();
+    assert!(err.contains("truncated or corrupt"), "{}", err);
+
+    // cut off in the category ids and in the texts
+    for cut in [100, 400] {
+        write_columnar_embeddings(&path, records().into_iter()).unwrap();
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(size - cut).unwrap();
+        assert!(ColumnarRecordReader::open(&path).is_err(), "{}", cut);
+    }
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn invalid_records_are_rejected_in_both_formats() {
+    let mut invalid = records();
+    invalid[3].embedding[1] = f32::NAN;
+    invalid[5].label = 2.0;
+    let (jsonl_path, columnar_path) = (temp_path("invalid.jsonl"), temp_path("invalid.bin"));
+    // JSON has no NaN, serde writes it as null which fails to parse
+    let lines: Vec<String> = invalid.iter().map(|record| serde_json::to_string(record).unwrap()).collect();
+    std::fs::write(&jsonl_path, lines.join("\n")).unwrap();
+    write_columnar_embeddings(&columnar_path, invalid.into_iter()).unwrap();
+
+    for path in [&jsonl_path, &columnar_path] {
+        let results: Vec<_> = read_embedding_records(path, RecordFilter::default()).unwrap().collect();
+        assert_eq!(results.len(), 50, "{}", path);
+        let errors: Vec<(usize, String)> = results.iter().enumerate()
+            .filter_map(|(index, result)| result.as_ref().err().map(|err| (index, err.to_string())))
+            .collect();
+        assert_eq!(errors.iter().map(|(index, _)| *index).collect::<Vec<usize>>(), vec![3, 5], "{}: {:?}", path, errors);
+        assert!(errors[1].1.contains("label 2 is not within [0, 1]"), "{:?}", errors);
+    }
+    let err = read_embedding_records(&columnar_path, RecordFilter::default()).unwrap().nth(3).unwrap().unwrap_err().to_string();
+    assert_eq!(err, "row 4: embedding contains non-finite values");
+    let _ = std::fs::remove_file(jsonl_path);
+    let _ = std::fs::remove_file(columnar_path);
+}
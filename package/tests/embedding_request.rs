@@ -0,0 +1,175 @@
+mod support;
+
+use std::time::Duration;
+
+use rust_bert_fraud_detection_tools::build::language_model::embeddings::{llama_cpp_embedding, text_embedding_request, text_embedding_request_to, EmbeddingErrorKind, EmbeddingProvider, LlamaCppEmbeddingProvider};
+use rust_bert_fraud_detection_tools::metrics::embedding_failure_kind;
+use serde_json::{json, Value};
+use support::{deterministic_embedding, unreachable_endpoint, MockEmbeddingServer, MockResponse, DEFAULT_DIMENSION};
+
+#[tokio::test]
+async fn returns_the_embedding() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+    let client = reqwest::Client::new();
+
+    let embedding = text_embedding_request_to(&client, &server.embedding_endpoint(), "Claim your airdrop now!").await.unwrap();
+
+    assert_eq!(embedding, deterministic_embedding("Claim your airdrop now!", DEFAULT_DIMENSION));
+    assert_eq!(embedding.len(), DEFAULT_DIMENSION);
+    assert_eq!(server.request_count(), 1);
+}
+
+#[tokio::test]
+async fn equal_texts_get_equal_embeddings() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+    let client = reqwest::Client::new();
+
+    let first = text_embedding_request_to(&client, &server.embedding_endpoint(), "meeting at 4pm").await.unwrap();
+    let second = text_embedding_request_to(&client, &server.embedding_endpoint(), "meeting at 4pm").await.unwrap();
+    let other = text_embedding_request_to(&client, &server.embedding_endpoint(), "free tokens").await.unwrap();
+
+    assert_eq!(first, second);
+    assert_ne!(first, other);
+}
+
+#[tokio::test]
+async fn empty_text_is_rejected_before_sending() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+
+    let err = text_embedding_request_to(&reqwest::Client::new(), &server.embedding_endpoint(), "").await.unwrap_err();
+
+    assert!(err.to_string().contains("Empty string"), "{}", err);
+    assert_eq!(embedding_failure_kind(&err.into()), "empty_text");
+    assert_eq!(server.request_count(), 0);
+}
+
+#[tokio::test]
+async fn connection_failure() {
+    let endpoint = unreachable_endpoint().await;
+
+    let err = text_embedding_request_to(&reqwest::Client::new(), &endpoint, "hello").await.unwrap_err();
+
+    assert!(err.to_string().starts_with("Command execution failed"), "{}", err);
+    assert_eq!(embedding_failure_kind(&err.into()), "connection");
+}
+
+#[tokio::test]
+async fn error_status() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+    let client = reqwest::Client::new();
+
+    for status in [500, 503, 404] {
+        server.set_response(MockResponse::Status(status));
+        let err = text_embedding_request_to(&client, &server.embedding_endpoint(), "hello").await.unwrap_err();
+        assert!(err.to_string().starts_with("Got negative response status"), "{}", err);
+        assert!(err.to_string().contains(&status.to_string()), "{}", err);
+        assert_eq!(embedding_failure_kind(&err.into()), "status");
+    }
+}
+
+#[tokio::test]
+async fn recovers_after_injected_server_errors() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+    let client = reqwest::Client::new();
+    server.fail_next(2, 502);
+
+    assert!(text_embedding_request_to(&client, &server.embedding_endpoint(), "hello").await.is_err());
+    assert!(text_embedding_request_to(&client, &server.embedding_endpoint(), "hello").await.is_err());
+    assert!(text_embedding_request_to(&client, &server.embedding_endpoint(), "hello").await.is_ok());
+    assert_eq!(server.request_count(), 3);
+}
+
+#[tokio::test]
+async fn malformed_json() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+    server.set_response(MockResponse::MalformedJson);
+
+    let err = text_embedding_request_to(&reqwest::Client::new(), &server.embedding_endpoint(), "hello").await.unwrap_err();
+
+    assert!(err.to_string().starts_with("Response body is not valid json"), "{}", err);
+    assert_eq!(embedding_failure_kind(&err.into()), "invalid_response");
+}
+
+#[tokio::test]
+async fn response_without_embedding() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+    server.set_response(MockResponse::WrongSchema);
+
+    let err = text_embedding_request_to(&reqwest::Client::new(), &server.embedding_endpoint(), "hello").await.unwrap_err();
+
+    assert!(err.to_string().starts_with("Parsing failed"), "{}", err);
+    assert!(err.to_string().contains("model not loaded"), "{}", err);
+    assert_eq!(embedding_failure_kind(&err.into()), "invalid_response");
+}
+
+#[tokio::test]
+async fn latency_beyond_the_client_timeout_is_a_connection_failure() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+    server.set_latency(Duration::from_millis(300));
+
+    let patient = reqwest::Client::new();
+    assert!(text_embedding_request_to(&patient, &server.embedding_endpoint(), "hello").await.is_ok());
+
+    let impatient = reqwest::Client::builder().timeout(Duration::from_millis(50)).build().unwrap();
+    let err = text_embedding_request_to(&impatient, &server.embedding_endpoint(), "hello").await.unwrap_err();
+    assert!(err.to_string().starts_with("Command execution failed"), "{}", err);
+    assert_eq!(err.kind, EmbeddingErrorKind::Connection);
+
+    // the kind survives wrapping, as the providers do
+    let provider = LlamaCppEmbeddingProvider::new(&server.embedding_endpoint(), 512);
+    server.set_response(MockResponse::Status(503));
+    let err = provider.embed("hello").await.unwrap_err().context("scoring failed");
+    assert_eq!(embedding_failure_kind(&err), "status");
+    assert_eq!(embedding_failure_kind(&anyhow::anyhow!("Got negative response status")), "other");
+}
+
+#[tokio::test]
+async fn provider_truncates_to_the_context_size() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+    let provider = LlamaCppEmbeddingProvider::new(&server.embedding_endpoint(), 2);
+
+    let embedding = provider.embed("abcdefghijklmnop").await.unwrap();
+
+    assert_eq!(embedding, deterministic_embedding("abcdefgh", DEFAULT_DIMENSION));
+    assert!(provider.health().await.is_ok());
+    server.set_response(MockResponse::Status(500));
+    assert!(provider.health().await.is_err());
+}
+
+// The only test touching the environment, tests run in parallel within this binary.
+#[tokio::test]
+async fn endpoint_and_context_size_from_the_environment() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+
+    std::env::remove_var("DOCKER_EMBEDDING_ENDPOINT");
+    let err = text_embedding_request("hello").await.unwrap_err();
+    assert!(err.to_string().contains("DOCKER_EMBEDDING_ENDPOINT is not set"), "{}", err);
+    assert_eq!(err.kind, EmbeddingErrorKind::Configuration);
+
+    std::env::set_var("DOCKER_EMBEDDING_ENDPOINT", server.embedding_endpoint());
+    assert_eq!(text_embedding_request("hello").await.unwrap(), deterministic_embedding("hello", DEFAULT_DIMENSION));
+
+    std::env::remove_var("EMBEDDING_CONTEXT_SIZE");
+    let err = llama_cpp_embedding("hello").await.unwrap_err();
+    assert!(err.to_string().contains("EMBEDDING_CONTEXT_SIZE is not set"), "{}", err);
+
+    std::env::set_var("EMBEDDING_CONTEXT_SIZE", "1");
+    assert_eq!(llama_cpp_embedding("hello world").await.unwrap(), deterministic_embedding("hell", DEFAULT_DIMENSION));
+}
+
+#[tokio::test]
+async fn mock_speaks_the_openai_schema() {
+    let server = MockEmbeddingServer::start(8).await;
+
+    let response: Value = reqwest::Client::new()
+        .post(server.openai_endpoint())
+        .json(&json!({"model": "nomic-embed-text", "input": ["first", "second"]}))
+        .send().await.unwrap()
+        .json().await.unwrap();
+
+    assert_eq!(response["object"], "list");
+    assert_eq!(response["data"].as_array().unwrap().len(), 2);
+    assert_eq!(response["data"][1]["index"], 1);
+    let embedding: Vec<f32> = serde_json::from_value(response["data"][0]["embedding"].clone()).unwrap();
+    assert_eq!(embedding, deterministic_embedding("first", 8));
+}
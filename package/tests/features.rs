@@ -0,0 +1,42 @@
+//! Scam signals: crypto addresses need a valid checksum and phone numbers need separators,
+//! so hashes, long words, amounts and ids are not counted.
+use rust_bert_fraud_detection_tools::build::features::SignalFeatures;
+
+#[test]
+fn crypto_addresses_are_counted_if_their_checksum_is_valid() {
+    for address in [
+        "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+        "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+        "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+        "cosmos194c3vs4hy6cygqtz0j5lhtpj7hy9xra3x5yd8n",
+        "0xde0B295669a9FD93d5F28D9Ec85E40f4cb697BAe",
+    ] {
+        assert_eq!(SignalFeatures::extract(&format!("Send your tokens to {} today", address)).crypto_address_count, 1, "{}", address);
+    }
+    for text in [
+        "Send your tokens to 3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLz today",
+        "Send your tokens to bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdx today",
+        "1abcdefghijkmnopqrstuvwxyzABCDE is not an address",
+        "Commit 3b18e512dba79e4c8300dd08aeb37f8e728b8dad fixed it",
+        "cosmos194c3vs4hy6cygqtz0j5lhtpj7hy9xra3x5yd8q",
+    ] {
+        assert_eq!(SignalFeatures::extract(text).crypto_address_count, 0, "{}", text);
+    }
+}
+
+#[test]
+fn phone_numbers_need_separators_or_a_leading_plus() {
+    for number in ["+1 555 123 4567", "+4915112345678", "+49 151 12345678", "(555) 123-4567", "555-123-4567", "555.123.4567", "030 1234 5678"] {
+        assert_eq!(SignalFeatures::extract(&format!("Call {} to claim", number)).phone_number_count, 1, "{}", number);
+    }
+    for text in [
+        "Order 1234567890 has shipped",
+        "Block 170929080012 was finalized",
+        "Transfer 100000000000 tokens",
+        "Voting ends on 2024-03-01",
+        "The node runs on 192.168.100.200",
+        "Proposal 42 passed with 123 456 votes",
+    ] {
+        assert_eq!(SignalFeatures::extract(text).phone_number_count, 0, "{}", text);
+    }
+}
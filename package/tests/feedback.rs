@@ -0,0 +1,79 @@
+//! Feedback on a saved KNN model: logged entries change its predictions on load, and compaction
+//! folds them into the bundle without losing any.
+mod support;
+
+use std::sync::Arc;
+
+use rust_bert_fraud_detection_tools::build::classification::bundle::ModelBundle;
+use rust_bert_fraud_detection_tools::build::classification::feedback::{self, FeedbackEntry};
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::EmbeddingRecord;
+use rust_bert_fraud_detection_tools::build::language_model::embeddings::LlamaCppEmbeddingProvider;
+use rust_bert_fraud_detection_tools::build::normalization::NormalizationConfig;
+use rust_bert_fraud_detection_tools::detector::FraudDetector;
+use support::{deterministic_embedding, record, temp_path, MockEmbeddingServer, DEFAULT_DIMENSION};
+
+fn cleanup(model_path: &str) {
+    let _ = std::fs::remove_file(model_path);
+    let _ = std::fs::remove_file(feedback::log_path(model_path));
+}
+
+#[test]
+fn logged_feedback_is_replayed_and_compacted() {
+    let model_path = &temp_path("feedback", "model.bin");
+    cleanup(model_path);
+
+    let records = vec![
+        record("ham one", 0.0, vec![0.0, 0.0]),
+        record("ham two", 0.0, vec![0.1, 0.0]),
+        record("spam one", 1.0, vec![1.0, 1.0]),
+        record("spam two", 1.0, vec![0.9, 1.0]),
+    ];
+    ModelBundle::fit_records(&records, 1, &NormalizationConfig::default()).unwrap().save(model_path).unwrap();
+    let probes = vec![vec![0.0, 0.45], vec![0.4, 1.0]];
+    assert_eq!(feedback::load_bundle(model_path).unwrap().predict(&probes).unwrap(), vec![0.0, 1.0]);
+
+    // the same text twice keeps only the latest label
+    feedback::append(model_path, &FeedbackEntry::new("new campaign", 0.0, vec![0.0, 0.5]).unwrap()).unwrap();
+    feedback::append(model_path, &FeedbackEntry::new("new campaign", 1.0, vec![0.0, 0.5]).unwrap()).unwrap();
+    feedback::append(model_path, &FeedbackEntry::new("false positive", 0.0, vec![0.4, 1.0]).unwrap()).unwrap();
+    assert!(FeedbackEntry::new("out of range", 2.0, vec![0.0, 0.0]).is_err());
+
+    let loaded = feedback::load_bundle(model_path).unwrap();
+    assert_eq!((loaded.metadata.training_count, loaded.metadata.feedback_count), (6, 2));
+    assert_eq!(loaded.predict(&probes).unwrap(), vec![1.0, 0.0]);
+    // the bundle file itself is unchanged until compaction
+    assert_eq!(ModelBundle::load(model_path).unwrap().metadata.training_count, 4);
+
+    let report = feedback::compact(model_path).unwrap();
+    assert_eq!((report.entry_count, report.feedback_count, report.training_count), (3, 2, 6));
+    assert!(!std::path::Path::new(&feedback::log_path(model_path)).exists());
+    let compacted = ModelBundle::load(model_path).unwrap();
+    assert_eq!(compacted.metadata.feedback_count, 2);
+    assert_eq!(compacted.predict(&probes).unwrap(), vec![1.0, 0.0]);
+
+    // a log that does not fit the model is ignored instead of failing the load
+    feedback::append(model_path, &FeedbackEntry::new("wrong dimension", 1.0, vec![1.0, 1.0, 1.0]).unwrap()).unwrap();
+    assert_eq!(feedback::load_bundle(model_path).unwrap().metadata.training_count, 6);
+
+    cleanup(model_path);
+}
+
+#[tokio::test]
+async fn relabeling_a_training_text_flips_its_score() {
+    let server = MockEmbeddingServer::start(DEFAULT_DIMENSION).await;
+    let texts = ["Claim your airdrop now", "Meeting moved to 4pm", "Free tokens for every wallet", "Minutes of the last call"];
+    let records: Vec<EmbeddingRecord> = texts.iter().enumerate()
+        .map(|(index, text)| record(text, (index == 2) as u8 as f32, deterministic_embedding(text, DEFAULT_DIMENSION)))
+        .collect();
+    let bundle = ModelBundle::fit_records(&records, 1, &NormalizationConfig::default()).unwrap();
+    let detector = FraudDetector::new(Arc::new(LlamaCppEmbeddingProvider::new(&server.embedding_endpoint(), 512)), bundle, 0.5);
+    assert!(!detector.score(&[texts[0]]).await.remove(0).unwrap().fraud);
+
+    // the feedback replaces the training example with the same text instead of sitting next to it
+    let entry = detector.feedback_entry(texts[0], 1.0).await.unwrap();
+    let (_, model) = detector.add_feedback(entry).unwrap();
+    assert_eq!((model.metadata().training_count, model.metadata().feedback_count), (4, 1));
+    let relabeled = detector.score(&[texts[0]]).await.remove(0).unwrap();
+    assert!(relabeled.fraud);
+    assert_eq!(relabeled.score, 1.0);
+}
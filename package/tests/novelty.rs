@@ -0,0 +1,46 @@
+//! Novelty of inputs relative to the training examples of a KNN bundle.
+mod support;
+
+use rust_bert_fraud_detection_tools::build::classification::bundle::ModelBundle;
+use rust_bert_fraud_detection_tools::build::classification::novelty::DEFAULT_NOVELTY_THRESHOLD;
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::EmbeddingRecord;
+use rust_bert_fraud_detection_tools::build::normalization::NormalizationConfig;
+use support::record;
+
+/// Two clusters of 50 points on a grid with spacing 0.1, ham around the origin and spam around (5, 5).
+fn clustered_records() -> Vec<EmbeddingRecord> {
+    (0..100).map(|index| {
+        let (offset, label) = if index < 50 { (0.0, 0.0) } else { (5.0, 1.0) };
+        let (row, column) = ((index % 50) / 10, index % 10);
+        record(&format!("example {}", index), label, vec![offset + row as f32 * 0.1, offset + column as f32 * 0.1])
+    }).collect()
+}
+
+#[test]
+fn inputs_far_from_the_training_examples_are_novel() {
+    let bundle = ModelBundle::fit_records(&clustered_records(), 3, &NormalizationConfig::default()).unwrap();
+    let calibration = bundle.novelty.as_ref().unwrap();
+    assert_eq!((calibration.k, calibration.reference_distances.len()), (3, 100));
+
+    let typical = bundle.novelty(&[0.25, 0.25]).unwrap();
+    assert!(typical.score < 0.5, "{:?}", typical);
+    let between = bundle.novelty(&[2.5, 2.5]).unwrap();
+    assert_eq!(between.score, 1.0);
+    assert!(between.score >= DEFAULT_NOVELTY_THRESHOLD);
+    // the regressor still scores it confidently
+    assert!(bundle.predict(&[vec![2.5, 2.5]]).unwrap()[0].is_finite());
+
+    assert!(bundle.novelty(&[1.0, 1.0, 1.0]).is_none());
+
+    // feedback keeps the calibration until it is compacted, which refits it on the examples that are searched
+    let far = record("far away", 1.0, vec![2.5, 2.5]);
+    let updated = bundle.with_feedback(&[(&far).into()]).unwrap();
+    assert_eq!(updated.novelty.as_ref().unwrap().reference_distances, calibration.reference_distances);
+    assert!(updated.novelty(&[2.5, 2.5]).unwrap().distance < between.distance);
+    let compacted = updated.compacted().unwrap();
+    assert_eq!(compacted.novelty.as_ref().unwrap().reference_distances.len(), 101);
+    assert_ne!(compacted.novelty.unwrap().reference_distances, calibration.reference_distances);
+    // bundles without examples have no calibration
+    let bare = ModelBundle::fit(&[vec![0.0, 0.0], vec![1.0, 1.0]], &[0.0, 1.0], 1).unwrap();
+    assert!(bare.novelty(&[0.0, 0.0]).is_none());
+}
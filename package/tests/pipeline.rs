@@ -0,0 +1,110 @@
+//! End-to-end: read the fixture datasets, embed them with the mock embedding server, train, save,
+//! reload and score, so a change to any stage that breaks the pipeline fails here.
+mod support;
+
+use futures::StreamExt;
+use rust_bert_fraud_detection_tools::build::classification::{predict_knn_regression_model, test_knn_regression_model, update_knn_regression_model, update_random_forest_regression_model, update_random_forest_regression_model_from_records, predict_with_model, ModelType, KNN_DEFAULT_K};
+use rust_bert_fraud_detection_tools::build::classification::bundle::ModelBundle;
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::EmbeddingRecord;
+use rust_bert_fraud_detection_tools::build::normalization::NormalizationConfig;
+use rust_bert_fraud_detection_tools::build::data::read_datasets;
+use rust_bert_fraud_detection_tools::build::language_model::embeddings::extract_embeddings;
+use rust_bert_fraud_detection_tools::{fraud_probabilities_with_model, fraud_probability_results, fraud_probability_results_with_provider};
+use serde_json::Value;
+use support::{record, temp_path, LengthEmbedder, MockEmbeddingServer};
+
+const TRAIN_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tiny_spam_ham.csv");
+const TEST_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tiny_spam_ham_test.csv");
+const DIMENSION: usize = 256;
+
+async fn embed(dataset: Vec<(String, f32)>) -> (Vec<Vec<f32>>, Vec<f32>) {
+    let entries: Vec<Value> = extract_embeddings(dataset)
+        .map(|entry| entry.expect("embedding"))
+        .collect()
+        .await;
+    entries.iter().map(|entry| {
+        let embedding: Vec<f32> = serde_json::from_value(entry["embedding"].clone()).expect("embedding values");
+        (embedding, entry["label"].as_f64().expect("label") as f32)
+    }).unzip()
+}
+
+#[tokio::test]
+async fn train_save_reload_and_score() {
+    let server = MockEmbeddingServer::start(DIMENSION).await;
+    std::env::set_var("DOCKER_EMBEDDING_ENDPOINT", server.embedding_endpoint());
+    std::env::set_var("EMBEDDING_CONTEXT_SIZE", "512");
+    let model_path = &temp_path("pipeline", "model.bin");
+
+    // the row shorter than the minimum text length is dropped
+    let train = read_datasets(&[TRAIN_FIXTURE]).unwrap();
+    assert_eq!(train.len(), 20);
+    assert_eq!(train.iter().filter(|(_, label)| *label >= 0.5).count(), 10);
+
+    let (x_train, y_train) = embed(train).await;
+    assert_eq!(x_train.len(), 20);
+    assert!(x_train.iter().all(|embedding| embedding.len() == DIMENSION));
+    assert_eq!(server.request_count(), 20);
+    update_knn_regression_model(model_path, &x_train, &y_train, KNN_DEFAULT_K).unwrap();
+
+    let test = read_datasets(&[TEST_FIXTURE]).unwrap();
+    let texts: Vec<String> = test.iter().map(|(text, _)| text.clone()).collect();
+    let (x_test, y_test) = embed(test).await;
+
+    let metrics = test_knn_regression_model(model_path, &x_test, &y_test).unwrap();
+    let at_half = metrics.iter().find(|metrics| metrics.threshold == 0.5).unwrap();
+    assert_eq!((at_half.true_positive_count, at_half.false_positive_count, at_half.false_negative_count), (2, 0, 0));
+    assert_eq!(at_half.f_score, 1.0);
+
+    // scoring the raw texts goes through the same embeddings and the reloaded model
+    let text_refs: Vec<&str> = texts.iter().map(|text| text.as_str()).collect();
+    let scores = fraud_probabilities_with_model(&text_refs, model_path).await.unwrap();
+    assert_eq!(scores, predict_knn_regression_model(model_path, &x_test).unwrap());
+
+    let mut ranking: Vec<usize> = (0..scores.len()).collect();
+    ranking.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+    let ranked_labels: Vec<f32> = ranking.iter().map(|&index| y_test[index]).collect();
+    assert_eq!(ranked_labels, vec![1.0, 1.0, 0.0, 0.0]);
+
+    let results = fraud_probability_results(&text_refs, model_path).await;
+    assert_eq!(results.into_iter().map(|result| result.unwrap()).collect::<Vec<f32>>(), scores);
+
+    let _ = std::fs::remove_file(model_path);
+}
+
+#[tokio::test]
+async fn the_model_type_is_read_from_the_model_file() {
+    let (knn_path, forest_path) = (temp_path("pipeline", "knn.bin"), temp_path("pipeline", "forest.bin"));
+    let x: Vec<Vec<f32>> = (1..=8).map(|length| vec![length as f32, 1.0]).collect();
+    let y: Vec<f32> = (1..=8).map(|length| (length > 4) as u8 as f32).collect();
+    update_knn_regression_model(&knn_path, &x, &y, KNN_DEFAULT_K).unwrap();
+    update_random_forest_regression_model(&forest_path, &x, &y).unwrap();
+    assert_eq!(ModelType::detect(&knn_path).unwrap(), ModelType::KNN);
+    assert_eq!(ModelType::detect(&forest_path).unwrap(), ModelType::RandomForest);
+
+    // a text that can not be embedded fails on its own and does not shift the scores of the others
+    for model_path in [&knn_path, &forest_path] {
+        let results = fraud_probability_results_with_provider(&["a", "", "abcdefgh"], model_path, &LengthEmbedder).await;
+        assert!(results[0].is_ok() && results[2].is_ok(), "{}: {:?}", model_path, results);
+        assert!(results[1].as_ref().unwrap_err().to_string().contains("embedding failed"));
+    }
+    // embeddings of another dimension are an error, not a panic
+    let err = predict_with_model(&knn_path, &ModelType::KNN, &[vec![1.0, 1.0, 1.0]]).unwrap_err().to_string();
+    assert!(err.contains("dimension 3 does not match the model (2)"), "{}", err);
+    assert!(predict_with_model(&forest_path, &ModelType::RandomForest, &[vec![1.0, 1.0], vec![1.0]]).is_err());
+
+    // a random forest trained with signal features gets them appended to the embeddings of its inputs
+    let records: Vec<EmbeddingRecord> = x.iter().zip(y.iter())
+        .map(|(embedding, label)| record(&"x".repeat(embedding[0] as usize), *label, embedding.clone()))
+        .collect();
+    update_random_forest_regression_model_from_records(&forest_path, &records, true, &NormalizationConfig::default()).unwrap();
+    let results = fraud_probability_results_with_provider(&["Claim now!!! http://example.com"], &forest_path, &LengthEmbedder).await;
+    assert!(results[0].is_ok(), "{:?}", results);
+    let err = predict_with_model(&forest_path, &ModelType::RandomForest, &x).unwrap_err().to_string();
+    assert!(err.contains("signal features"), "{}", err);
+    // only KNN models can be served
+    let err = ModelBundle::load(&forest_path).err().unwrap().to_string();
+    assert!(err.contains("is a random forest model"), "{}", err);
+
+    let _ = std::fs::remove_file(knn_path);
+    let _ = std::fs::remove_file(forest_path);
+}
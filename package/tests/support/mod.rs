@@ -0,0 +1,220 @@
+//! Test support: a local stand-in for a llama.cpp embedding server, and fixture helpers.
+//!
+//! Speaks the llama.cpp `/embedding` schema (`{"content": ...}` -> `{"embedding": [...]}`) and the
+//! OpenAI `/v1/embeddings` schema, returns deterministic vectors derived from the text and can inject
+//! latency, error statuses and malformed responses.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use futures::future::BoxFuture;
+use rust_bert_fraud_detection_tools::build::data::embedding_dataset::EmbeddingRecord;
+use rust_bert_fraud_detection_tools::build::language_model::embeddings::EmbeddingProvider;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_DIMENSION: usize = 32;
+
+/// What the server answers to successful requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MockResponse {
+    /// Embeddings in the schema of the requested endpoint.
+    Embedding,
+    /// A body that is not JSON.
+    MalformedJson,
+    /// Valid JSON without an embedding.
+    WrongSchema,
+    /// An empty body with this status.
+    Status(u16),
+}
+
+struct MockConfig {
+    latency: Duration,
+    response: MockResponse,
+    /// The next `failures_left` requests get `failure_status`, before `response` applies again.
+    failures_left: usize,
+    failure_status: u16,
+}
+
+struct MockState {
+    dimension: usize,
+    config: Mutex<MockConfig>,
+    request_count: AtomicUsize,
+}
+
+pub struct MockEmbeddingServer {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    handle: JoinHandle<()>,
+}
+
+impl MockEmbeddingServer {
+    /// Starts a server on a free local port, returning embeddings of `dimension` values.
+    pub async fn start(dimension: usize) -> Self {
+        let state = Arc::new(MockState {
+            dimension,
+            config: Mutex::new(MockConfig { latency: Duration::ZERO, response: MockResponse::Embedding, failures_left: 0, failure_status: 500 }),
+            request_count: AtomicUsize::new(0),
+        });
+        let app = Router::new()
+            .route("/embedding", post(llama_cpp_embedding))
+            .route("/v1/embeddings", post(openai_embeddings))
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind mock embedding server");
+        let addr = listener.local_addr().expect("mock embedding server address");
+        let handle = tokio::spawn(async move {
+            axum::serve(listener, app).await.expect("mock embedding server");
+        });
+        MockEmbeddingServer { addr, state, handle }
+    }
+
+    /// The llama.cpp endpoint, what `DOCKER_EMBEDDING_ENDPOINT` points to.
+    pub fn embedding_endpoint(&self) -> String {
+        format!("http://{}/embedding", self.addr)
+    }
+
+    pub fn openai_endpoint(&self) -> String {
+        format!("http://{}/v1/embeddings", self.addr)
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.config().latency = latency;
+    }
+
+    pub fn set_response(&self, response: MockResponse) {
+        self.config().response = response;
+    }
+
+    /// Answers the next `count` requests with `status`, e.g. to simulate a restarting server.
+    pub fn fail_next(&self, count: usize, status: u16) {
+        let mut config = self.config();
+        config.failures_left = count;
+        config.failure_status = status;
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.state.request_count.load(Ordering::SeqCst)
+    }
+
+    fn config(&self) -> std::sync::MutexGuard<'_, MockConfig> {
+        self.state.config.lock().expect("mock config lock")
+    }
+}
+
+impl Drop for MockEmbeddingServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// An embedding record of `fixture.csv` without categories.
+pub fn record(text: &str, label: f32, embedding: Vec<f32>) -> EmbeddingRecord {
+    EmbeddingRecord { text: text.to_string(), label, source: "fixture.csv".to_string(), embedding, categories: Vec::new() }
+}
+
+/// A path in the temp directory, unique per test binary (`test`) and process.
+pub fn temp_path(test: &str, name: &str) -> String {
+    std::env::temp_dir().join(format!("{}_test_{}_{}", test, std::process::id(), name)).to_str().unwrap().to_string()
+}
+
+/// Embeds a text as its length, and fails for empty texts.
+pub struct LengthEmbedder;
+
+impl EmbeddingProvider for LengthEmbedder {
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<f32>>> {
+        Box::pin(async move {
+            if text.is_empty() { Err(anyhow::anyhow!("empty text")) } else { Ok(vec![text.len() as f32, 1.0]) }
+        })
+    }
+
+    fn name(&self) -> String {
+        "length".to_string()
+    }
+}
+
+/// A local URL nothing listens on.
+pub async fn unreachable_endpoint() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind free port");
+    let addr = listener.local_addr().expect("free port address");
+    drop(listener);
+    format!("http://{}/embedding", addr)
+}
+
+/// L2 normalized counts of the hashed character trigrams of the lowercased text,
+/// so equal texts get equal vectors and texts sharing words get similar ones.
+pub fn deterministic_embedding(text: &str, dimension: usize) -> Vec<f32> {
+    let chars: Vec<char> = format!(" {} ", text.to_lowercase()).chars().collect();
+    let mut embedding = vec![0.0f32; dimension];
+    for gram in chars.windows(3) {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for c in gram {
+            for byte in c.to_string().bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        embedding[(hash % dimension as u64) as usize] += 1.0;
+    }
+    let norm = embedding.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|value| *value /= norm);
+    }
+    embedding
+}
+
+/// Applies latency and injected failures, `Ok` means the request should be answered normally.
+async fn intercept(state: &MockState) -> Result<(), Response> {
+    state.request_count.fetch_add(1, Ordering::SeqCst);
+    let (latency, response) = {
+        let mut config = state.config.lock().expect("mock config lock");
+        let response = if config.failures_left > 0 {
+            config.failures_left -= 1;
+            MockResponse::Status(config.failure_status)
+        } else {
+            config.response
+        };
+        (config.latency, response)
+    };
+    if !latency.is_zero() {
+        tokio::time::sleep(latency).await;
+    }
+    match response {
+        MockResponse::Embedding => Ok(()),
+        MockResponse::MalformedJson => Err((StatusCode::OK, [("content-type", "application/json")], "{\"embedding\": [0.1, ").into_response()),
+        MockResponse::WrongSchema => Err(Json(json!({"error": "model not loaded"})).into_response()),
+        MockResponse::Status(status) => Err(StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR).into_response()),
+    }
+}
+
+async fn llama_cpp_embedding(State(state): State<Arc<MockState>>, Json(request): Json<Value>) -> Response {
+    if let Err(response) = intercept(&state).await {
+        return response;
+    }
+    match request["content"].as_str() {
+        Some(content) => Json(json!({"embedding": deterministic_embedding(content, state.dimension)})).into_response(),
+        None => (StatusCode::BAD_REQUEST, "missing content").into_response(),
+    }
+}
+
+async fn openai_embeddings(State(state): State<Arc<MockState>>, Json(request): Json<Value>) -> Response {
+    if let Err(response) = intercept(&state).await {
+        return response;
+    }
+    let inputs: Vec<String> = match &request["input"] {
+        Value::String(input) => vec![input.clone()],
+        Value::Array(inputs) => inputs.iter().filter_map(|input| input.as_str().map(str::to_string)).collect(),
+        _ => return (StatusCode::BAD_REQUEST, "missing input").into_response(),
+    };
+    let data: Vec<Value> = inputs.iter().enumerate()
+        .map(|(index, input)| json!({"object": "embedding", "index": index, "embedding": deterministic_embedding(input, state.dimension)}))
+        .collect();
+    Json(json!({"object": "list", "data": data, "model": request["model"].as_str().unwrap_or("mock")})).into_response()
+}